@@ -0,0 +1,4 @@
+DROP TABLE journal_progress;
+DROP TABLE trades;
+DROP TABLE order_transitions;
+DROP TABLE orders;
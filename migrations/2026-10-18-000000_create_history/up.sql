@@ -0,0 +1,60 @@
+-- Every order the exchange has accepted and where it has got to.
+-- Prices and amounts are exact, timestamps are milliseconds since the unix epoch.
+CREATE TABLE orders (
+    id UUID PRIMARY KEY,
+    symbol TEXT NOT NULL,
+    side TEXT NOT NULL,
+    order_type TEXT NOT NULL,
+    time_in_force TEXT NOT NULL,
+    price NUMERIC,
+    protection_price NUMERIC,
+    amount NUMERIC NOT NULL,
+    filled NUMERIC NOT NULL,
+    remaining NUMERIC NOT NULL,
+    cancelled NUMERIC NOT NULL,
+    status TEXT NOT NULL,
+    created_at BIGINT NOT NULL,
+    updated_at BIGINT NOT NULL
+);
+
+CREATE INDEX orders_symbol_created_at ON orders (symbol, created_at);
+CREATE INDEX orders_created_at ON orders (created_at);
+
+-- Each change to an order, with the order as it was after it.
+CREATE TABLE order_transitions (
+    id BIGSERIAL PRIMARY KEY,
+    order_id UUID NOT NULL REFERENCES orders (id),
+    change TEXT NOT NULL,
+    status TEXT NOT NULL,
+    price NUMERIC,
+    filled NUMERIC NOT NULL,
+    remaining NUMERIC NOT NULL,
+    cancelled NUMERIC NOT NULL,
+    timestamp BIGINT NOT NULL
+);
+
+CREATE INDEX order_transitions_order_id ON order_transitions (order_id, id);
+
+CREATE TABLE trades (
+    id UUID PRIMARY KEY,
+    symbol TEXT NOT NULL,
+    aggressor_id UUID NOT NULL,
+    passive_id UUID NOT NULL,
+    aggressor_side TEXT NOT NULL,
+    price NUMERIC NOT NULL,
+    amount NUMERIC NOT NULL,
+    timestamp BIGINT NOT NULL
+);
+
+CREATE INDEX trades_symbol_timestamp ON trades (symbol, timestamp);
+CREATE INDEX trades_timestamp ON trades (timestamp);
+CREATE INDEX trades_aggressor_id ON trades (aggressor_id);
+CREATE INDEX trades_passive_id ON trades (passive_id);
+
+-- How many journal records have been written to the tables above.
+CREATE TABLE journal_progress (
+    id INTEGER PRIMARY KEY CHECK (id = 1),
+    records BIGINT NOT NULL
+);
+
+INSERT INTO journal_progress (id, records) VALUES (1, 0);
@@ -0,0 +1,4 @@
+-- Who placed each order, NULL for those from before there were accounts.
+ALTER TABLE orders ADD COLUMN account UUID;
+
+CREATE INDEX orders_account_created_at ON orders (account, created_at);
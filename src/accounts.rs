@@ -0,0 +1,292 @@
+use crate::balances::Ledger;
+use crate::error::Error;
+use rand::Rng;
+use serde::{Deserialize, Serialize};
+use std::collections::HashMap;
+use std::fmt;
+use uuid::Uuid;
+
+const MAX_NAME_LEN: usize = 64;
+// Random bytes in an API key's secret.
+const SECRET_LEN: usize = 32;
+
+// Someone who trades, every order belongs to one.
+#[derive(Serialize, Deserialize, Debug, Clone, PartialEq)]
+pub struct Account {
+    pub id: Uuid,
+    pub name: String,
+    // Milliseconds since the unix epoch.
+    pub created_at: u64,
+}
+
+// What a client sends to /accounts to open one.
+#[derive(Serialize, Deserialize, Debug, Clone)]
+pub struct AccountRequest {
+    pub name: String,
+}
+
+// What an API key can be used for, each scope can do everything the ones before it can.
+// ReadOnly can look at the account's orders, fills and keys, Trade can also place, cancel
+// and amend orders and manage the account's keys, and Admin can do anything to any account
+// and manage instruments.
+#[derive(Serialize, Deserialize, Debug, Copy, Clone, PartialEq, Eq, PartialOrd, Ord)]
+pub enum Scope {
+    ReadOnly,
+    Trade,
+    Admin,
+}
+
+// Credentials an account signs its requests with. The secret is only ever given out when
+// the key is made.
+#[derive(Serialize, Deserialize, Clone, PartialEq)]
+pub struct ApiKey {
+    pub id: Uuid,
+    pub account: Uuid,
+    pub scope: Scope,
+    // Hex encoded.
+    pub secret: String,
+    // Milliseconds since the unix epoch.
+    pub created_at: u64,
+}
+
+// An API key without its secret.
+#[derive(Serialize, Deserialize, Debug, Copy, Clone, PartialEq)]
+pub struct KeyInfo {
+    pub id: Uuid,
+    pub account: Uuid,
+    pub scope: Scope,
+    pub created_at: u64,
+}
+
+// Without the secret, so it can't end up in the logs.
+impl fmt::Debug for ApiKey {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        f.debug_struct("ApiKey")
+            .field("id", &self.id)
+            .field("account", &self.account)
+            .field("scope", &self.scope)
+            .field("secret", &"<redacted>")
+            .field("created_at", &self.created_at)
+            .finish()
+    }
+}
+
+impl ApiKey {
+    pub fn info(&self) -> KeyInfo {
+        KeyInfo {
+            id: self.id,
+            account: self.account,
+            scope: self.scope,
+            created_at: self.created_at,
+        }
+    }
+}
+
+// What a client sends to /accounts/{id}/keys to make a key.
+#[derive(Serialize, Deserialize, Debug, Copy, Clone)]
+pub struct KeyRequest {
+    pub scope: Scope,
+}
+
+// Every account, the orders each has placed, their API keys and balances.
+pub struct Accounts {
+    accounts: HashMap<Uuid, Account>,
+    by_name: HashMap<String, Uuid>,
+    // Every order each account has placed, oldest first.
+    orders: HashMap<Uuid, Vec<Uuid>>,
+    keys: HashMap<Uuid, ApiKey>,
+    ledger: Ledger,
+}
+
+impl Accounts {
+    pub fn new() -> Accounts {
+        Accounts {
+            accounts: HashMap::new(),
+            by_name: HashMap::new(),
+            orders: HashMap::new(),
+            keys: HashMap::new(),
+            ledger: Ledger::new(),
+        }
+    }
+
+    // Opens a new account with a fresh id. Names are unique.
+    pub fn open(&mut self, request: AccountRequest, now: u64) -> Result<Account, Error> {
+        let name = request.name;
+        if name.is_empty() || name.len() > MAX_NAME_LEN {
+            return Err(Error::InvalidAccount(
+                "name must be between 1 and 64 characters",
+            ));
+        }
+        if !name
+            .bytes()
+            .all(|b| b.is_ascii_alphanumeric() || b == b'.' || b == b'-' || b == b'_')
+        {
+            return Err(Error::InvalidAccount(
+                "name can only contain letters, digits, '.', '-' and '_'",
+            ));
+        }
+        let account = Account {
+            id: Uuid::new_v4(),
+            name,
+            created_at: now,
+        };
+        self.insert(account.clone())?;
+        Ok(account)
+    }
+
+    // Puts back an account opened before, e.g. when recovering.
+    pub fn insert(&mut self, account: Account) -> Result<(), Error> {
+        if self.accounts.contains_key(&account.id) || self.by_name.contains_key(&account.name) {
+            return Err(Error::AccountExists);
+        }
+        self.by_name.insert(account.name.clone(), account.id);
+        self.accounts.insert(account.id, account);
+        Ok(())
+    }
+
+    pub fn get(&self, id: Uuid) -> Option<Account> {
+        self.accounts.get(&id).cloned()
+    }
+
+    // Every account, sorted by name.
+    pub fn list(&self) -> Vec<Account> {
+        let mut accounts: Vec<Account> = self.accounts.values().cloned().collect();
+        accounts.sort_by(|a, b| a.name.cmp(&b.name));
+        accounts
+    }
+
+    pub fn record_order(&mut self, account: Uuid, order: Uuid) {
+        self.orders.entry(account).or_default().push(order);
+    }
+
+    // Every order the account has placed, oldest first.
+    pub fn orders(&self, account: Uuid) -> &[Uuid] {
+        match self.orders.get(&account) {
+            Some(orders) => orders,
+            None => &[],
+        }
+    }
+
+    pub fn ledger(&self) -> &Ledger {
+        &self.ledger
+    }
+
+    pub fn ledger_mut(&mut self) -> &mut Ledger {
+        &mut self.ledger
+    }
+
+    // Makes a new key for the account with a random secret.
+    pub fn add_key(&mut self, account: Uuid, scope: Scope, now: u64) -> Result<ApiKey, Error> {
+        let secret: [u8; SECRET_LEN] = rand::thread_rng().gen();
+        let key = ApiKey {
+            id: Uuid::new_v4(),
+            account,
+            scope,
+            secret: hex::encode(secret),
+            created_at: now,
+        };
+        self.insert_key(key.clone())?;
+        Ok(key)
+    }
+
+    // Puts back a key made before, e.g. when recovering.
+    pub fn insert_key(&mut self, key: ApiKey) -> Result<(), Error> {
+        if !self.accounts.contains_key(&key.account) {
+            return Err(Error::NoSuchAccount);
+        }
+        self.keys.insert(key.id, key);
+        Ok(())
+    }
+
+    // Stops one of the account's keys from working.
+    pub fn revoke_key(&mut self, account: Uuid, id: Uuid) -> Result<KeyInfo, Error> {
+        match self.keys.get(&id) {
+            Some(key) if key.account == account => Ok(self.keys.remove(&id).unwrap().info()),
+            _ => Err(Error::NoSuchKey),
+        }
+    }
+
+    pub fn key(&self, id: Uuid) -> Option<&ApiKey> {
+        self.keys.get(&id)
+    }
+
+    // The account's keys, oldest first.
+    pub fn keys(&self, account: Uuid) -> Vec<ApiKey> {
+        let mut keys: Vec<ApiKey> = self
+            .keys
+            .values()
+            .filter(|key| key.account == account)
+            .cloned()
+            .collect();
+        keys.sort_by_key(|key| (key.created_at, key.id));
+        keys
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use crate::accounts::{AccountRequest, Accounts, Scope};
+    use crate::error::Error;
+    use uuid::Uuid;
+
+    fn request(name: &str) -> AccountRequest {
+        AccountRequest {
+            name: name.to_string(),
+        }
+    }
+
+    #[test]
+    fn test_accounts() {
+        let mut accounts = Accounts::new();
+        let bob = accounts.open(request("bob"), 2).unwrap();
+        let alice = accounts.open(request("alice.smith"), 1).unwrap();
+        assert_ne!(alice.id, bob.id);
+        assert_eq!(accounts.get(bob.id), Some(bob.clone()));
+        assert!(accounts.get(Uuid::new_v4()).is_none());
+        assert_eq!(accounts.list(), vec![alice.clone(), bob.clone()]);
+
+        assert_eq!(
+            accounts.open(request("bob"), 3).unwrap_err(),
+            Error::AccountExists
+        );
+        assert_eq!(
+            accounts.insert(alice.clone()).unwrap_err(),
+            Error::AccountExists
+        );
+        for name in ["", "bob smith", &"a".repeat(65)].iter() {
+            assert!(matches!(
+                accounts.open(request(name), 3),
+                Err(Error::InvalidAccount(_))
+            ));
+        }
+
+        let (first, second) = (Uuid::new_v4(), Uuid::new_v4());
+        accounts.record_order(bob.id, first);
+        accounts.record_order(bob.id, second);
+        assert_eq!(accounts.orders(bob.id), &[first, second]);
+        assert!(accounts.orders(alice.id).is_empty());
+
+        let read = accounts.add_key(bob.id, Scope::ReadOnly, 1).unwrap();
+        let trade = accounts.add_key(bob.id, Scope::Trade, 2).unwrap();
+        assert_ne!(read.secret, trade.secret);
+        assert_eq!(read.secret.len(), 64);
+        assert!(!format!("{:?}", read).contains(&read.secret));
+        assert_eq!(accounts.keys(bob.id), vec![read.clone(), trade.clone()]);
+        assert_eq!(accounts.key(trade.id), Some(&trade));
+        assert!(Scope::ReadOnly < Scope::Trade && Scope::Trade < Scope::Admin);
+        assert_eq!(
+            accounts
+                .add_key(Uuid::new_v4(), Scope::Trade, 3)
+                .unwrap_err(),
+            Error::NoSuchAccount
+        );
+        // Only by the account it belongs to.
+        assert_eq!(
+            accounts.revoke_key(alice.id, read.id).unwrap_err(),
+            Error::NoSuchKey
+        );
+        assert_eq!(accounts.revoke_key(bob.id, read.id).unwrap(), read.info());
+        assert!(accounts.key(read.id).is_none());
+        assert_eq!(accounts.keys(bob.id), vec![trade]);
+    }
+}
@@ -0,0 +1,271 @@
+use crate::accounts::{Accounts, Scope};
+use crate::error::Error;
+use hmac::{Hmac, Mac};
+use hyper::Request;
+use sha2::Sha256;
+use std::cmp::Reverse;
+use std::collections::{BinaryHeap, HashSet};
+use uuid::Uuid;
+
+// How far a request's timestamp can be from the server's clock, in milliseconds. A request
+// can't be replayed once it's outside of this, and until then its nonce is remembered.
+pub const REPLAY_WINDOW: u64 = 30_000;
+const MAX_NONCE_LEN: usize = 64;
+
+// Who made a request, from the API key it was signed with.
+#[derive(Debug, Copy, Clone, PartialEq)]
+pub struct Caller {
+    pub key: Uuid,
+    // None for the admin key from the environment, which isn't any account's.
+    pub account: Option<Uuid>,
+    pub scope: Scope,
+}
+
+impl Caller {
+    pub fn require(&self, scope: Scope) -> Result<(), Error> {
+        if self.scope < scope {
+            return Err(Error::Forbidden);
+        }
+        Ok(())
+    }
+
+    // The account the key belongs to, for placing orders and the like.
+    pub fn account(&self) -> Result<Uuid, Error> {
+        self.account.ok_or(Error::Forbidden)
+    }
+
+    // The account an /accounts/{id} path is for, which has to be the caller's own unless
+    // they're an admin.
+    pub fn for_account(&self, account: Uuid) -> Result<Uuid, Error> {
+        if self.account != Some(account) && self.scope < Scope::Admin {
+            return Err(Error::Forbidden);
+        }
+        Ok(account)
+    }
+}
+
+type HmacSha256 = Hmac<Sha256>;
+
+// Requests are signed with the hex encoded HMAC-SHA256, keyed with the secret, of the
+// timestamp, nonce, method and path with its query string, each followed by a newline, and
+// then the body.
+fn signer(
+    secret: &str,
+    timestamp: u64,
+    nonce: &str,
+    head: &Request<()>,
+    body: &[u8],
+) -> HmacSha256 {
+    let path = head
+        .uri()
+        .path_and_query()
+        .map_or(head.uri().path(), |path| path.as_str());
+    // Any key length is fine for HMAC.
+    let mut mac = HmacSha256::new_from_slice(secret.as_bytes()).unwrap();
+    mac.update(format!("{}\n{}\n{}\n{}\n", timestamp, nonce, head.method(), path).as_bytes());
+    mac.update(body);
+    mac
+}
+
+fn header<'a>(head: &'a Request<()>, name: &'static str) -> Result<&'a str, Error> {
+    head.headers()
+        .get(name)
+        .and_then(|value| value.to_str().ok())
+        .ok_or(Error::Unauthenticated(
+            "requests must be signed with X-Api-Key, X-Timestamp, X-Nonce and X-Signature headers",
+        ))
+}
+
+// Checks request signatures and remembers the nonces of recent ones so they can't be
+// replayed.
+pub struct Authenticator {
+    // The key and secret of an admin that isn't any account's, so there's a way to open the
+    // first account.
+    admin: Option<(Uuid, String)>,
+    seen: HashSet<(Uuid, String)>,
+    // When each seen nonce can be forgotten, soonest first.
+    expiries: BinaryHeap<Reverse<(u64, Uuid, String)>>,
+}
+
+impl Authenticator {
+    pub fn new(admin: Option<(Uuid, String)>) -> Authenticator {
+        Authenticator {
+            admin,
+            seen: HashSet::new(),
+            expiries: BinaryHeap::new(),
+        }
+    }
+
+    // Who signed the request. It has to be signed with a key that hasn't been revoked,
+    // within REPLAY_WINDOW of now and with a nonce that key hasn't used in that time.
+    pub fn authenticate(
+        &mut self,
+        accounts: &Accounts,
+        head: &Request<()>,
+        body: &[u8],
+        now: u64,
+    ) -> Result<Caller, Error> {
+        let key = Uuid::parse_str(header(head, "X-Api-Key")?)
+            .map_err(|_| Error::Unauthenticated("X-Api-Key must be an API key id"))?;
+        let timestamp: u64 = header(head, "X-Timestamp")?.parse().map_err(|_| {
+            Error::Unauthenticated("X-Timestamp must be milliseconds since the unix epoch")
+        })?;
+        let nonce = header(head, "X-Nonce")?;
+        let signature = hex::decode(header(head, "X-Signature")?)
+            .map_err(|_| Error::Unauthenticated("X-Signature must be hex encoded"))?;
+        if nonce.is_empty() || nonce.len() > MAX_NONCE_LEN {
+            return Err(Error::Unauthenticated(
+                "X-Nonce must be between 1 and 64 characters",
+            ));
+        }
+
+        let (caller, secret) = match &self.admin {
+            Some((id, secret)) if *id == key => (
+                Caller {
+                    key,
+                    account: None,
+                    scope: Scope::Admin,
+                },
+                secret.as_str(),
+            ),
+            _ => {
+                let api_key = accounts
+                    .key(key)
+                    .ok_or(Error::Unauthenticated("no such API key"))?;
+                (
+                    Caller {
+                        key,
+                        account: Some(api_key.account),
+                        scope: api_key.scope,
+                    },
+                    api_key.secret.as_str(),
+                )
+            }
+        };
+        if timestamp.max(now) - timestamp.min(now) > REPLAY_WINDOW {
+            return Err(Error::Unauthenticated(
+                "X-Timestamp is too far from the server's clock",
+            ));
+        }
+        signer(secret, timestamp, nonce, head, body)
+            .verify_slice(&signature)
+            .map_err(|_| Error::Unauthenticated("signature doesn't match"))?;
+
+        // Only checked once the signature is, so nobody else can use up a key's nonces.
+        while let Some(Reverse((expiry, _, _))) = self.expiries.peek() {
+            if *expiry >= now {
+                break;
+            }
+            let Reverse((_, key, nonce)) = self.expiries.pop().unwrap();
+            self.seen.remove(&(key, nonce));
+        }
+        if !self.seen.insert((key, nonce.to_string())) {
+            return Err(Error::Unauthenticated("nonce already used"));
+        }
+        self.expiries
+            .push(Reverse((timestamp + REPLAY_WINDOW, key, nonce.to_string())));
+        Ok(caller)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use crate::accounts::{AccountRequest, Accounts, Scope};
+    use crate::auth::{signer, Authenticator, Caller, REPLAY_WINDOW};
+    use crate::error::Error;
+    use hmac::Mac;
+    use hyper::Request;
+    use uuid::Uuid;
+
+    fn signed(key: Uuid, secret: &str, timestamp: u64, nonce: &str, body: &[u8]) -> Request<()> {
+        let head = Request::post("/order?symbol=AAPL").body(()).unwrap();
+        let mac = signer(secret, timestamp, nonce, &head, body);
+        let signature = hex::encode(mac.finalize().into_bytes());
+        Request::post("/order?symbol=AAPL")
+            .header("X-Api-Key", key.to_string())
+            .header("X-Timestamp", timestamp.to_string())
+            .header("X-Nonce", nonce)
+            .header("X-Signature", signature)
+            .body(())
+            .unwrap()
+    }
+
+    fn rejected(result: Result<Caller, Error>) -> &'static str {
+        match result {
+            Err(Error::Unauthenticated(message)) => message,
+            result => panic!("expected to be unauthenticated, got {:?}", result),
+        }
+    }
+
+    #[test]
+    fn test_authenticate() {
+        let mut accounts = Accounts::new();
+        let request = AccountRequest {
+            name: "alice".to_string(),
+        };
+        let alice = accounts.open(request, 0).unwrap().id;
+        let key = accounts.add_key(alice, Scope::Trade, 0).unwrap();
+        let admin = Uuid::new_v4();
+        let mut auth = Authenticator::new(Some((admin, "hunter2".to_string())));
+        let now = 1_000_000;
+        let body = br#"{"symbol": "AAPL"}"#;
+
+        let head = signed(key.id, &key.secret, now, "1", body);
+        let caller = auth.authenticate(&accounts, &head, body, now).unwrap();
+        assert_eq!(
+            caller,
+            Caller {
+                key: key.id,
+                account: Some(alice),
+                scope: Scope::Trade,
+            }
+        );
+        assert_eq!(caller.account(), Ok(alice));
+        assert_eq!(caller.require(Scope::ReadOnly), Ok(()));
+        assert_eq!(caller.require(Scope::Admin), Err(Error::Forbidden));
+        assert_eq!(caller.for_account(Uuid::new_v4()), Err(Error::Forbidden));
+
+        let head = signed(key.id, &key.secret, now, "1", body);
+        assert_eq!(
+            rejected(auth.authenticate(&accounts, &head, body, now + 1)),
+            "nonce already used"
+        );
+        // Different body, or a different key's secret.
+        let head = signed(key.id, &key.secret, now, "2", body);
+        assert_eq!(
+            rejected(auth.authenticate(&accounts, &head, b"{}", now)),
+            "signature doesn't match"
+        );
+        let head = signed(key.id, "hunter2", now, "2", body);
+        assert_eq!(
+            rejected(auth.authenticate(&accounts, &head, body, now)),
+            "signature doesn't match"
+        );
+        let head = signed(key.id, &key.secret, now - REPLAY_WINDOW - 1, "3", body);
+        assert_eq!(
+            rejected(auth.authenticate(&accounts, &head, body, now)),
+            "X-Timestamp is too far from the server's clock"
+        );
+        let head = Request::post("/order").body(()).unwrap();
+        assert!(rejected(auth.authenticate(&accounts, &head, body, now))
+            .starts_with("requests must be signed"));
+
+        // Nonces are forgotten once their request couldn't be replayed anyway.
+        let later = now + REPLAY_WINDOW + 1;
+        let head = signed(key.id, &key.secret, later, "1", body);
+        assert!(auth.authenticate(&accounts, &head, body, later).is_ok());
+        assert_eq!(auth.seen.len(), 1);
+
+        let head = signed(admin, "hunter2", later, "1", body);
+        let caller = auth.authenticate(&accounts, &head, body, later).unwrap();
+        assert_eq!(caller.account(), Err(Error::Forbidden));
+        assert_eq!(caller.for_account(alice), Ok(alice));
+
+        accounts.revoke_key(alice, key.id).unwrap();
+        let head = signed(key.id, &key.secret, later, "2", body);
+        assert_eq!(
+            rejected(auth.authenticate(&accounts, &head, body, later)),
+            "no such API key"
+        );
+    }
+}
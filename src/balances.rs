@@ -0,0 +1,728 @@
+use crate::decimal::Decimal;
+use crate::error::Error;
+use crate::fees::{self, Fee, FeeLedger, FeeSchedule};
+use crate::instruments::{Instrument, MAX_UNITS};
+use crate::order_book::{OpenLimitOrder, OrderEvent, Side, Symbol, Trade};
+use crate::positions::Position;
+use crate::trade_log::Liquidity;
+use serde::{Deserialize, Serialize};
+use std::collections::{BTreeMap, HashMap};
+use uuid::Uuid;
+
+// How much of something an account has, and how much of that live orders are holding back.
+#[derive(Serialize, Deserialize, Debug, Copy, Clone, PartialEq)]
+pub struct Balance {
+    pub total: Decimal,
+    pub reserved: Decimal,
+    // Always total - reserved.
+    pub available: Decimal,
+}
+
+impl Default for Balance {
+    fn default() -> Balance {
+        Balance {
+            total: Decimal::ZERO,
+            reserved: Decimal::ZERO,
+            available: Decimal::ZERO,
+        }
+    }
+}
+
+impl Balance {
+    fn changed(&self, total: Decimal, reserved: Decimal) -> Result<Balance, Error> {
+        let total = self.total.checked_add(total).ok_or(Error::Overflow)?;
+        let reserved = self.reserved.checked_add(reserved).ok_or(Error::Overflow)?;
+        Ok(Balance {
+            total,
+            reserved,
+            available: total.checked_sub(reserved).ok_or(Error::Overflow)?,
+        })
+    }
+
+    fn change(&mut self, total: Decimal, reserved: Decimal) -> Result<(), Error> {
+        *self = self.changed(total, reserved)?;
+        Ok(())
+    }
+}
+
+// An account's cash and what it has of each instrument.
+#[derive(Serialize, Deserialize, Debug, Clone, Default, PartialEq)]
+pub struct Balances {
+    pub cash: Balance,
+    pub instruments: BTreeMap<Symbol, Balance>,
+}
+
+impl Balances {
+    // Cash for buying symbol, or the instrument itself for selling it.
+    fn for_side(&mut self, symbol: Symbol, side: Side) -> &mut Balance {
+        match side {
+            Side::Buy => &mut self.cash,
+            Side::Sell => self.instruments.entry(symbol).or_default(),
+        }
+    }
+}
+
+// What a live order holds back so it can be settled, price * amount of cash for a buy and
+// amount of the instrument for a sell.
+#[derive(Serialize, Deserialize, Debug, Copy, Clone, PartialEq)]
+pub struct Hold {
+    pub symbol: Symbol,
+    pub side: Side,
+    pub amount: Decimal,
+}
+
+impl Hold {
+    pub fn new(symbol: Symbol, side: Side, price: Decimal, amount: Decimal) -> Result<Hold, Error> {
+        let amount = match side {
+            Side::Buy => price.checked_mul(amount).ok_or(Error::Overflow)?,
+            Side::Sell => amount,
+        };
+        Ok(Hold {
+            symbol,
+            side,
+            amount,
+        })
+    }
+
+    pub fn for_order(order: &OpenLimitOrder) -> Result<Hold, Error> {
+        Hold::new(order.symbol, order.side, order.price, order.amount)
+    }
+}
+
+// Cash, or an amount of an instrument, moved into an account or out of it. What a client
+// sends to /accounts/{id}/deposits or /withdrawals.
+#[derive(Serialize, Deserialize, Debug, Copy, Clone)]
+pub struct TransferRequest {
+    #[serde(default)]
+    pub symbol: Option<Symbol>,
+    pub amount: Decimal,
+}
+
+impl TransferRequest {
+    // Transfers have to be positive and no bigger than the largest amount of the instrument,
+    // which is given for them, or for cash the same number of whole units. Returns it with
+    // exactly the instrument's amount_scale decimal places.
+    pub fn validate(self, instrument: Option<&Instrument>) -> Result<TransferRequest, Error> {
+        let invalid = |message: &str| Err(Error::InvalidRequest(message.to_string()));
+        if !self.amount.is_positive() {
+            return invalid("amount must be positive");
+        }
+        let (amount, limit) = match instrument {
+            Some(instrument) => match self.amount.rescale(instrument.amount_scale) {
+                Some(amount) => (amount, instrument.amount_limit()),
+                None => {
+                    return invalid("amount has more decimal places than the instrument's amounts")
+                }
+            },
+            None => (self.amount, Decimal::new(MAX_UNITS, 0)),
+        };
+        if amount > limit {
+            return invalid("amount is above the largest supported amount");
+        }
+        Ok(TransferRequest { amount, ..self })
+    }
+}
+
+// Every account's balances, positions and fees, and what each resting order is holding back.
+// Reserved balances only ever change along with the books: an order reserves what it
+// needs when it's accepted and whatever is left resting keeps it reserved until it trades
+// or is cancelled. Trades move the totals between buyer and seller and fill both their
+// positions, and their fees come out of, or rebates go into, each side's cash.
+pub struct Ledger {
+    balances: HashMap<Uuid, Balances>,
+    positions: HashMap<Uuid, BTreeMap<Symbol, Position>>,
+    // Who each resting order belongs to and what it holds.
+    holds: HashMap<Uuid, (Uuid, Hold)>,
+    fees: FeeLedger,
+}
+
+impl Ledger {
+    pub fn new() -> Ledger {
+        Ledger {
+            balances: HashMap::new(),
+            positions: HashMap::new(),
+            holds: HashMap::new(),
+            fees: FeeLedger::new(),
+        }
+    }
+
+    pub fn balances(&self, account: Uuid) -> Balances {
+        self.balances.get(&account).cloned().unwrap_or_default()
+    }
+
+    // The account's position in every instrument it has traded.
+    pub fn positions(&self, account: Uuid) -> BTreeMap<Symbol, Position> {
+        self.positions.get(&account).cloned().unwrap_or_default()
+    }
+
+    pub fn restore(
+        &mut self,
+        account: Uuid,
+        balances: Balances,
+        positions: BTreeMap<Symbol, Position>,
+    ) {
+        self.balances.insert(account, balances);
+        self.positions.insert(account, positions);
+    }
+
+    pub fn fee_ledger(&self) -> &FeeLedger {
+        &self.fees
+    }
+
+    pub fn fee_ledger_mut(&mut self) -> &mut FeeLedger {
+        &mut self.fees
+    }
+
+    fn position(&self, account: Uuid, symbol: Symbol) -> Position {
+        self.positions
+            .get(&account)
+            .and_then(|positions| positions.get(&symbol))
+            .copied()
+            .unwrap_or_default()
+    }
+
+    fn balance(&mut self, account: Uuid, symbol: Option<Symbol>) -> &mut Balance {
+        let balances = self.balances.entry(account).or_default();
+        match symbol {
+            Some(symbol) => balances.instruments.entry(symbol).or_default(),
+            None => &mut balances.cash,
+        }
+    }
+
+    // Checks a deposit, or a withdrawal if it's negative, can be made, so it's known to
+    // apply before it's journaled. Only what isn't reserved can be withdrawn.
+    pub fn check_transfer(&self, account: Uuid, transfer: TransferRequest) -> Result<(), Error> {
+        let balances = self.balances(account);
+        let balance = match transfer.symbol {
+            Some(symbol) => balances
+                .instruments
+                .get(&symbol)
+                .copied()
+                .unwrap_or_default(),
+            None => balances.cash,
+        };
+        if balance.available < -transfer.amount {
+            return Err(insufficient(
+                transfer.symbol,
+                -transfer.amount,
+                balance.available,
+            ));
+        }
+        balance.changed(transfer.amount, Decimal::ZERO)?;
+        Ok(())
+    }
+
+    // Moves cash or an instrument into the account, or out of it if amount is negative.
+    pub fn transfer(
+        &mut self,
+        account: Uuid,
+        transfer: TransferRequest,
+    ) -> Result<Balances, Error> {
+        self.balance(account, transfer.symbol)
+            .change(transfer.amount, Decimal::ZERO)?;
+        Ok(self.balances(account))
+    }
+
+    // Holds back what an order needs before it's matched, as long as the account has it.
+    pub fn reserve(&mut self, account: Uuid, hold: Hold) -> Result<(), Error> {
+        let balance = self
+            .balances
+            .entry(account)
+            .or_default()
+            .for_side(hold.symbol, hold.side);
+        if balance.available < hold.amount {
+            let symbol = Some(hold.symbol).filter(|_| hold.side == Side::Sell);
+            return Err(insufficient(symbol, hold.amount, balance.available));
+        }
+        balance.change(Decimal::ZERO, hold.amount)?;
+        Ok(())
+    }
+
+    // A buy's hold with enough more cash for the most it could be charged as the taker, at
+    // the highest rate it could get in case its volume tier changes before it fills. Makers
+    // only get rebates so there's nothing to reserve for those.
+    pub fn with_taker_fee(
+        &self,
+        schedule: &FeeSchedule,
+        account: Uuid,
+        hold: Hold,
+    ) -> Result<Hold, Error> {
+        if hold.side == Side::Sell {
+            return Ok(hold);
+        }
+        let bps = schedule.max_taker_bps(hold.symbol, account);
+        let fee = fees::fee(hold.amount, bps)?;
+        if !fee.is_positive() {
+            return Ok(hold);
+        }
+        Ok(Hold {
+            amount: hold.amount.checked_add(fee).ok_or(Error::Overflow)?,
+            ..hold
+        })
+    }
+
+    // What each side of an order's trades is charged, from the schedule and what they'd
+    // traded before the order. The order belongs to owner.
+    pub fn fees(
+        &self,
+        schedule: &FeeSchedule,
+        owner: Uuid,
+        trades: &[Trade],
+    ) -> Result<Vec<Fee>, Error> {
+        let mut charged = Vec::new();
+        for trade in trades {
+            let maker = match self.holds.get(&trade.passive_id) {
+                Some(&(maker, _)) => maker,
+                None => return Err(Error::NoSuchOrder),
+            };
+            let notional = trade
+                .price
+                .checked_mul(trade.amount)
+                .ok_or(Error::Overflow)?;
+            for &(account, order, liquidity) in [
+                (owner, trade.aggressor_id, Liquidity::Taker),
+                (maker, trade.passive_id, Liquidity::Maker),
+            ]
+            .iter()
+            {
+                let rates = schedule.rates(
+                    trade.symbol,
+                    account,
+                    self.fees.volume(account, trade.timestamp)?,
+                );
+                let bps = match liquidity {
+                    Liquidity::Maker => rates.maker_bps,
+                    Liquidity::Taker => rates.taker_bps,
+                };
+                charged.push(Fee {
+                    trade: trade.id,
+                    order,
+                    account,
+                    symbol: trade.symbol,
+                    liquidity,
+                    notional,
+                    bps,
+                    amount: fees::fee(notional, bps)?,
+                    timestamp: trade.timestamp,
+                });
+            }
+        }
+        Ok(charged)
+    }
+
+    pub fn release(&mut self, account: Uuid, hold: Hold) -> Result<(), Error> {
+        self.balances
+            .entry(account)
+            .or_default()
+            .for_side(hold.symbol, hold.side)
+            .change(Decimal::ZERO, -hold.amount)
+    }
+
+    // Settles the trades an order on symbol made, and the fees charged for them, and keeps
+    // what resting orders hold in step with the events. The order, and any the events add,
+    // belong to owner. It's the same whether it's happening or being replayed from the
+    // journal, nothing is checked. Everything is worked out before any of it is applied, so
+    // it's settled all at once or not at all.
+    pub fn settle(
+        &mut self,
+        symbol: Symbol,
+        owner: Uuid,
+        events: &[OrderEvent],
+        trades: &[Trade],
+        fees: &[Fee],
+    ) -> Result<(), Error> {
+        let mut settlement = Settlement::new(self);
+        for trade in trades {
+            let maker = match settlement.hold(trade.passive_id) {
+                Some((maker, _)) => maker,
+                None => return Err(Error::NoSuchOrder),
+            };
+            let (buyer, seller) = match trade.aggressor_side {
+                Side::Buy => (owner, maker),
+                Side::Sell => (maker, owner),
+            };
+            let notional = trade
+                .price
+                .checked_mul(trade.amount)
+                .ok_or(Error::Overflow)?;
+            // Trading with itself, the sell follows on from the buy.
+            settlement.fill(buyer, symbol, Side::Buy, trade)?;
+            settlement.fill(seller, symbol, Side::Sell, trade)?;
+            settlement.change(buyer, None, -notional, Decimal::ZERO)?;
+            settlement.change(buyer, Some(symbol), trade.amount, Decimal::ZERO)?;
+            settlement.change(seller, Some(symbol), -trade.amount, Decimal::ZERO)?;
+            settlement.change(seller, None, notional, Decimal::ZERO)?;
+        }
+        for fee in fees {
+            settlement.change(fee.account, None, -fee.amount, Decimal::ZERO)?;
+        }
+        for &event in events {
+            settlement.apply_event(symbol, owner, event)?;
+        }
+
+        let Settlement {
+            balances,
+            positions,
+            holds,
+            ..
+        } = settlement;
+        for ((account, symbol), balance) in balances {
+            *self.balance(account, symbol) = balance;
+        }
+        for ((account, symbol), position) in positions {
+            self.positions
+                .entry(account)
+                .or_default()
+                .insert(symbol, position);
+        }
+        for (id, hold) in holds {
+            match hold {
+                Some(hold) => self.holds.insert(id, hold),
+                None => self.holds.remove(&id),
+            };
+        }
+        for &fee in fees {
+            self.fees.accrue(fee);
+        }
+        Ok(())
+    }
+
+    // Releases everything held by orders resting on symbol, when it's delisted.
+    pub fn delist(&mut self, symbol: Symbol) -> Result<(), Error> {
+        let delisted: Vec<Uuid> = self
+            .holds
+            .iter()
+            .filter(|(_, (_, hold))| hold.symbol == symbol)
+            .map(|(&id, _)| id)
+            .collect();
+        for id in delisted {
+            let (owner, hold) = self.holds.remove(&id).unwrap();
+            self.release(owner, hold)?;
+        }
+        Ok(())
+    }
+
+    // Remembers what a resting order restored from a snapshot holds, which is already
+    // included in its owner's reserved balance.
+    pub fn restore_hold(&mut self, order: &OpenLimitOrder) -> Result<(), Error> {
+        self.holds
+            .insert(order.id, (order.owner, Hold::for_order(order)?));
+        Ok(())
+    }
+}
+
+fn insufficient(symbol: Option<Symbol>, needed: Decimal, available: Decimal) -> Error {
+    let asset = match symbol {
+        Some(symbol) => symbol.to_string(),
+        None => "cash".to_string(),
+    };
+    Error::InsufficientBalance(format!(
+        "not enough {}: {} is needed and only {} is available",
+        asset, needed, available
+    ))
+}
+
+// What a settlement changes, worked out against the ledger without touching it.
+struct Settlement<'a> {
+    ledger: &'a Ledger,
+    balances: HashMap<(Uuid, Option<Symbol>), Balance>,
+    positions: HashMap<(Uuid, Symbol), Position>,
+    // None once the order no longer holds anything.
+    holds: HashMap<Uuid, Option<(Uuid, Hold)>>,
+}
+
+impl<'a> Settlement<'a> {
+    fn new(ledger: &'a Ledger) -> Settlement<'a> {
+        Settlement {
+            ledger,
+            balances: HashMap::new(),
+            positions: HashMap::new(),
+            holds: HashMap::new(),
+        }
+    }
+
+    fn hold(&self, id: Uuid) -> Option<(Uuid, Hold)> {
+        match self.holds.get(&id) {
+            Some(&hold) => hold,
+            None => self.ledger.holds.get(&id).copied(),
+        }
+    }
+
+    fn change(
+        &mut self,
+        account: Uuid,
+        symbol: Option<Symbol>,
+        total: Decimal,
+        reserved: Decimal,
+    ) -> Result<(), Error> {
+        let balance = match self.balances.get(&(account, symbol)) {
+            Some(&balance) => balance,
+            None => {
+                let balances = self.ledger.balances.get(&account);
+                match symbol {
+                    Some(symbol) => balances
+                        .and_then(|balances| balances.instruments.get(&symbol))
+                        .copied(),
+                    None => balances.map(|balances| balances.cash),
+                }
+                .unwrap_or_default()
+            }
+        };
+        let balance = balance.changed(total, reserved)?;
+        self.balances.insert((account, symbol), balance);
+        Ok(())
+    }
+
+    // Changes what's reserved for a hold, which is cash for a buy and the instrument for a
+    // sell.
+    fn reserve(&mut self, account: Uuid, hold: Hold, amount: Decimal) -> Result<(), Error> {
+        let symbol = Some(hold.symbol).filter(|_| hold.side == Side::Sell);
+        self.change(account, symbol, Decimal::ZERO, amount)
+    }
+
+    fn fill(
+        &mut self,
+        account: Uuid,
+        symbol: Symbol,
+        side: Side,
+        trade: &Trade,
+    ) -> Result<(), Error> {
+        let position = match self.positions.get(&(account, symbol)) {
+            Some(&position) => position,
+            None => self.ledger.position(account, symbol),
+        };
+        let position = position.fill(side, trade.price, trade.amount)?;
+        self.positions.insert((account, symbol), position);
+        Ok(())
+    }
+
+    fn apply_event(&mut self, symbol: Symbol, owner: Uuid, event: OrderEvent) -> Result<(), Error> {
+        match event {
+            OrderEvent::Add {
+                id,
+                side,
+                price,
+                amount,
+            } => {
+                let hold = Hold::new(symbol, side, price, amount)?;
+                self.reserve(owner, hold, hold.amount)?;
+                self.holds.insert(id, Some((owner, hold)));
+            }
+            OrderEvent::Cancel { id, .. } => {
+                let (owner, hold) = self.hold(id).ok_or(Error::NoSuchOrder)?;
+                self.reserve(owner, hold, -hold.amount)?;
+                self.holds.insert(id, None);
+            }
+            OrderEvent::Modify {
+                id,
+                side,
+                price,
+                amount,
+            }
+            | OrderEvent::Execute {
+                id,
+                side,
+                price,
+                amount,
+            } => {
+                let (owner, held) = self.hold(id).ok_or(Error::NoSuchOrder)?;
+                let changed = Hold::new(symbol, side, price, amount)?.amount;
+                // A modify says what's left, an execution how much traded.
+                let left = match event {
+                    OrderEvent::Modify { .. } => changed,
+                    _ => held.amount.checked_sub(changed).ok_or(Error::Overflow)?,
+                };
+                let released = held.amount.checked_sub(left).ok_or(Error::Overflow)?;
+                self.reserve(owner, held, -released)?;
+                let hold = Some(Hold {
+                    amount: left,
+                    ..held
+                })
+                .filter(|_| left.is_positive());
+                self.holds.insert(id, hold.map(|hold| (owner, hold)));
+            }
+        }
+        Ok(())
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use crate::balances::{Balance, Hold, Ledger, TransferRequest};
+    use crate::decimal::Decimal;
+    use crate::error::Error;
+    use crate::fees::{FeeSchedule, Rates};
+    use crate::instruments::Instrument;
+    use crate::order_book::{AmendRequest, OpenLimitOrder, OrderBook, Side, Symbol, TimeInForce};
+    use crate::positions::Position;
+    use uuid::Uuid;
+
+    fn d(s: &str) -> Decimal {
+        s.parse().unwrap()
+    }
+
+    fn order(owner: Uuid, side: Side, price: &str, amount: &str) -> OpenLimitOrder {
+        OpenLimitOrder {
+            id: Uuid::new_v4(),
+            owner,
+            amount: d(amount),
+            symbol: Symbol::new("AAPL").unwrap(),
+            price: d(price),
+            side,
+        }
+    }
+
+    fn balance(total: &str, reserved: &str) -> Balance {
+        Balance {
+            total: d(total),
+            reserved: d(reserved),
+            available: d(total) - d(reserved),
+        }
+    }
+
+    #[test]
+    fn test_ledger() {
+        let aapl = Symbol::new("AAPL").unwrap();
+        let (alice, bob) = (Uuid::new_v4(), Uuid::new_v4());
+        let mut ledger = Ledger::new();
+        let cash = |amount| TransferRequest {
+            symbol: None,
+            amount: d(amount),
+        };
+        ledger.transfer(alice, cash("1000")).unwrap();
+        let shares = TransferRequest {
+            symbol: Some(aapl),
+            amount: d("10"),
+        };
+        ledger.transfer(bob, shares).unwrap();
+        assert!(matches!(
+            ledger.check_transfer(alice, cash("-1000.01")),
+            Err(Error::InsufficientBalance(_))
+        ));
+        // Transfers have to fit, and can't overflow a balance once they're journaled.
+        let huge = TransferRequest {
+            symbol: None,
+            amount: Decimal::new(i128::MAX, 0),
+        };
+        assert_eq!(ledger.check_transfer(alice, huge), Err(Error::Overflow));
+        assert!(matches!(huge.validate(None), Err(Error::InvalidRequest(_))));
+        assert!(cash("1000000000000000").validate(None).is_ok());
+        let instrument: Instrument =
+            serde_json::from_str(r#"{"symbol": "AAPL", "amount_scale": 1}"#).unwrap();
+        let shares = |amount| TransferRequest {
+            symbol: Some(aapl),
+            amount: d(amount),
+        };
+        let validated = shares("2.5").validate(Some(&instrument)).unwrap();
+        assert_eq!(validated.amount.to_string(), "2.5");
+        for amount in ["2.25", "0", "100000000000000.1"].iter() {
+            assert!(matches!(
+                shares(amount).validate(Some(&instrument)),
+                Err(Error::InvalidRequest(_))
+            ));
+        }
+
+        // Bob offers 8 at 12.5, what he can't sell again until it trades or is cancelled.
+        let mut sell_book = OrderBook::new(Side::Sell);
+        let sell = order(bob, Side::Sell, "12.5", "8");
+        ledger
+            .reserve(bob, Hold::for_order(&sell).unwrap())
+            .unwrap();
+        sell_book.add_order(sell).unwrap();
+        let events = sell_book.take_events();
+        ledger.settle(aapl, bob, &events, &[], &[]).unwrap();
+        ledger
+            .release(bob, Hold::for_order(&sell).unwrap())
+            .unwrap();
+        assert_eq!(ledger.balances(bob).instruments[&aapl], balance("10", "8"));
+        let too_many = order(bob, Side::Sell, "12", "3");
+        let e = ledger.reserve(bob, Hold::for_order(&too_many).unwrap());
+        assert_eq!(
+            e.unwrap_err().to_string(),
+            "not enough AAPL: 3 is needed and only 2 is available"
+        );
+
+        // Alice buys 5 of it, reserving enough for all 5 at her limit and the taker fee on
+        // that until it's settled. She pays 10 basis points and Bob gets a 2 point rebate.
+        let schedule = FeeSchedule {
+            rates: Rates {
+                maker_bps: d("-2"),
+                taker_bps: d("10"),
+            },
+            ..FeeSchedule::default()
+        };
+        let buy = order(alice, Side::Buy, "13", "5");
+        let hold = Hold::for_order(&buy).unwrap();
+        let hold = ledger.with_taker_fee(&schedule, alice, hold).unwrap();
+        ledger.reserve(alice, hold).unwrap();
+        assert_eq!(ledger.balances(alice).cash, balance("1000", "65.065"));
+        let fr = sell_book
+            .fill_order(buy, TimeInForce::GoodTillCancel)
+            .unwrap();
+        let events = sell_book.take_events();
+        let fees = ledger.fees(&schedule, alice, &fr.trades).unwrap();
+        let charged: Vec<_> = fees.iter().map(|fee| (fee.account, fee.amount)).collect();
+        assert_eq!(charged, vec![(alice, d("0.0625")), (bob, d("-0.0125"))]);
+        // Failing part of the way through settles none of it.
+        let mut bad_fees = fees.clone();
+        bad_fees[1].amount = Decimal::MAX;
+        let before = (ledger.balances(alice), ledger.balances(bob));
+        assert_eq!(
+            ledger.settle(aapl, alice, &events, &fr.trades, &bad_fees),
+            Err(Error::Overflow)
+        );
+        assert_eq!((ledger.balances(alice), ledger.balances(bob)), before);
+        assert!(ledger.positions(alice).is_empty());
+        assert!(ledger.fee_ledger().fees(alice).is_empty());
+        ledger
+            .settle(aapl, alice, &events, &fr.trades, &fees)
+            .unwrap();
+        ledger.release(alice, hold).unwrap();
+        assert_eq!(ledger.fee_ledger().volume(bob, 0).unwrap(), d("62.5"));
+        assert_eq!(ledger.balances(alice).cash, balance("937.4375", "0"));
+        assert_eq!(ledger.balances(alice).instruments[&aapl], balance("5", "0"));
+        assert_eq!(ledger.balances(bob).cash, balance("62.5125", "0"));
+        assert_eq!(ledger.balances(bob).instruments[&aapl], balance("5", "3"));
+        let position = |net: &str| Position {
+            traded_net: d(net),
+            avg_price: Some(d("12.5")),
+            realized_pnl: Decimal::ZERO,
+        };
+        assert_eq!(ledger.positions(alice)[&aapl], position("5"));
+        // Bob still holds 5 of the 10 he deposited, but positions are only from trading.
+        assert_eq!(ledger.positions(bob)[&aapl], position("-5"));
+
+        // A bid resting on the book keeps its cash reserved.
+        let mut buy_book = OrderBook::new(Side::Buy);
+        let bid = order(alice, Side::Buy, "12", "2");
+        ledger
+            .reserve(alice, Hold::for_order(&bid).unwrap())
+            .unwrap();
+        buy_book.add_order(bid).unwrap();
+        let events = buy_book.take_events();
+        ledger.settle(aapl, alice, &events, &[], &[]).unwrap();
+        ledger
+            .release(alice, Hold::for_order(&bid).unwrap())
+            .unwrap();
+        assert_eq!(ledger.balances(alice).cash, balance("937.4375", "24"));
+        assert!(ledger.check_transfer(alice, cash("-913.4375")).is_ok());
+
+        // Reducing Bob's order releases some, cancelling Alice's releases all of hers.
+        let amend = AmendRequest {
+            price: None,
+            amount: Some(d("1")),
+        };
+        sell_book.amend_order(sell.id, amend).unwrap();
+        let events = sell_book.take_events();
+        ledger.settle(aapl, bob, &events, &[], &[]).unwrap();
+        assert_eq!(ledger.balances(bob).instruments[&aapl], balance("5", "1"));
+        buy_book.remove_order(bid.id).unwrap();
+        let events = buy_book.take_events();
+        ledger.settle(aapl, alice, &events, &[], &[]).unwrap();
+        assert_eq!(ledger.balances(alice).cash, balance("937.4375", "0"));
+
+        // Delisting releases whatever is still resting.
+        ledger.delist(aapl).unwrap();
+        assert_eq!(ledger.balances(bob).instruments[&aapl], balance("5", "0"));
+    }
+}
@@ -0,0 +1,1077 @@
+use crate::decimal::Decimal;
+use crate::error::Error;
+use crate::instruments::InstrumentStatus;
+use crate::journal::Record;
+use crate::order_book::{
+    now_millis, OrderEvent, OrderRequest, OrderStatus, OrderType, Side, Symbol, TimeInForce, Trade,
+};
+use crate::schema::{journal_progress, order_transitions, orders, trades};
+use diesel::deserialize::{self, FromSql};
+use diesel::pg::data_types::PgNumeric;
+use diesel::pg::Pg;
+use diesel::prelude::*;
+use diesel::r2d2::{ConnectionManager, Pool, PooledConnection};
+use diesel::serialize::{self, Output, ToSql};
+use diesel::sql_types::Numeric;
+use log::{error, info, warn};
+use serde::de::DeserializeOwned;
+use serde::{Deserialize, Serialize};
+use std::io::Write;
+use std::sync::mpsc::{self, Receiver, Sender};
+use std::sync::Mutex;
+use std::thread;
+use std::time::Duration;
+use uuid::Uuid;
+
+embed_migrations!();
+
+// Most records written to the database in one transaction.
+const MAX_BATCH: usize = 1000;
+// How long to wait before trying a write again after it failed.
+const RETRY_DELAY: Duration = Duration::from_secs(1);
+pub const DEFAULT_HISTORY_LIMIT: usize = 100;
+pub const MAX_HISTORY_LIMIT: usize = 1000;
+
+// A Decimal as a Postgres NUMERIC, which holds it exactly.
+#[derive(Debug, Copy, Clone, AsExpression, FromSqlRow)]
+#[sql_type = "Numeric"]
+pub struct PgDecimal(pub Decimal);
+
+impl ToSql<Numeric, Pg> for PgDecimal {
+    fn to_sql<W: Write>(&self, out: &mut Output<W, Pg>) -> serialize::Result {
+        ToSql::<Numeric, Pg>::to_sql(&to_numeric(self.0), out)
+    }
+}
+
+impl FromSql<Numeric, Pg> for PgDecimal {
+    fn from_sql(bytes: Option<&[u8]>) -> deserialize::Result<Self> {
+        let numeric = PgNumeric::from_sql(bytes)?;
+        Ok(PgDecimal(from_numeric(&numeric)?))
+    }
+}
+
+// NUMERIC keeps its digits in base 10000, in groups of four decimal digits either side of
+// the decimal point. weight is the power of 10000 of the first group.
+fn to_numeric(value: Decimal) -> PgNumeric {
+    let text = value.to_string();
+    let (negative, text) = match text.strip_prefix('-') {
+        Some(text) => (true, text),
+        None => (false, text.as_str()),
+    };
+    let (whole, fraction) = match text.find('.') {
+        Some(i) => (&text[..i], &text[i + 1..]),
+        None => (text, ""),
+    };
+    let whole = format!("{:0>width$}", whole, width = whole.len().div_ceil(4) * 4);
+    let fraction = format!(
+        "{:0<width$}",
+        fraction,
+        width = fraction.len().div_ceil(4) * 4
+    );
+    let mut digits: Vec<i16> = format!("{}{}", whole, fraction)
+        .as_bytes()
+        .chunks(4)
+        .map(|group| std::str::from_utf8(group).unwrap().parse().unwrap())
+        .collect();
+    let mut weight = (whole.len() / 4) as i16 - 1;
+    while digits.first() == Some(&0) {
+        digits.remove(0);
+        weight -= 1;
+    }
+    while digits.last() == Some(&0) {
+        digits.pop();
+    }
+    if digits.is_empty() {
+        weight = 0;
+    }
+    let scale = value.scale() as u16;
+    if negative {
+        PgNumeric::Negative {
+            weight,
+            scale,
+            digits,
+        }
+    } else {
+        PgNumeric::Positive {
+            weight,
+            scale,
+            digits,
+        }
+    }
+}
+
+fn from_numeric(numeric: &PgNumeric) -> Result<Decimal, &'static str> {
+    let (sign, weight, scale, digits) = match numeric {
+        PgNumeric::Positive {
+            weight,
+            scale,
+            digits,
+        } => ("", *weight as i32, *scale as usize, digits),
+        PgNumeric::Negative {
+            weight,
+            scale,
+            digits,
+        } => ("-", *weight as i32, *scale as usize, digits),
+        PgNumeric::NaN => return Err("NaN is not a decimal"),
+    };
+    // The group for 10000^power, zero if it isn't stored.
+    let group = |power: i32| -> i16 {
+        let i = weight - power;
+        if i < 0 || i as usize >= digits.len() {
+            0
+        } else {
+            digits[i as usize]
+        }
+    };
+    let mut whole = String::new();
+    for power in (0..=weight.max(0)).rev() {
+        whole.push_str(&format!("{:04}", group(power)));
+    }
+    let whole = whole.trim_start_matches('0');
+    let whole = if whole.is_empty() { "0" } else { whole };
+    let mut fraction = String::new();
+    for power in 1..=scale.div_ceil(4) as i32 {
+        fraction.push_str(&format!("{:04}", group(-power)));
+    }
+    fraction.truncate(scale);
+    let text = if fraction.is_empty() {
+        format!("{}{}", sign, whole)
+    } else {
+        format!("{}{}.{}", sign, whole, fraction)
+    };
+    text.parse()
+}
+
+// Enums are stored by the names they have in the API.
+fn name<T: Serialize>(value: &T) -> String {
+    match serde_json::to_value(value) {
+        Ok(serde_json::Value::String(name)) => name,
+        _ => unreachable!("not a unit variant"),
+    }
+}
+
+fn from_name<T: DeserializeOwned>(name: &str) -> Result<T, Error> {
+    serde_json::from_value(serde_json::Value::String(name.to_string()))
+        .map_err(|e| Error::DatabaseFailed(e.to_string()))
+}
+
+fn parse_symbol(symbol: &str) -> Result<Symbol, Error> {
+    Symbol::new(symbol).map_err(|e| Error::DatabaseFailed(e.to_string()))
+}
+
+fn database_error<E: std::fmt::Display>(e: E) -> Error {
+    Error::DatabaseFailed(e.to_string())
+}
+
+// What happened to an order.
+#[derive(Serialize, Deserialize, Debug, Copy, Clone, PartialEq)]
+pub enum Change {
+    // Submitted and matched, whatever was left rested or was cancelled.
+    Accepted,
+    // Traded against an incoming order while resting.
+    Executed,
+    Amended,
+    Cancelled,
+}
+
+// An order as the database has it, remaining is what's still resting on the book.
+#[derive(Serialize, Deserialize, Debug, Copy, Clone, PartialEq)]
+pub struct HistoricalOrder {
+    pub id: Uuid,
+    // Who placed it, None if it was placed before there were accounts.
+    pub account: Option<Uuid>,
+    pub symbol: Symbol,
+    pub side: Side,
+    pub order_type: OrderType,
+    pub time_in_force: TimeInForce,
+    pub price: Option<Decimal>,
+    pub protection_price: Option<Decimal>,
+    pub amount: Decimal,
+    pub filled: Decimal,
+    pub remaining: Decimal,
+    pub cancelled: Decimal,
+    pub status: OrderStatus,
+    // Milliseconds since the unix epoch.
+    pub created_at: u64,
+    pub updated_at: u64,
+}
+
+// A change to an order and how it was left.
+#[derive(Serialize, Deserialize, Debug, Copy, Clone, PartialEq)]
+pub struct OrderTransition {
+    pub change: Change,
+    pub status: OrderStatus,
+    pub price: Option<Decimal>,
+    pub filled: Decimal,
+    pub remaining: Decimal,
+    pub cancelled: Decimal,
+    pub timestamp: u64,
+}
+
+// Which orders or trades to look up, newest first. before is in milliseconds since the unix
+// epoch, account only applies to orders and order only applies to trades.
+#[derive(Debug, Copy, Clone)]
+pub struct HistoryQuery {
+    pub account: Option<Uuid>,
+    pub symbol: Option<Symbol>,
+    pub order: Option<Uuid>,
+    pub before: Option<u64>,
+    pub limit: usize,
+}
+
+#[derive(Queryable, Insertable, AsChangeset, Debug, Clone)]
+#[table_name = "orders"]
+struct OrderRow {
+    id: Uuid,
+    symbol: String,
+    side: String,
+    order_type: String,
+    time_in_force: String,
+    price: Option<PgDecimal>,
+    protection_price: Option<PgDecimal>,
+    amount: PgDecimal,
+    filled: PgDecimal,
+    remaining: PgDecimal,
+    cancelled: PgDecimal,
+    status: String,
+    created_at: i64,
+    updated_at: i64,
+    account: Option<Uuid>,
+}
+
+impl OrderRow {
+    fn to_order(&self) -> Result<HistoricalOrder, Error> {
+        Ok(HistoricalOrder {
+            id: self.id,
+            account: self.account,
+            symbol: parse_symbol(&self.symbol)?,
+            side: from_name(&self.side)?,
+            order_type: from_name(&self.order_type)?,
+            time_in_force: from_name(&self.time_in_force)?,
+            price: self.price.map(|price| price.0),
+            protection_price: self.protection_price.map(|price| price.0),
+            amount: self.amount.0,
+            filled: self.filled.0,
+            remaining: self.remaining.0,
+            cancelled: self.cancelled.0,
+            status: from_name(&self.status)?,
+            created_at: self.created_at as u64,
+            updated_at: self.updated_at as u64,
+        })
+    }
+}
+
+#[derive(Insertable)]
+#[table_name = "order_transitions"]
+struct NewTransition {
+    order_id: Uuid,
+    change: String,
+    status: String,
+    price: Option<PgDecimal>,
+    filled: PgDecimal,
+    remaining: PgDecimal,
+    cancelled: PgDecimal,
+    timestamp: i64,
+}
+
+#[derive(Queryable)]
+struct TransitionRow {
+    _id: i64,
+    _order_id: Uuid,
+    change: String,
+    status: String,
+    price: Option<PgDecimal>,
+    filled: PgDecimal,
+    remaining: PgDecimal,
+    cancelled: PgDecimal,
+    timestamp: i64,
+}
+
+impl TransitionRow {
+    fn to_transition(&self) -> Result<OrderTransition, Error> {
+        Ok(OrderTransition {
+            change: from_name(&self.change)?,
+            status: from_name(&self.status)?,
+            price: self.price.map(|price| price.0),
+            filled: self.filled.0,
+            remaining: self.remaining.0,
+            cancelled: self.cancelled.0,
+            timestamp: self.timestamp as u64,
+        })
+    }
+}
+
+#[derive(Queryable, Insertable)]
+#[table_name = "trades"]
+struct TradeRow {
+    id: Uuid,
+    symbol: String,
+    aggressor_id: Uuid,
+    passive_id: Uuid,
+    aggressor_side: String,
+    price: PgDecimal,
+    amount: PgDecimal,
+    timestamp: i64,
+}
+
+impl TradeRow {
+    fn new(trade: &Trade) -> TradeRow {
+        TradeRow {
+            id: trade.id,
+            symbol: trade.symbol.to_string(),
+            aggressor_id: trade.aggressor_id,
+            passive_id: trade.passive_id,
+            aggressor_side: name(&trade.aggressor_side),
+            price: PgDecimal(trade.price),
+            amount: PgDecimal(trade.amount),
+            timestamp: trade.timestamp as i64,
+        }
+    }
+
+    fn to_trade(&self) -> Result<Trade, Error> {
+        Ok(Trade {
+            id: self.id,
+            symbol: parse_symbol(&self.symbol)?,
+            aggressor_id: self.aggressor_id,
+            passive_id: self.passive_id,
+            aggressor_side: from_name(&self.aggressor_side)?,
+            price: self.price.0,
+            amount: self.amount.0,
+            timestamp: self.timestamp as u64,
+        })
+    }
+}
+
+// A journaled record waiting to be written, number is how many records the journal had
+// once it was appended.
+struct Pending {
+    number: usize,
+    timestamp: u64,
+    record: Record,
+}
+
+type ConnectionPool = Pool<ConnectionManager<PgConnection>>;
+
+// Order and trade history in Postgres. The journal stays the record of what happened, the
+// database is written from it in the background so matching never waits on it, and keeps
+// track of how much of it it has so nothing is missed or written twice across restarts.
+pub struct Database {
+    pool: ConnectionPool,
+    sender: Mutex<Sender<Pending>>,
+}
+
+impl Database {
+    // Connects, brings the schema up to date and starts writing.
+    pub fn connect(url: &str) -> Result<Database, String> {
+        let pool = Pool::builder()
+            .build(ConnectionManager::<PgConnection>::new(url))
+            .map_err(|e| format!("unable to connect to the database: {}", e))?;
+        let connection = pool.get().map_err(|e| e.to_string())?;
+        embedded_migrations::run(&connection)
+            .map_err(|e| format!("unable to migrate the database: {}", e))?;
+        let (sender, receiver) = mpsc::channel();
+        let writer_pool = pool.clone();
+        thread::Builder::new()
+            .name("database writer".to_string())
+            .spawn(move || write_batches(writer_pool, receiver))
+            .map_err(|e| e.to_string())?;
+        Ok(Database {
+            pool,
+            sender: Mutex::new(sender),
+        })
+    }
+
+    // Queues a record that has just been journaled, number is how many records the journal
+    // now has. Records must be queued in the order they were journaled.
+    pub fn write(&self, number: usize, record: Record) {
+        let pending = Pending {
+            number,
+            timestamp: now_millis(),
+            record,
+        };
+        // The writer only stops if it panics, in which case there's nothing to be done here
+        // but say so. The journal still has it.
+        if self.sender.lock().unwrap().send(pending).is_err() {
+            error!(
+                "the database writer has stopped, journal record {} won't be written",
+                number
+            );
+        }
+    }
+
+    // Queues whatever journal records the database doesn't have yet, e.g. those journaled
+    // but not yet written when the exchange stopped. Has to be called before any new
+    // records are written. Their changes are timestamped with when they're queued.
+    pub fn catch_up(&self, records: &[Record]) -> Result<(), String> {
+        let connection = self.pool.get().map_err(|e| e.to_string())?;
+        let progress = progress(&connection).map_err(|e| e.to_string())?;
+        if progress > records.len() {
+            // Order ids are never reused so what's there can stay.
+            warn!(
+                "database has {} journal records but the journal has {}, taking it as a new journal",
+                progress,
+                records.len()
+            );
+            diesel::update(journal_progress::table)
+                .set(journal_progress::records.eq(records.len() as i64))
+                .execute(&connection)
+                .map_err(|e| e.to_string())?;
+            return Ok(());
+        }
+        info!(
+            "writing {} journal records to the database",
+            records.len() - progress
+        );
+        for (i, record) in records.iter().enumerate().skip(progress) {
+            self.write(i + 1, record.clone());
+        }
+        Ok(())
+    }
+
+    pub fn orders(&self, query: &HistoryQuery) -> Result<Vec<HistoricalOrder>, Error> {
+        let mut select = orders::table.into_boxed();
+        if let Some(account) = query.account {
+            select = select.filter(orders::account.eq(account));
+        }
+        if let Some(symbol) = query.symbol {
+            select = select.filter(orders::symbol.eq(symbol.to_string()));
+        }
+        if let Some(before) = query.before {
+            select = select.filter(orders::created_at.lt(before as i64));
+        }
+        let rows: Vec<OrderRow> = select
+            .order((orders::created_at.desc(), orders::id))
+            .limit(query.limit as i64)
+            .load(&self.connection()?)
+            .map_err(database_error)?;
+        rows.iter().map(OrderRow::to_order).collect()
+    }
+
+    pub fn order(&self, id: Uuid) -> Result<HistoricalOrder, Error> {
+        let row: Option<OrderRow> = orders::table
+            .find(id)
+            .first(&self.connection()?)
+            .optional()
+            .map_err(database_error)?;
+        row.ok_or(Error::NoSuchOrder)?.to_order()
+    }
+
+    // Every change to the order, oldest first.
+    pub fn transitions(&self, id: Uuid) -> Result<Vec<OrderTransition>, Error> {
+        let connection = self.connection()?;
+        let rows: Vec<TransitionRow> = order_transitions::table
+            .filter(order_transitions::order_id.eq(id))
+            .order(order_transitions::id)
+            .load(&connection)
+            .map_err(database_error)?;
+        if rows.is_empty() {
+            return Err(Error::NoSuchOrder);
+        }
+        rows.iter().map(TransitionRow::to_transition).collect()
+    }
+
+    pub fn trades(&self, query: &HistoryQuery) -> Result<Vec<Trade>, Error> {
+        let mut select = trades::table.into_boxed();
+        if let Some(symbol) = query.symbol {
+            select = select.filter(trades::symbol.eq(symbol.to_string()));
+        }
+        if let Some(order) = query.order {
+            select = select.filter(
+                trades::aggressor_id
+                    .eq(order)
+                    .or(trades::passive_id.eq(order)),
+            );
+        }
+        if let Some(before) = query.before {
+            select = select.filter(trades::timestamp.lt(before as i64));
+        }
+        let rows: Vec<TradeRow> = select
+            .order((trades::timestamp.desc(), trades::id))
+            .limit(query.limit as i64)
+            .load(&self.connection()?)
+            .map_err(database_error)?;
+        rows.iter().map(TradeRow::to_trade).collect()
+    }
+
+    fn connection(&self) -> Result<PooledConnection<ConnectionManager<PgConnection>>, Error> {
+        self.pool.get().map_err(database_error)
+    }
+}
+
+fn progress(connection: &PgConnection) -> QueryResult<usize> {
+    let records: i64 = journal_progress::table
+        .select(journal_progress::records)
+        .first(connection)?;
+    Ok(records as usize)
+}
+
+// Writes whatever has been queued in batches until the exchange stops.
+fn write_batches(pool: ConnectionPool, receiver: Receiver<Pending>) {
+    while let Ok(first) = receiver.recv() {
+        let mut batch = vec![first];
+        batch.extend(receiver.try_iter().take(MAX_BATCH - 1));
+        // It's all in the journal, so it's safe to keep trying for as long as it takes.
+        loop {
+            let written = pool
+                .get()
+                .map_err(|e| e.to_string())
+                .and_then(|connection| write_batch(&connection, &batch).map_err(|e| e.to_string()));
+            match written {
+                Ok(()) => break,
+                Err(e) => {
+                    error!("unable to write to the database, trying again: {}", e);
+                    thread::sleep(RETRY_DELAY);
+                }
+            }
+        }
+    }
+}
+
+fn write_batch(connection: &PgConnection, batch: &[Pending]) -> QueryResult<()> {
+    connection.transaction(|| {
+        let progress = progress(connection)?;
+        let mut written = progress;
+        for pending in batch.iter().filter(|pending| pending.number > progress) {
+            apply(connection, pending)?;
+            written = pending.number;
+        }
+        if written != progress {
+            diesel::update(journal_progress::table)
+                .set(journal_progress::records.eq(written as i64))
+                .execute(connection)?;
+        }
+        Ok(())
+    })
+}
+
+fn apply(connection: &PgConnection, pending: &Pending) -> QueryResult<()> {
+    let timestamp = pending.timestamp;
+    match &pending.record {
+        Record::Order {
+            id,
+            account,
+            request,
+            events,
+            trades,
+            ..
+        } => {
+            insert_order(
+                connection, *id, *account, request, events, trades, timestamp,
+            )?;
+            for event in events.iter() {
+                if let OrderEvent::Execute { id, amount, .. } = *event {
+                    update_order(connection, id, Change::Executed, timestamp, |order| {
+                        order.filled.0 = order.filled.0.checked_add(amount)?;
+                        order.remaining.0 = order.remaining.0.checked_sub(amount)?;
+                        order.status = name(&if order.remaining.0.is_zero() {
+                            OrderStatus::Filled
+                        } else {
+                            OrderStatus::PartiallyFilled
+                        });
+                        Some(())
+                    })?;
+                }
+            }
+        }
+        Record::Cancel { events, .. } => {
+            for event in events.iter() {
+                if let OrderEvent::Cancel { id, amount, .. } = *event {
+                    cancel_order(connection, id, amount, timestamp)?;
+                }
+            }
+        }
+        // Either reduced in place or requeued, a cancel followed by an add.
+        Record::Amend { id, events, .. } => {
+            let amended = events.iter().rev().find_map(|event| match *event {
+                OrderEvent::Modify { price, amount, .. }
+                | OrderEvent::Add { price, amount, .. } => Some((price, amount)),
+                _ => None,
+            });
+            if let Some((price, amount)) = amended {
+                update_order(connection, *id, Change::Amended, timestamp, |order| {
+                    order.price = Some(PgDecimal(price));
+                    order.remaining = PgDecimal(amount);
+                    Some(())
+                })?;
+            }
+        }
+        // Delisting throws away everything on the books.
+        Record::SetStatus {
+            symbol,
+            status: InstrumentStatus::Delisted,
+        } => {
+            let resting: Vec<OrderRow> = orders::table
+                .filter(orders::symbol.eq(symbol.to_string()))
+                .filter(orders::remaining.gt(PgDecimal(Decimal::ZERO)))
+                .load(connection)?;
+            for order in resting {
+                cancel_order(connection, order.id, order.remaining.0, timestamp)?;
+            }
+        }
+        Record::SetStatus { .. }
+        | Record::AddInstrument { .. }
+        | Record::OpenAccount { .. }
+        | Record::AddKey { .. }
+        | Record::RevokeKey { .. }
+        | Record::Transfer { .. } => {}
+    }
+    Ok(())
+}
+
+// An incoming order, after matching. Whatever it didn't fill or leave on the book was
+// cancelled. One whose amounts overflow is logged and left out, along with its trades.
+fn insert_order(
+    connection: &PgConnection,
+    id: Uuid,
+    account: Uuid,
+    request: &OrderRequest,
+    events: &[OrderEvent],
+    trades: &[Trade],
+    timestamp: u64,
+) -> QueryResult<()> {
+    let remaining = events
+        .iter()
+        .find_map(|event| match *event {
+            OrderEvent::Add {
+                id: added, amount, ..
+            } if added == id => Some(amount),
+            _ => None,
+        })
+        .unwrap_or(Decimal::ZERO);
+    let amounts = trades
+        .iter()
+        .try_fold(Decimal::ZERO, |filled, trade| {
+            filled.checked_add(trade.amount)
+        })
+        .and_then(|filled| {
+            let cancelled = request.amount.checked_sub(filled)?.checked_sub(remaining)?;
+            Some((filled, cancelled))
+        });
+    let (filled, cancelled) = match amounts {
+        Some(amounts) => amounts,
+        None => {
+            error!("order {}'s amounts overflow, it can't be recorded", id);
+            return Ok(());
+        }
+    };
+    let status = if remaining.is_positive() {
+        if filled.is_zero() {
+            OrderStatus::New
+        } else {
+            OrderStatus::PartiallyFilled
+        }
+    } else if cancelled.is_zero() {
+        OrderStatus::Filled
+    } else {
+        OrderStatus::Cancelled
+    };
+    let order = OrderRow {
+        id,
+        symbol: request.symbol.to_string(),
+        side: name(&request.side),
+        order_type: name(&request.order_type),
+        time_in_force: name(&request.time_in_force),
+        price: request.price.map(PgDecimal),
+        protection_price: request.protection_price.map(PgDecimal),
+        amount: PgDecimal(request.amount),
+        filled: PgDecimal(filled),
+        remaining: PgDecimal(remaining),
+        cancelled: PgDecimal(cancelled),
+        status: name(&status),
+        created_at: timestamp as i64,
+        updated_at: timestamp as i64,
+        account: Some(account).filter(|account| !account.is_nil()),
+    };
+    diesel::insert_into(orders::table)
+        .values(&order)
+        .execute(connection)?;
+    insert_transition(connection, &order, Change::Accepted)?;
+    let rows: Vec<TradeRow> = trades.iter().map(TradeRow::new).collect();
+    diesel::insert_into(trades::table)
+        .values(&rows)
+        .execute(connection)?;
+    Ok(())
+}
+
+fn cancel_order(
+    connection: &PgConnection,
+    id: Uuid,
+    amount: Decimal,
+    timestamp: u64,
+) -> QueryResult<()> {
+    update_order(connection, id, Change::Cancelled, timestamp, |order| {
+        order.cancelled.0 = order.cancelled.0.checked_add(amount)?;
+        order.remaining = PgDecimal(Decimal::ZERO);
+        order.status = name(&OrderStatus::Cancelled);
+        Some(())
+    })
+}
+
+// The update returns None if the order's amounts would overflow, which is logged and
+// leaves the order as it was.
+fn update_order<F: FnOnce(&mut OrderRow) -> Option<()>>(
+    connection: &PgConnection,
+    id: Uuid,
+    change: Change,
+    timestamp: u64,
+    update: F,
+) -> QueryResult<()> {
+    let order: Option<OrderRow> = orders::table.find(id).first(connection).optional()?;
+    let mut order = match order {
+        Some(order) => order,
+        None => {
+            warn!(
+                "order {} isn't in the database, can't record {:?}",
+                id, change
+            );
+            return Ok(());
+        }
+    };
+    if update(&mut order).is_none() {
+        error!("order {}'s amounts overflow, can't record {:?}", id, change);
+        return Ok(());
+    }
+    order.updated_at = timestamp as i64;
+    diesel::update(orders::table.find(id))
+        .set(&order)
+        .execute(connection)?;
+    insert_transition(connection, &order, change)
+}
+
+fn insert_transition(
+    connection: &PgConnection,
+    order: &OrderRow,
+    change: Change,
+) -> QueryResult<()> {
+    let transition = NewTransition {
+        order_id: order.id,
+        change: name(&change),
+        status: order.status.clone(),
+        price: order.price,
+        filled: order.filled,
+        remaining: order.remaining,
+        cancelled: order.cancelled,
+        timestamp: order.updated_at,
+    };
+    diesel::insert_into(order_transitions::table)
+        .values(&transition)
+        .execute(connection)?;
+    Ok(())
+}
+
+#[cfg(test)]
+mod tests {
+    use crate::db::{from_numeric, to_numeric, Change, Database, HistoryQuery};
+    use crate::decimal::Decimal;
+    use crate::instruments::InstrumentStatus;
+    use crate::journal::Record;
+    use crate::order_book::{OrderEvent, OrderStatus, Side, Symbol, Trade};
+    use crate::schema::journal_progress;
+    use diesel::pg::data_types::PgNumeric;
+    use diesel::prelude::*;
+    use std::fs;
+    use std::path::PathBuf;
+    use std::process::Command;
+    use std::thread;
+    use std::time::{Duration, Instant};
+    use uuid::Uuid;
+
+    fn d(s: &str) -> Decimal {
+        s.parse().unwrap()
+    }
+
+    // A throwaway Postgres, listening only on a socket in its own directory.
+    struct TestPostgres {
+        dir: PathBuf,
+        url: String,
+    }
+
+    impl TestPostgres {
+        // None if Postgres isn't installed.
+        fn start() -> Option<TestPostgres> {
+            if Command::new("pg_ctl").arg("--version").output().is_err() {
+                return None;
+            }
+            let dir = std::env::temp_dir().join(format!("postgres-{}", Uuid::new_v4()));
+            fs::create_dir(&dir).unwrap();
+            if root() {
+                run(Command::new("chown").arg("postgres").arg(&dir));
+            }
+            let data = dir.join("data");
+            run(postgres_command("initdb").arg("-D").arg(&data).args([
+                "-U",
+                "postgres",
+                "--auth=trust",
+            ]));
+            run(postgres_command("pg_ctl")
+                .arg("-D")
+                .arg(&data)
+                .arg("-l")
+                .arg(dir.join("log"))
+                .arg("-o")
+                .arg(format!("-k {} -c listen_addresses=''", dir.display()))
+                .args(["-w", "start"]));
+            let url = format!("postgres:///postgres?host={}&user=postgres", dir.display());
+            Some(TestPostgres { dir, url })
+        }
+    }
+
+    impl Drop for TestPostgres {
+        fn drop(&mut self) {
+            run(postgres_command("pg_ctl")
+                .arg("-D")
+                .arg(self.dir.join("data"))
+                .args(["-m", "immediate", "stop"]));
+            fs::remove_dir_all(&self.dir).unwrap();
+        }
+    }
+
+    fn root() -> bool {
+        let output = Command::new("id").arg("-u").output().unwrap();
+        String::from_utf8_lossy(&output.stdout).trim() == "0"
+    }
+
+    // Postgres won't run as root.
+    fn postgres_command(program: &str) -> Command {
+        if root() {
+            let mut command = Command::new("runuser");
+            command.args(["-u", "postgres", "--", program]);
+            command
+        } else {
+            Command::new(program)
+        }
+    }
+
+    fn run(command: &mut Command) {
+        let output = command.output().unwrap();
+        assert!(
+            output.status.success(),
+            "{:?} failed: {}",
+            command,
+            String::from_utf8_lossy(&output.stderr)
+        );
+    }
+
+    fn query(symbol: Option<Symbol>, order: Option<Uuid>) -> HistoryQuery {
+        HistoryQuery {
+            account: None,
+            symbol,
+            order,
+            before: None,
+            limit: 100,
+        }
+    }
+
+    fn wait_for(database: &Database, records: usize) {
+        let deadline = Instant::now() + Duration::from_secs(10);
+        loop {
+            let connection = database.pool.get().unwrap();
+            let written: i64 = journal_progress::table
+                .select(journal_progress::records)
+                .first(&connection)
+                .unwrap();
+            if written as usize == records {
+                return;
+            }
+            assert!(Instant::now() < deadline, "only {} written", written);
+            thread::sleep(Duration::from_millis(10));
+        }
+    }
+
+    #[test]
+    fn test_numeric() {
+        assert_eq!(
+            to_numeric(d("12345.678")),
+            PgNumeric::Positive {
+                weight: 1,
+                scale: 3,
+                digits: vec![1, 2345, 6780],
+            }
+        );
+        assert_eq!(
+            to_numeric(d("-0.00001")),
+            PgNumeric::Negative {
+                weight: -2,
+                scale: 5,
+                digits: vec![1000],
+            }
+        );
+        for value in [
+            "0",
+            "0.00",
+            "1",
+            "10000",
+            "-187.25",
+            "0.50",
+            "123456789.000000001",
+            "170141183460469231731687303715884105727",
+        ]
+        .iter()
+        {
+            let round_trip = from_numeric(&to_numeric(d(value))).unwrap();
+            assert_eq!(round_trip.to_string(), *value);
+        }
+        assert!(from_numeric(&PgNumeric::NaN).is_err());
+    }
+
+    #[test]
+    fn test_database() {
+        let postgres = match TestPostgres::start() {
+            Some(postgres) => postgres,
+            None => {
+                eprintln!("Postgres isn't installed, skipping test_database");
+                return;
+            }
+        };
+        let aapl = Symbol::new("AAPL").unwrap();
+        let (sell, buy, other_buy) = (Uuid::new_v4(), Uuid::new_v4(), Uuid::new_v4());
+        let (seller, buyer) = (Uuid::new_v4(), Uuid::new_v4());
+        let request = |side: &str, price: &str, amount: &str| {
+            let json = format!(
+                r#"{{"symbol": "AAPL", "side": "{}", "price": "{}", "amount": "{}"}}"#,
+                side, price, amount
+            );
+            serde_json::from_str(&json).unwrap()
+        };
+        let trade = Trade {
+            id: Uuid::new_v4(),
+            symbol: aapl,
+            aggressor_id: buy,
+            passive_id: sell,
+            aggressor_side: Side::Buy,
+            price: d("10"),
+            amount: d("3"),
+            timestamp: 1,
+        };
+        let mut records = vec![
+            Record::Order {
+                id: sell,
+                account: seller,
+                request: request("Sell", "10", "5"),
+                events: vec![OrderEvent::Add {
+                    id: sell,
+                    side: Side::Sell,
+                    price: d("10"),
+                    amount: d("5"),
+                }],
+                trades: Vec::new(),
+                fees: Vec::new(),
+            },
+            Record::Order {
+                id: buy,
+                account: buyer,
+                request: request("Buy", "10", "3"),
+                events: vec![OrderEvent::Execute {
+                    id: sell,
+                    side: Side::Sell,
+                    price: d("10"),
+                    amount: d("3"),
+                }],
+                trades: vec![trade],
+                fees: Vec::new(),
+            },
+            Record::Order {
+                id: other_buy,
+                account: buyer,
+                request: request("Buy", "9", "4"),
+                events: vec![OrderEvent::Add {
+                    id: other_buy,
+                    side: Side::Buy,
+                    price: d("9"),
+                    amount: d("4"),
+                }],
+                trades: Vec::new(),
+                fees: Vec::new(),
+            },
+            Record::Amend {
+                id: other_buy,
+                symbol: aapl,
+                amend: serde_json::from_str(r#"{"amount": "2"}"#).unwrap(),
+                events: vec![OrderEvent::Modify {
+                    id: other_buy,
+                    side: Side::Buy,
+                    price: d("9"),
+                    amount: d("2"),
+                }],
+            },
+            Record::Cancel {
+                id: sell,
+                symbol: aapl,
+                events: vec![OrderEvent::Cancel {
+                    id: sell,
+                    side: Side::Sell,
+                    price: d("10"),
+                    amount: d("2"),
+                }],
+            },
+        ];
+        let database = Database::connect(&postgres.url).unwrap();
+        for (i, record) in records.iter().enumerate() {
+            database.write(i + 1, record.clone());
+        }
+        wait_for(&database, 5);
+
+        let order = database.order(sell).unwrap();
+        assert_eq!(order.status, OrderStatus::Cancelled);
+        assert_eq!(
+            (order.filled, order.remaining, order.cancelled),
+            (d("3"), d("0"), d("2"))
+        );
+        let changes: Vec<Change> = database
+            .transitions(sell)
+            .unwrap()
+            .iter()
+            .map(|transition| transition.change)
+            .collect();
+        assert_eq!(
+            changes,
+            vec![Change::Accepted, Change::Executed, Change::Cancelled]
+        );
+        assert_eq!(database.order(buy).unwrap().status, OrderStatus::Filled);
+        let order = database.order(other_buy).unwrap();
+        assert_eq!((order.status, order.remaining), (OrderStatus::New, d("2")));
+        assert_eq!(
+            database.trades(&query(Some(aapl), None)).unwrap(),
+            vec![trade]
+        );
+        assert_eq!(
+            database.trades(&query(None, Some(sell))).unwrap(),
+            vec![trade]
+        );
+        assert!(database
+            .trades(&query(None, Some(other_buy)))
+            .unwrap()
+            .is_empty());
+        let orders = database.orders(&query(Some(aapl), None)).unwrap();
+        assert_eq!(orders.len(), 3);
+        let buyers_orders = HistoryQuery {
+            account: Some(buyer),
+            ..query(None, None)
+        };
+        let ids: Vec<Uuid> = database
+            .orders(&buyers_orders)
+            .unwrap()
+            .iter()
+            .map(|order| order.id)
+            .collect();
+        assert_eq!(ids.len(), 2);
+        assert!(ids.contains(&buy) && ids.contains(&other_buy));
+        assert_eq!(database.order(sell).unwrap().account, Some(seller));
+        assert!(database.order(Uuid::new_v4()).is_err());
+
+        // Starting again only writes what's new.
+        records.push(Record::SetStatus {
+            symbol: aapl,
+            status: InstrumentStatus::Delisted,
+        });
+        let database = Database::connect(&postgres.url).unwrap();
+        database.catch_up(&records).unwrap();
+        wait_for(&database, 6);
+        assert_eq!(database.order(sell).unwrap().filled, d("3"));
+        let order = database.order(other_buy).unwrap();
+        assert_eq!(
+            (order.status, order.remaining, order.cancelled),
+            (OrderStatus::Cancelled, d("0"), d("2"))
+        );
+    }
+}
@@ -0,0 +1,447 @@
+use serde::de::{self, Visitor};
+use serde::{Deserialize, Deserializer, Serialize, Serializer};
+use std::cmp::Ordering;
+use std::fmt;
+use std::hash::{Hash, Hasher};
+use std::ops::{Add, AddAssign, Neg, Sub, SubAssign};
+use std::str::FromStr;
+
+// Most decimal places a Decimal can have.
+pub const MAX_SCALE: u32 = 18;
+
+// A fixed-point decimal number: mantissa * 10^-scale, so 187.25 is 18725 with a scale of 2.
+// All arithmetic on it is exact, the only rounding is in checked_div where the caller
+// says how many decimal places they want.
+// Values with the same value but different scales (1.5 and 1.50) are equal.
+// Serialized as a string so that JSON clients never see it as a float.
+#[derive(Copy, Clone)]
+pub struct Decimal {
+    mantissa: i128,
+    scale: u32,
+}
+
+fn pow10(exp: u32) -> Option<i128> {
+    10i128.checked_pow(exp)
+}
+
+impl Decimal {
+    pub const ZERO: Decimal = Decimal {
+        mantissa: 0,
+        scale: 0,
+    };
+
+    // Larger than any price or amount, used for market buys with no protection price.
+    pub const MAX: Decimal = Decimal {
+        mantissa: i128::MAX,
+        scale: 0,
+    };
+
+    pub fn new(mantissa: i128, scale: u32) -> Decimal {
+        assert!(scale <= MAX_SCALE, "scale {} is above {}", scale, MAX_SCALE);
+        Decimal { mantissa, scale }
+    }
+
+    pub fn scale(&self) -> u32 {
+        self.scale
+    }
+
+    pub fn is_zero(&self) -> bool {
+        self.mantissa == 0
+    }
+
+    pub fn is_positive(&self) -> bool {
+        self.mantissa > 0
+    }
+
+    pub fn is_negative(&self) -> bool {
+        self.mantissa < 0
+    }
+
+    // The same value with the given number of decimal places.
+    // None if that would lose precision or overflow.
+    pub fn rescale(self, scale: u32) -> Option<Decimal> {
+        if scale > MAX_SCALE {
+            return None;
+        }
+        if scale >= self.scale {
+            let mantissa = self.mantissa.checked_mul(pow10(scale - self.scale)?)?;
+            return Some(Decimal { mantissa, scale });
+        }
+        let divisor = pow10(self.scale - scale)?;
+        if self.mantissa % divisor != 0 {
+            return None;
+        }
+        Some(Decimal {
+            mantissa: self.mantissa / divisor,
+            scale,
+        })
+    }
+
+    // The same value with no trailing zeros after the decimal point.
+    pub fn normalize(self) -> Decimal {
+        let mut normalized = self;
+        while normalized.scale > 0 && normalized.mantissa % 10 == 0 {
+            normalized.mantissa /= 10;
+            normalized.scale -= 1;
+        }
+        normalized
+    }
+
+    // Both mantissas at the larger of the two scales.
+    fn align(self, other: Decimal) -> Option<(i128, i128, u32)> {
+        let scale = self.scale.max(other.scale);
+        let a = self.rescale(scale)?;
+        let b = other.rescale(scale)?;
+        Some((a.mantissa, b.mantissa, scale))
+    }
+
+    pub fn checked_add(self, other: Decimal) -> Option<Decimal> {
+        let (a, b, scale) = self.align(other)?;
+        Some(Decimal {
+            mantissa: a.checked_add(b)?,
+            scale,
+        })
+    }
+
+    pub fn checked_sub(self, other: Decimal) -> Option<Decimal> {
+        let (a, b, scale) = self.align(other)?;
+        Some(Decimal {
+            mantissa: a.checked_sub(b)?,
+            scale,
+        })
+    }
+
+    // Exact, the scale of the result is the sum of both scales.
+    pub fn checked_mul(self, other: Decimal) -> Option<Decimal> {
+        let product = Decimal {
+            mantissa: self.mantissa.checked_mul(other.mantissa)?,
+            scale: self.scale + other.scale,
+        };
+        if product.scale <= MAX_SCALE {
+            return Some(product);
+        }
+        // Only fine if the extra places are all zeros.
+        product.normalize().rescale(MAX_SCALE)
+    }
+
+    // self / other with scale decimal places, rounding half to even.
+    pub fn checked_div(self, other: Decimal, scale: u32) -> Option<Decimal> {
+        if other.is_zero() || scale > MAX_SCALE {
+            return None;
+        }
+        // self / other * 10^scale = self.mantissa * 10^(scale + other.scale - self.scale) / other.mantissa
+        let exp = scale as i64 + other.scale as i64 - self.scale as i64;
+        let (numerator, denominator) = if exp >= 0 {
+            (
+                self.mantissa.checked_mul(pow10(exp as u32)?)?,
+                other.mantissa,
+            )
+        } else {
+            (
+                self.mantissa,
+                other.mantissa.checked_mul(pow10((-exp) as u32)?)?,
+            )
+        };
+        let mut quotient = numerator / denominator;
+        let remainder = numerator % denominator;
+        // Compare 2 * |remainder| with |denominator| without overflowing.
+        let twice = remainder.unsigned_abs().checked_mul(2);
+        let round_away = match twice.map(|twice| twice.cmp(&denominator.unsigned_abs())) {
+            None | Some(Ordering::Greater) => true,
+            Some(Ordering::Equal) => quotient % 2 != 0,
+            Some(Ordering::Less) => false,
+        };
+        if round_away {
+            if (numerator < 0) == (denominator < 0) {
+                quotient = quotient.checked_add(1)?;
+            } else {
+                quotient = quotient.checked_sub(1)?;
+            }
+        }
+        Some(Decimal {
+            mantissa: quotient,
+            scale,
+        })
+    }
+
+    // Whether self is a whole number of other, e.g. a price on a tick.
+    pub fn is_multiple_of(self, other: Decimal) -> bool {
+        match self.align(other) {
+            Some((_, 0, _)) | None => false,
+            Some((a, b, _)) => a % b == 0,
+        }
+    }
+}
+
+impl From<u32> for Decimal {
+    fn from(value: u32) -> Decimal {
+        Decimal::new(value as i128, 0)
+    }
+}
+
+impl From<u64> for Decimal {
+    fn from(value: u64) -> Decimal {
+        Decimal::new(value as i128, 0)
+    }
+}
+
+impl From<i64> for Decimal {
+    fn from(value: i64) -> Decimal {
+        Decimal::new(value as i128, 0)
+    }
+}
+
+impl FromStr for Decimal {
+    type Err = &'static str;
+
+    // Plain decimal notation only, e.g. "187.25" or "-3", no exponents.
+    fn from_str(s: &str) -> Result<Decimal, &'static str> {
+        let (negative, digits) = match s.strip_prefix('-') {
+            Some(digits) => (true, digits),
+            None => (false, s),
+        };
+        let (whole, fraction) = match digits.find('.') {
+            Some(i) => (&digits[..i], &digits[i + 1..]),
+            None => (digits, ""),
+        };
+        if whole.is_empty() || (digits.contains('.') && fraction.is_empty()) {
+            return Err("invalid decimal");
+        }
+        if !whole
+            .bytes()
+            .chain(fraction.bytes())
+            .all(|b| b.is_ascii_digit())
+        {
+            return Err("invalid decimal");
+        }
+        if fraction.len() > MAX_SCALE as usize {
+            return Err("too many decimal places");
+        }
+        let mut mantissa: i128 = 0;
+        for b in whole.bytes().chain(fraction.bytes()) {
+            mantissa = mantissa
+                .checked_mul(10)
+                .and_then(|m| m.checked_add((b - b'0') as i128))
+                .ok_or("decimal out of range")?;
+        }
+        if negative {
+            mantissa = -mantissa;
+        }
+        Ok(Decimal::new(mantissa, fraction.len() as u32))
+    }
+}
+
+impl fmt::Display for Decimal {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        let digits = self.mantissa.unsigned_abs().to_string();
+        let sign = if self.mantissa < 0 { "-" } else { "" };
+        let scale = self.scale as usize;
+        if scale == 0 {
+            return write!(f, "{}{}", sign, digits);
+        }
+        let digits = format!("{:0>width$}", digits, width = scale + 1);
+        let (whole, fraction) = digits.split_at(digits.len() - scale);
+        write!(f, "{}{}.{}", sign, whole, fraction)
+    }
+}
+
+impl fmt::Debug for Decimal {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        fmt::Display::fmt(self, f)
+    }
+}
+
+impl PartialEq for Decimal {
+    fn eq(&self, other: &Decimal) -> bool {
+        self.cmp(other) == Ordering::Equal
+    }
+}
+
+impl Eq for Decimal {}
+
+impl PartialOrd for Decimal {
+    fn partial_cmp(&self, other: &Decimal) -> Option<Ordering> {
+        Some(self.cmp(other))
+    }
+}
+
+impl Ord for Decimal {
+    fn cmp(&self, other: &Decimal) -> Ordering {
+        match self.align(*other) {
+            Some((a, b, _)) => a.cmp(&b),
+            // Only the one with fewer decimal places can overflow when rescaled, which
+            // means it's bigger in size than anything the other one can hold.
+            None => {
+                let (bigger, sign) = if self.scale < other.scale {
+                    (Ordering::Greater, self.mantissa.signum())
+                } else {
+                    (Ordering::Less, other.mantissa.signum())
+                };
+                if sign < 0 {
+                    bigger.reverse()
+                } else {
+                    bigger
+                }
+            }
+        }
+    }
+}
+
+impl Hash for Decimal {
+    fn hash<H: Hasher>(&self, state: &mut H) {
+        let normalized = self.normalize();
+        normalized.mantissa.hash(state);
+        normalized.scale.hash(state);
+    }
+}
+
+// These panic on overflow just like the integer operators.
+impl Add for Decimal {
+    type Output = Decimal;
+
+    fn add(self, other: Decimal) -> Decimal {
+        self.checked_add(other).expect("decimal overflow")
+    }
+}
+
+impl Sub for Decimal {
+    type Output = Decimal;
+
+    fn sub(self, other: Decimal) -> Decimal {
+        self.checked_sub(other).expect("decimal overflow")
+    }
+}
+
+impl AddAssign for Decimal {
+    fn add_assign(&mut self, other: Decimal) {
+        *self = *self + other;
+    }
+}
+
+impl SubAssign for Decimal {
+    fn sub_assign(&mut self, other: Decimal) {
+        *self = *self - other;
+    }
+}
+
+impl Neg for Decimal {
+    type Output = Decimal;
+
+    fn neg(self) -> Decimal {
+        Decimal {
+            mantissa: -self.mantissa,
+            scale: self.scale,
+        }
+    }
+}
+
+impl Serialize for Decimal {
+    fn serialize<S: Serializer>(&self, serializer: S) -> Result<S::Ok, S::Error> {
+        serializer.serialize_str(&self.to_string())
+    }
+}
+
+struct DecimalVisitor;
+
+impl<'de> Visitor<'de> for DecimalVisitor {
+    type Value = Decimal;
+
+    fn expecting(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        f.write_str("a decimal string such as \"187.25\" or an integer")
+    }
+
+    fn visit_str<E: de::Error>(self, value: &str) -> Result<Decimal, E> {
+        value.parse().map_err(E::custom)
+    }
+
+    fn visit_u64<E: de::Error>(self, value: u64) -> Result<Decimal, E> {
+        Ok(Decimal::from(value))
+    }
+
+    fn visit_i64<E: de::Error>(self, value: i64) -> Result<Decimal, E> {
+        Ok(Decimal::from(value))
+    }
+}
+
+// Accepts strings and integers, floats are rejected since they may already have been rounded.
+impl<'de> Deserialize<'de> for Decimal {
+    fn deserialize<D: Deserializer<'de>>(deserializer: D) -> Result<Decimal, D::Error> {
+        deserializer.deserialize_any(DecimalVisitor)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use crate::decimal::Decimal;
+
+    fn d(s: &str) -> Decimal {
+        s.parse().unwrap()
+    }
+
+    #[test]
+    fn test_parse_and_display() {
+        for &s in ["0", "3", "187.25", "-1.5", "0.001", "10.10"].iter() {
+            assert_eq!(d(s).to_string(), s);
+        }
+        assert_eq!(d("187.25"), Decimal::new(18725, 2));
+        assert_eq!(d("187.25").scale(), 2);
+        for &s in [
+            "",
+            "-",
+            ".5",
+            "5.",
+            "1e5",
+            "1.2.3",
+            "abc",
+            "0.1234567890123456789",
+        ]
+        .iter()
+        {
+            assert!(s.parse::<Decimal>().is_err(), "{}", s);
+        }
+        assert_eq!(serde_json::to_string(&d("1.50")).unwrap(), "\"1.50\"");
+        assert_eq!(
+            serde_json::from_str::<Decimal>("\"1.5\"").unwrap(),
+            d("1.5")
+        );
+        assert_eq!(serde_json::from_str::<Decimal>("3").unwrap(), d("3"));
+        assert!(serde_json::from_str::<Decimal>("1.5").is_err());
+    }
+
+    #[test]
+    fn test_arithmetic() {
+        assert_eq!(d("1.5"), d("1.50"));
+        assert!(d("1.5") < d("1.51"));
+        assert!(d("-2") < d("1"));
+        assert!(Decimal::MAX > d("0.000000000000000001"));
+        assert!(-Decimal::MAX < d("-0.000000000000000001"));
+        assert_eq!(d("0.1") + d("0.2"), d("0.3"));
+        assert_eq!(d("1") - d("0.25"), d("0.75"));
+        assert_eq!(d("187.25").checked_mul(d("3")).unwrap(), d("561.75"));
+        assert_eq!(d("0.1").checked_mul(d("0.1")).unwrap().to_string(), "0.01");
+        assert!(Decimal::MAX.checked_add(d("1")).is_none());
+        assert!(d("1.5").rescale(0).is_none());
+        assert_eq!(d("1.5").rescale(3).unwrap().to_string(), "1.500");
+        assert!(d("1.5").is_multiple_of(d("0.5")));
+        assert!(!d("1.5").is_multiple_of(d("1")));
+        assert!(!d("1.5").is_multiple_of(Decimal::ZERO));
+    }
+
+    #[test]
+    fn test_div() {
+        assert_eq!(d("1").checked_div(d("3"), 4).unwrap().to_string(), "0.3333");
+        assert_eq!(d("2").checked_div(d("3"), 4).unwrap().to_string(), "0.6667");
+        assert_eq!(
+            d("-2").checked_div(d("3"), 4).unwrap().to_string(),
+            "-0.6667"
+        );
+        // Half to even
+        assert_eq!(d("0.25").checked_div(d("1"), 1).unwrap().to_string(), "0.2");
+        assert_eq!(d("0.35").checked_div(d("1"), 1).unwrap().to_string(), "0.4");
+        assert_eq!(
+            d("95").checked_div(d("21"), 8).unwrap().to_string(),
+            "4.52380952"
+        );
+        assert!(d("1").checked_div(Decimal::ZERO, 2).is_none());
+    }
+}
@@ -0,0 +1,167 @@
+use crate::instruments::Rejection;
+use serde::ser::SerializeStruct;
+use serde::{Serialize, Serializer};
+use std::error;
+use std::fmt;
+
+// Everything that can go wrong handling a request.
+// Serialized as {"error": "NoSuchOrder", "message": "no such order"}, rejections also
+// say which of the instrument's rules the order broke in "reason".
+#[derive(Debug, Clone, PartialEq)]
+pub enum Error {
+    // The request couldn't be parsed, e.g. bad JSON or a bad order id in the path.
+    InvalidRequest(String),
+    InvalidSymbol(&'static str),
+    // An order that doesn't make sense for any instrument, e.g. a limit order with no price.
+    InvalidOrder(&'static str),
+    // Reference data that doesn't make sense.
+    InvalidInstrument(&'static str),
+    InvalidFees(&'static str),
+    // An order or amend outside of the instrument's reference data.
+    Rejected(Rejection),
+    UnknownSymbol,
+    SymbolExists,
+    Halted,
+    NoSuchOrder,
+    DuplicateOrder,
+    AmendWouldCross,
+    // An order given to the book for the wrong side.
+    WrongSide,
+    Overflow,
+    // Events asked to be replayed that are no longer kept.
+    EventsExpired,
+    SnapshotFailed(String),
+    // Order and trade history needs a database.
+    HistoryUnavailable,
+    DatabaseFailed(String),
+    InvalidAccount(&'static str),
+    AccountExists,
+    // The request isn't signed, or its signature can't be trusted.
+    Unauthenticated(&'static str),
+    // The request's API key doesn't have the scope for it, or is for another account.
+    Forbidden,
+    NoSuchAccount,
+    NoSuchKey,
+    // An order, amend or withdrawal needs more than the account has available.
+    InsufficientBalance(String),
+}
+
+impl Error {
+    // Stable name for clients to match on.
+    pub fn code(&self) -> &'static str {
+        match self {
+            Error::InvalidRequest(_) => "InvalidRequest",
+            Error::InvalidSymbol(_) => "InvalidSymbol",
+            Error::InvalidOrder(_) => "InvalidOrder",
+            Error::InvalidInstrument(_) => "InvalidInstrument",
+            Error::InvalidFees(_) => "InvalidFees",
+            Error::Rejected(_) => "Rejected",
+            Error::UnknownSymbol => "UnknownSymbol",
+            Error::SymbolExists => "SymbolExists",
+            Error::Halted => "Halted",
+            Error::NoSuchOrder => "NoSuchOrder",
+            Error::DuplicateOrder => "DuplicateOrder",
+            Error::AmendWouldCross => "AmendWouldCross",
+            Error::WrongSide => "WrongSide",
+            Error::Overflow => "Overflow",
+            Error::EventsExpired => "EventsExpired",
+            Error::SnapshotFailed(_) => "SnapshotFailed",
+            Error::HistoryUnavailable => "HistoryUnavailable",
+            Error::DatabaseFailed(_) => "DatabaseFailed",
+            Error::InvalidAccount(_) => "InvalidAccount",
+            Error::AccountExists => "AccountExists",
+            Error::Unauthenticated(_) => "Unauthenticated",
+            Error::Forbidden => "Forbidden",
+            Error::NoSuchAccount => "NoSuchAccount",
+            Error::NoSuchKey => "NoSuchKey",
+            Error::InsufficientBalance(_) => "InsufficientBalance",
+        }
+    }
+}
+
+impl fmt::Display for Error {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        match self {
+            Error::InvalidRequest(message) => write!(f, "invalid request: {}", message),
+            Error::InvalidSymbol(message)
+            | Error::InvalidOrder(message)
+            | Error::InvalidInstrument(message)
+            | Error::InvalidFees(message)
+            | Error::InvalidAccount(message)
+            | Error::Unauthenticated(message) => f.write_str(message),
+            Error::Rejected(rejection) => f.write_str(&rejection.message),
+            Error::UnknownSymbol => f.write_str("unknown symbol"),
+            Error::SymbolExists => f.write_str("symbol already exists"),
+            Error::Halted => f.write_str("trading is halted"),
+            Error::NoSuchOrder => f.write_str("no such order"),
+            Error::DuplicateOrder => f.write_str("order already on the book"),
+            Error::AmendWouldCross => f.write_str("amended price would cross the book"),
+            Error::WrongSide => f.write_str("order is for the other side of the book"),
+            Error::Overflow => f.write_str("arithmetic overflow"),
+            Error::EventsExpired => {
+                f.write_str("events are no longer kept, start again from a snapshot")
+            }
+            Error::SnapshotFailed(message) => write!(f, "unable to take a snapshot: {}", message),
+            Error::HistoryUnavailable => {
+                f.write_str("there is no history without a database, set DATABASE_URL")
+            }
+            Error::DatabaseFailed(message) => write!(f, "database error: {}", message),
+            Error::AccountExists => f.write_str("account name already taken"),
+            Error::Forbidden => f.write_str("the API key isn't allowed to do that"),
+            Error::NoSuchAccount => f.write_str("no such account"),
+            Error::NoSuchKey => f.write_str("no such API key"),
+            Error::InsufficientBalance(message) => f.write_str(message),
+        }
+    }
+}
+
+impl error::Error for Error {}
+
+impl From<Rejection> for Error {
+    fn from(rejection: Rejection) -> Error {
+        Error::Rejected(rejection)
+    }
+}
+
+impl Serialize for Error {
+    fn serialize<S: Serializer>(&self, serializer: S) -> Result<S::Ok, S::Error> {
+        let reason = match self {
+            Error::Rejected(rejection) => Some(rejection.reason),
+            _ => None,
+        };
+        let fields = if reason.is_some() { 3 } else { 2 };
+        let mut state = serializer.serialize_struct("Error", fields)?;
+        state.serialize_field("error", self.code())?;
+        state.serialize_field("message", &self.to_string())?;
+        if let Some(reason) = reason {
+            state.serialize_field("reason", &reason)?;
+        }
+        state.end()
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use crate::error::Error;
+    use crate::instruments::{RejectReason, Rejection};
+
+    #[test]
+    fn test_serialize() {
+        assert_eq!(
+            serde_json::to_string(&Error::NoSuchOrder).unwrap(),
+            r#"{"error":"NoSuchOrder","message":"no such order"}"#
+        );
+        assert_eq!(
+            serde_json::to_string(&Error::InvalidOrder("limit order requires a price")).unwrap(),
+            r#"{"error":"InvalidOrder","message":"limit order requires a price"}"#
+        );
+        let rejected = Error::from(Rejection {
+            reason: RejectReason::InvalidTickSize,
+            message: "price must be a multiple of the tick size 5".to_string(),
+        });
+        assert_eq!(
+            serde_json::to_string(&rejected).unwrap(),
+            r#"{"error":"Rejected","message":"price must be a multiple of the tick size 5","reason":"InvalidTickSize"}"#
+        );
+    }
+}
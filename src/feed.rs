@@ -0,0 +1,712 @@
+use crate::decimal::Decimal;
+use crate::error::Error;
+use crate::order_book::{OrderBook, OrderEvent, PriceLevel, Side, Symbol, TopOfBook, Trade};
+use futures::sync::mpsc::UnboundedSender;
+use log::debug;
+use serde::{Deserialize, Serialize};
+use std::collections::{HashMap, HashSet, VecDeque};
+use uuid::Uuid;
+use websocket_base::OwnedMessage;
+
+// How many of each symbol's most recent order events are kept for replaying.
+pub const MAX_REPLAY_EVENTS: usize = 100_000;
+
+#[derive(Serialize, Deserialize, Debug, Copy, Clone, PartialEq, Eq, Hash)]
+#[serde(rename_all = "lowercase")]
+pub enum Channel {
+    Trades,
+    // Best bid and ask.
+    Top,
+    // Every price level.
+    Book,
+    // Every change to every order.
+    Orders,
+}
+
+// What a client sends over the feed, e.g.
+// {"op": "subscribe", "symbol": "AAPL", "channels": ["book", "trades"]}.
+#[derive(Serialize, Deserialize, Debug, Clone)]
+#[serde(tag = "op", rename_all = "lowercase")]
+pub enum FeedRequest {
+    Subscribe {
+        symbol: Symbol,
+        channels: Vec<Channel>,
+    },
+    Unsubscribe {
+        symbol: Symbol,
+        channels: Vec<Channel>,
+    },
+}
+
+#[derive(Serialize, Deserialize, Debug, Clone, PartialEq)]
+#[serde(tag = "type", rename_all = "lowercase")]
+pub enum Data {
+    // Every price level, replacing whatever the client had. Sent on subscribing to book.
+    Snapshot {
+        bids: Vec<PriceLevel>,
+        asks: Vec<PriceLevel>,
+    },
+    // The new totals for every level that changed, best price first.
+    // A level with no orders left is gone from the book.
+    Update {
+        bids: Vec<PriceLevel>,
+        asks: Vec<PriceLevel>,
+    },
+    // Sent on subscribing and whenever it changes.
+    Top(TopOfBook),
+    // Every trade from a single order, in the order they happened.
+    Trades {
+        trades: Vec<Trade>,
+    },
+    // Every order on the book, replacing whatever the client had. Sent on subscribing to orders.
+    Orders {
+        bids: Vec<RestingOrder>,
+        asks: Vec<RestingOrder>,
+    },
+    Order(OrderEvent),
+    // Trades have no snapshot so subscribing to them is acknowledged with this.
+    Subscribed,
+    Unsubscribed,
+}
+
+#[derive(Serialize, Deserialize, Debug, Clone, PartialEq)]
+pub struct Message {
+    pub channel: Channel,
+    pub symbol: Symbol,
+    // Goes up by one with every change to the symbol's books, messages for the same change
+    // share it. A snapshot has the seq of the last change it includes, so the book is only
+    // up to date if every update after it is exactly one more than the last.
+    // On the orders channel it is the order event's seq instead, which goes up by one with
+    // every event, and an orders snapshot has the seq of the last event it includes.
+    pub seq: u64,
+    #[serde(flatten)]
+    pub data: Data,
+}
+
+// An order in a market-by-order snapshot.
+#[derive(Serialize, Deserialize, Debug, Copy, Clone, PartialEq)]
+pub struct RestingOrder {
+    pub id: Uuid,
+    pub price: Decimal,
+    pub amount: Decimal,
+}
+
+// Every order on both sides of a symbol's books, best price first and then in queue order.
+// Applying every event after seq brings it up to date.
+#[derive(Serialize, Deserialize, Debug, Clone, PartialEq)]
+pub struct OrdersSnapshot {
+    pub symbol: Symbol,
+    pub seq: u64,
+    pub bids: Vec<RestingOrder>,
+    pub asks: Vec<RestingOrder>,
+}
+
+#[derive(Serialize, Deserialize, Debug, Copy, Clone, PartialEq)]
+pub struct SequencedEvent {
+    pub seq: u64,
+    #[serde(flatten)]
+    pub event: OrderEvent,
+}
+
+// A symbol's most recent order events.
+#[derive(Default)]
+struct EventLog {
+    last_seq: u64,
+    events: VecDeque<SequencedEvent>,
+}
+
+fn resting_orders(book: &OrderBook) -> Vec<RestingOrder> {
+    book.get_book()
+        .into_iter()
+        .flatten()
+        .map(|order| RestingOrder {
+            id: order.id,
+            price: order.price,
+            amount: order.amount,
+        })
+        .collect()
+}
+
+pub type ClientId = u64;
+
+// Everyone connected to the market data feed and what they're subscribed to.
+// Changes to a symbol's books have to be published while they are still locked, and
+// subscribing has to happen with them locked, so that every client sees every change
+// exactly once and in order.
+pub struct Feed {
+    clients: HashMap<ClientId, UnboundedSender<OwnedMessage>>,
+    subscriptions: HashMap<(Symbol, Channel), HashSet<ClientId>>,
+    seqs: HashMap<Symbol, u64>,
+    // The last top of book published for each symbol.
+    tops: HashMap<Symbol, TopOfBook>,
+    events: HashMap<Symbol, EventLog>,
+    next_client: ClientId,
+}
+
+impl Feed {
+    pub fn new() -> Feed {
+        Feed {
+            clients: HashMap::new(),
+            subscriptions: HashMap::new(),
+            seqs: HashMap::new(),
+            tops: HashMap::new(),
+            events: HashMap::new(),
+            next_client: 0,
+        }
+    }
+
+    // Messages for the client are sent to sender.
+    pub fn connect(&mut self, sender: UnboundedSender<OwnedMessage>) -> ClientId {
+        let client = self.next_client;
+        self.next_client += 1;
+        self.clients.insert(client, sender);
+        debug!("feed client {} connected", client);
+        client
+    }
+
+    pub fn disconnect(&mut self, client: ClientId) {
+        self.clients.remove(&client);
+        for subscribers in self.subscriptions.values_mut() {
+            subscribers.remove(&client);
+        }
+        debug!("feed client {} disconnected", client);
+    }
+
+    // Sends the client the channel's snapshot and then every message after it.
+    pub fn subscribe(
+        &mut self,
+        client: ClientId,
+        symbol: Symbol,
+        channel: Channel,
+        buy_book: &OrderBook,
+        sell_book: &OrderBook,
+    ) -> Result<(), Error> {
+        let data = match channel {
+            Channel::Trades => Data::Subscribed,
+            Channel::Top => Data::Top(TopOfBook::new(buy_book, sell_book)?),
+            Channel::Book => Data::Snapshot {
+                bids: buy_book.depth(usize::MAX)?,
+                asks: sell_book.depth(usize::MAX)?,
+            },
+            Channel::Orders => Data::Orders {
+                bids: resting_orders(buy_book),
+                asks: resting_orders(sell_book),
+            },
+        };
+        self.subscriptions
+            .entry((symbol, channel))
+            .or_default()
+            .insert(client);
+        let seq = if channel == Channel::Orders {
+            self.event_seq(symbol)
+        } else {
+            self.seq(symbol)
+        };
+        let message = Message {
+            channel,
+            symbol,
+            seq,
+            data,
+        };
+        self.send_to(client, &message);
+        Ok(())
+    }
+
+    pub fn unsubscribe(&mut self, client: ClientId, symbol: Symbol, channel: Channel) {
+        if let Some(subscribers) = self.subscriptions.get_mut(&(symbol, channel)) {
+            subscribers.remove(&client);
+        }
+        let message = Message {
+            channel,
+            symbol,
+            seq: self.seq(symbol),
+            data: Data::Unsubscribed,
+        };
+        self.send_to(client, &message);
+    }
+
+    // Tells subscribers about a change to the symbol's books, the events that made it
+    // in the order they happened and any trades made, and keeps the events for replaying.
+    pub fn publish(
+        &mut self,
+        symbol: Symbol,
+        buy_book: &OrderBook,
+        sell_book: &OrderBook,
+        events: &[OrderEvent],
+        trades: &[Trade],
+    ) -> Result<(), Error> {
+        if events.is_empty() && trades.is_empty() {
+            return Ok(());
+        }
+        let changed: Vec<(Side, Decimal)> = events.iter().map(OrderEvent::level).collect();
+        let mut bid_prices: Vec<Decimal> = changed
+            .iter()
+            .filter(|(side, _)| *side == Side::Buy)
+            .map(|&(_, price)| price)
+            .collect();
+        bid_prices.sort_by(|a, b| b.cmp(a));
+        bid_prices.dedup();
+        let mut ask_prices: Vec<Decimal> = changed
+            .iter()
+            .filter(|(side, _)| *side == Side::Sell)
+            .map(|&(_, price)| price)
+            .collect();
+        ask_prices.sort();
+        ask_prices.dedup();
+        let bids = bid_prices
+            .into_iter()
+            .map(|price| buy_book.level(price))
+            .collect::<Result<Vec<PriceLevel>, Error>>()?;
+        let asks = ask_prices
+            .into_iter()
+            .map(|price| sell_book.level(price))
+            .collect::<Result<Vec<PriceLevel>, Error>>()?;
+        let top = TopOfBook::new(buy_book, sell_book)?;
+
+        for &event in events.iter() {
+            let log = self.events.entry(symbol).or_default();
+            log.last_seq += 1;
+            let sequenced = SequencedEvent {
+                seq: log.last_seq,
+                event,
+            };
+            if log.events.len() == MAX_REPLAY_EVENTS {
+                log.events.pop_front();
+            }
+            log.events.push_back(sequenced);
+            self.send(Message {
+                channel: Channel::Orders,
+                symbol,
+                seq: sequenced.seq,
+                data: Data::Order(event),
+            });
+        }
+
+        let seq = self.seq(symbol) + 1;
+        self.seqs.insert(symbol, seq);
+        if !bids.is_empty() || !asks.is_empty() {
+            let data = Data::Update { bids, asks };
+            self.send(Message {
+                channel: Channel::Book,
+                symbol,
+                seq,
+                data,
+            });
+        }
+        if !trades.is_empty() {
+            let trades = trades.to_vec();
+            self.send(Message {
+                channel: Channel::Trades,
+                symbol,
+                seq,
+                data: Data::Trades { trades },
+            });
+        }
+        if self.tops.get(&symbol).cloned().unwrap_or_default() != top {
+            self.tops.insert(symbol, top);
+            self.send(Message {
+                channel: Channel::Top,
+                symbol,
+                seq,
+                data: Data::Top(top),
+            });
+        }
+        Ok(())
+    }
+
+    // A delisted instrument's books are thrown away. Its subscribers are sent an empty
+    // book and then forgotten.
+    pub fn delist(&mut self, symbol: Symbol) {
+        let seq = self.seq(symbol) + 1;
+        self.seqs.insert(symbol, seq);
+        self.send(Message {
+            channel: Channel::Book,
+            symbol,
+            seq,
+            data: Data::Snapshot {
+                bids: Vec::new(),
+                asks: Vec::new(),
+            },
+        });
+        self.send(Message {
+            channel: Channel::Top,
+            symbol,
+            seq,
+            data: Data::Top(TopOfBook::default()),
+        });
+        self.send(Message {
+            channel: Channel::Orders,
+            symbol,
+            seq: self.event_seq(symbol),
+            data: Data::Orders {
+                bids: Vec::new(),
+                asks: Vec::new(),
+            },
+        });
+        self.tops.remove(&symbol);
+        self.events.remove(&symbol);
+        self.subscriptions.retain(|&(s, _), _| s != symbol);
+    }
+
+    // Both books should be locked so nothing changes while it's taken.
+    pub fn orders_snapshot(
+        &self,
+        symbol: Symbol,
+        buy_book: &OrderBook,
+        sell_book: &OrderBook,
+    ) -> OrdersSnapshot {
+        OrdersSnapshot {
+            symbol,
+            seq: self.event_seq(symbol),
+            bids: resting_orders(buy_book),
+            asks: resting_orders(sell_book),
+        }
+    }
+
+    // The symbol's order events after since, oldest first. If some of them are no longer kept
+    // there's no way to catch up and a new snapshot is needed.
+    pub fn replay(&self, symbol: Symbol, since: u64) -> Result<Vec<SequencedEvent>, Error> {
+        let log = match self.events.get(&symbol) {
+            Some(log) => log,
+            None => return Ok(Vec::new()),
+        };
+        let first_kept = log.last_seq - log.events.len() as u64 + 1;
+        if since + 1 < first_kept {
+            return Err(Error::EventsExpired);
+        }
+        let skip = since.saturating_sub(first_kept - 1) as usize;
+        Ok(log.events.iter().skip(skip).cloned().collect())
+    }
+
+    // The symbol's change and order event seqs, for a snapshot.
+    pub fn seqs(&self, symbol: Symbol) -> (u64, u64) {
+        (self.seq(symbol), self.event_seq(symbol))
+    }
+
+    // Carries on the symbol's seqs from a snapshot of its books. Events from before it
+    // aren't kept so can't be replayed.
+    pub fn restore(
+        &mut self,
+        symbol: Symbol,
+        seq: u64,
+        event_seq: u64,
+        buy_book: &OrderBook,
+        sell_book: &OrderBook,
+    ) -> Result<(), Error> {
+        self.seqs.insert(symbol, seq);
+        self.tops
+            .insert(symbol, TopOfBook::new(buy_book, sell_book)?);
+        self.events.insert(
+            symbol,
+            EventLog {
+                last_seq: event_seq,
+                events: VecDeque::new(),
+            },
+        );
+        Ok(())
+    }
+
+    pub fn send_error(&mut self, client: ClientId, e: &Error) {
+        if let Some(sender) = self.clients.get(&client) {
+            let text = serde_json::to_string(e).unwrap();
+            // The connection is cleaned up when it closes, nothing more to do if it has.
+            let _ = sender.unbounded_send(OwnedMessage::Text(text));
+        }
+    }
+
+    fn seq(&self, symbol: Symbol) -> u64 {
+        self.seqs.get(&symbol).cloned().unwrap_or(0)
+    }
+
+    fn event_seq(&self, symbol: Symbol) -> u64 {
+        self.events.get(&symbol).map_or(0, |log| log.last_seq)
+    }
+
+    // To every client subscribed to the message's channel.
+    fn send(&mut self, message: Message) {
+        let subscribers = match self.subscriptions.get(&(message.symbol, message.channel)) {
+            Some(subscribers) if !subscribers.is_empty() => subscribers,
+            _ => return,
+        };
+        let text = serde_json::to_string(&message).unwrap();
+        for client in subscribers.iter() {
+            if let Some(sender) = self.clients.get(client) {
+                let _ = sender.unbounded_send(OwnedMessage::Text(text.clone()));
+            }
+        }
+    }
+
+    fn send_to(&mut self, client: ClientId, message: &Message) {
+        if let Some(sender) = self.clients.get(&client) {
+            let text = serde_json::to_string(message).unwrap();
+            let _ = sender.unbounded_send(OwnedMessage::Text(text));
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use crate::decimal::Decimal;
+    use crate::error::Error;
+    use crate::feed::{Channel, Data, Feed, Message, RestingOrder, MAX_REPLAY_EVENTS};
+    use crate::order_book::{
+        OpenLimitOrder, OrderBook, OrderEvent, PriceLevel, Side, Symbol, TimeInForce, TopOfBook,
+    };
+    use futures::sync::mpsc;
+    use futures::Stream;
+    use uuid::Uuid;
+    use websocket_base::OwnedMessage;
+
+    fn d(n: u32) -> Decimal {
+        Decimal::from(n)
+    }
+
+    fn level(price: u32, amount: u32, orders: usize) -> PriceLevel {
+        PriceLevel {
+            price: d(price),
+            amount: d(amount),
+            orders,
+        }
+    }
+
+    #[test]
+    fn test_feed() {
+        let aapl = Symbol::new("AAPL").unwrap();
+        let order = |side, price, amount| OpenLimitOrder {
+            id: Uuid::new_v4(),
+            owner: Uuid::nil(),
+            amount: d(amount),
+            symbol: aapl,
+            price: d(price),
+            side,
+        };
+        let mut buy_book = OrderBook::new(Side::Buy);
+        let mut sell_book = OrderBook::new(Side::Sell);
+        buy_book.add_order(order(Side::Buy, 5, 2)).unwrap();
+        sell_book.add_order(order(Side::Sell, 7, 1)).unwrap();
+        buy_book.take_events();
+        sell_book.take_events();
+
+        let mut feed = Feed::new();
+        let (sender, receiver) = mpsc::unbounded();
+        let client = feed.connect(sender);
+        let (other_sender, other_receiver) = mpsc::unbounded();
+        let other = feed.connect(other_sender);
+        feed.subscribe(client, aapl, Channel::Book, &buy_book, &sell_book)
+            .unwrap();
+        feed.subscribe(client, aapl, Channel::Top, &buy_book, &sell_book)
+            .unwrap();
+        feed.subscribe(other, aapl, Channel::Trades, &buy_book, &sell_book)
+            .unwrap();
+
+        // A new best bid.
+        buy_book.add_order(order(Side::Buy, 6, 3)).unwrap();
+        let events = buy_book.take_events();
+        feed.publish(aapl, &buy_book, &sell_book, &events, &[])
+            .unwrap();
+        // Nothing changed, nothing sent.
+        feed.publish(aapl, &buy_book, &sell_book, &[], &[]).unwrap();
+        // Another order behind the best ask leaves the top as it was.
+        sell_book.add_order(order(Side::Sell, 8, 4)).unwrap();
+        let events = sell_book.take_events();
+        feed.publish(aapl, &buy_book, &sell_book, &events, &[])
+            .unwrap();
+        feed.unsubscribe(client, aapl, Channel::Top);
+        buy_book.remove_order(buy_book.get_book()[0][0].id).unwrap();
+        let events = buy_book.take_events();
+        feed.publish(aapl, &buy_book, &sell_book, &events, &[])
+            .unwrap();
+        feed.delist(aapl);
+        feed.disconnect(client);
+        feed.disconnect(other);
+
+        let messages: Vec<Message> = receiver
+            .wait()
+            .map(|message| match message.unwrap() {
+                OwnedMessage::Text(text) => serde_json::from_str(&text).unwrap(),
+                message => panic!("unexpected {:?}", message),
+            })
+            .collect();
+        let book = |seq, data| Message {
+            channel: Channel::Book,
+            symbol: aapl,
+            seq,
+            data,
+        };
+        let top = |seq, best_bid: u32, bid_amount| Message {
+            channel: Channel::Top,
+            symbol: aapl,
+            seq,
+            data: Data::Top(TopOfBook {
+                best_bid: Some(d(best_bid)),
+                bid_amount: Some(d(bid_amount)),
+                best_ask: Some(d(7)),
+                ask_amount: Some(d(1)),
+            }),
+        };
+        assert_eq!(
+            messages,
+            vec![
+                book(
+                    0,
+                    Data::Snapshot {
+                        bids: vec![level(5, 2, 1)],
+                        asks: vec![level(7, 1, 1)],
+                    }
+                ),
+                top(0, 5, 2),
+                book(
+                    1,
+                    Data::Update {
+                        bids: vec![level(6, 3, 1)],
+                        asks: vec![],
+                    }
+                ),
+                top(1, 6, 3),
+                book(
+                    2,
+                    Data::Update {
+                        bids: vec![],
+                        asks: vec![level(8, 4, 1)],
+                    }
+                ),
+                Message {
+                    channel: Channel::Top,
+                    symbol: aapl,
+                    seq: 2,
+                    data: Data::Unsubscribed,
+                },
+                book(
+                    3,
+                    Data::Update {
+                        bids: vec![PriceLevel {
+                            price: d(6),
+                            amount: Decimal::ZERO,
+                            orders: 0,
+                        }],
+                        asks: vec![],
+                    }
+                ),
+                book(
+                    4,
+                    Data::Snapshot {
+                        bids: vec![],
+                        asks: vec![],
+                    }
+                ),
+            ]
+        );
+        // Only subscribed to trades, and there weren't any.
+        assert_eq!(other_receiver.wait().count(), 1);
+    }
+
+    #[test]
+    fn test_order_events() {
+        let aapl = Symbol::new("AAPL").unwrap();
+        let order = |side, price, amount| OpenLimitOrder {
+            id: Uuid::new_v4(),
+            owner: Uuid::nil(),
+            amount: d(amount),
+            symbol: aapl,
+            price: d(price),
+            side,
+        };
+        let mut buy_book = OrderBook::new(Side::Buy);
+        let mut sell_book = OrderBook::new(Side::Sell);
+        let resting = sell_book.add_order(order(Side::Sell, 7, 3)).unwrap();
+        let events = sell_book.take_events();
+        let mut feed = Feed::new();
+        feed.publish(aapl, &buy_book, &sell_book, &events, &[])
+            .unwrap();
+
+        let (sender, receiver) = mpsc::unbounded();
+        let client = feed.connect(sender);
+        feed.subscribe(client, aapl, Channel::Orders, &buy_book, &sell_book)
+            .unwrap();
+        let snapshot = feed.orders_snapshot(aapl, &buy_book, &sell_book);
+        assert_eq!(snapshot.seq, 1);
+        assert!(snapshot.bids.is_empty());
+        assert_eq!(
+            snapshot.asks,
+            vec![RestingOrder {
+                id: resting.id,
+                price: d(7),
+                amount: d(3),
+            }]
+        );
+
+        // Partly fill it and rest the rest of the buy.
+        let buy = order(Side::Buy, 7, 5);
+        let fr = sell_book
+            .fill_order(buy, TimeInForce::GoodTillCancel)
+            .unwrap();
+        let mut rest = buy;
+        rest.amount = fr.remaining;
+        buy_book.add_order(rest).unwrap();
+        let mut events = sell_book.take_events();
+        events.extend(buy_book.take_events());
+        feed.publish(aapl, &buy_book, &sell_book, &events, &fr.trades)
+            .unwrap();
+        feed.disconnect(client);
+
+        let execute = OrderEvent::Execute {
+            id: resting.id,
+            side: Side::Sell,
+            price: d(7),
+            amount: d(3),
+        };
+        let add = OrderEvent::Add {
+            id: buy.id,
+            side: Side::Buy,
+            price: d(7),
+            amount: d(2),
+        };
+        let messages: Vec<Message> = receiver
+            .wait()
+            .map(|message| match message.unwrap() {
+                OwnedMessage::Text(text) => serde_json::from_str(&text).unwrap(),
+                message => panic!("unexpected {:?}", message),
+            })
+            .collect();
+        let seqs: Vec<u64> = messages.iter().map(|message| message.seq).collect();
+        assert_eq!(seqs, vec![1, 2, 3]);
+        assert_eq!(
+            messages[0].data,
+            Data::Orders {
+                bids: snapshot.bids,
+                asks: snapshot.asks,
+            }
+        );
+        assert_eq!(messages[1].data, Data::Order(execute));
+        assert_eq!(messages[2].data, Data::Order(add));
+
+        let replayed: Vec<OrderEvent> = feed
+            .replay(aapl, 1)
+            .unwrap()
+            .into_iter()
+            .map(|sequenced| sequenced.event)
+            .collect();
+        assert_eq!(replayed, vec![execute, add]);
+        assert_eq!(feed.replay(aapl, 0).unwrap().len(), 3);
+        assert!(feed.replay(aapl, 3).unwrap().is_empty());
+        assert!(feed
+            .replay(Symbol::new("MSFT").unwrap(), 0)
+            .unwrap()
+            .is_empty());
+
+        // Once events are dropped they can't be replayed from before them.
+        let cancel = OrderEvent::Cancel {
+            id: Uuid::new_v4(),
+            side: Side::Buy,
+            price: d(1),
+            amount: d(1),
+        };
+        let events = vec![cancel; MAX_REPLAY_EVENTS];
+        feed.publish(aapl, &buy_book, &sell_book, &events, &[])
+            .unwrap();
+        assert_eq!(feed.replay(aapl, 2).unwrap_err(), Error::EventsExpired);
+        let replayed = feed.replay(aapl, 3).unwrap();
+        assert_eq!(replayed.len(), MAX_REPLAY_EVENTS);
+        assert_eq!(replayed[0].seq, 4);
+    }
+}
@@ -0,0 +1,349 @@
+use crate::decimal::{Decimal, MAX_SCALE};
+use crate::error::Error;
+use crate::order_book::{FillResult, Symbol};
+use crate::trade_log::Liquidity;
+use log::info;
+use serde::{Deserialize, Serialize};
+use std::collections::{BTreeMap, HashMap};
+use std::fs;
+use uuid::Uuid;
+
+// How far back the volume that picks a volume tier goes, 30 days in milliseconds.
+pub const VOLUME_WINDOW: u64 = 30 * 24 * 60 * 60 * 1000;
+const BPS_PER_UNIT: u32 = 10_000;
+
+// What's charged on each side of a trade in basis points of its notional. Negative is a
+// rebate, which is all makers can get: nothing is reserved for a resting order's fee.
+#[derive(Serialize, Deserialize, Debug, Copy, Clone, PartialEq)]
+#[serde(default)]
+pub struct Rates {
+    pub maker_bps: Decimal,
+    pub taker_bps: Decimal,
+}
+
+impl Default for Rates {
+    fn default() -> Rates {
+        Rates {
+            maker_bps: Decimal::ZERO,
+            taker_bps: Decimal::ZERO,
+        }
+    }
+}
+
+// Rates for accounts that have traded at least min_volume over VOLUME_WINDOW.
+#[derive(Serialize, Deserialize, Debug, Copy, Clone, PartialEq)]
+pub struct VolumeTier {
+    pub min_volume: Decimal,
+    #[serde(flatten)]
+    pub rates: Rates,
+}
+
+// Which rates a trade is charged, the most specific that applies: the rates for the
+// account's tier, then the instrument's, then the highest volume tier it has reached and
+// otherwise the base rates. Without any trades are free.
+#[derive(Serialize, Deserialize, Debug, Clone, Default, PartialEq)]
+#[serde(default)]
+pub struct FeeSchedule {
+    #[serde(flatten)]
+    pub rates: Rates,
+    // Lowest min_volume first.
+    pub volume_tiers: Vec<VolumeTier>,
+    pub instruments: BTreeMap<Symbol, Rates>,
+    pub account_tiers: BTreeMap<String, Rates>,
+    // Which of the account tiers each account is in.
+    pub accounts: HashMap<Uuid, String>,
+}
+
+impl FeeSchedule {
+    // Reads a schedule from JSON, e.g. {"maker_bps": "-1", "taker_bps": "5"}.
+    pub fn load(path: &str) -> Result<FeeSchedule, String> {
+        let contents =
+            fs::read_to_string(path).map_err(|e| format!("unable to read {}: {}", path, e))?;
+        let schedule: FeeSchedule = serde_json::from_str(&contents)
+            .map_err(|e| format!("unable to parse {}: {}", path, e))?;
+        schedule.check().map_err(|e| format!("{}: {}", path, e))?;
+        info!("loaded fee schedule from {}", path);
+        Ok(schedule)
+    }
+
+    pub fn check(&self) -> Result<(), Error> {
+        let every = self
+            .volume_tiers
+            .iter()
+            .map(|tier| &tier.rates)
+            .chain(self.instruments.values())
+            .chain(self.account_tiers.values())
+            .chain(Some(&self.rates));
+        let limit = Decimal::from(BPS_PER_UNIT);
+        for rates in every {
+            for &bps in [rates.maker_bps, rates.taker_bps].iter() {
+                if bps > limit || bps < -limit {
+                    return Err(Error::InvalidFees(
+                        "rates must be between -10000 and 10000 basis points",
+                    ));
+                }
+            }
+            if rates.maker_bps.is_positive() {
+                return Err(Error::InvalidFees(
+                    "maker_bps can't be positive, makers can only be given rebates",
+                ));
+            }
+        }
+        if self
+            .volume_tiers
+            .iter()
+            .any(|tier| tier.min_volume.is_negative())
+            || self
+                .volume_tiers
+                .windows(2)
+                .any(|tiers| tiers[0].min_volume >= tiers[1].min_volume)
+        {
+            return Err(Error::InvalidFees(
+                "volume_tiers must have increasing min_volumes that aren't negative",
+            ));
+        }
+        if self
+            .accounts
+            .values()
+            .any(|tier| !self.account_tiers.contains_key(tier))
+        {
+            return Err(Error::InvalidFees(
+                "accounts can only be in one of the account_tiers",
+            ));
+        }
+        Ok(())
+    }
+
+    // The rates for account trading symbol, when it's traded volume over VOLUME_WINDOW.
+    pub fn rates(&self, symbol: Symbol, account: Uuid, volume: Decimal) -> Rates {
+        if let Some(rates) = self
+            .accounts
+            .get(&account)
+            .and_then(|tier| self.account_tiers.get(tier))
+        {
+            return *rates;
+        }
+        if let Some(rates) = self.instruments.get(&symbol) {
+            return *rates;
+        }
+        self.volume_tiers
+            .iter()
+            .rev()
+            .find(|tier| volume >= tier.min_volume)
+            .map_or(self.rates, |tier| tier.rates)
+    }
+
+    // The most account could be charged taking liquidity on symbol, whatever its volume is
+    // by the time it trades.
+    pub fn max_taker_bps(&self, symbol: Symbol, account: Uuid) -> Decimal {
+        if self.accounts.contains_key(&account) || self.instruments.contains_key(&symbol) {
+            return self.rates(symbol, account, Decimal::ZERO).taker_bps;
+        }
+        self.volume_tiers
+            .iter()
+            .map(|tier| tier.rates.taker_bps)
+            .fold(self.rates.taker_bps, Decimal::max)
+    }
+}
+
+// bps basis points of notional, exactly unless that needs more than MAX_SCALE decimal places.
+pub fn fee(notional: Decimal, bps: Decimal) -> Result<Decimal, Error> {
+    let scale = (notional.scale() + bps.scale() + 4).min(MAX_SCALE);
+    notional
+        .checked_mul(bps)
+        .and_then(|fee| fee.checked_div(Decimal::from(BPS_PER_UNIT), scale))
+        .map(Decimal::normalize)
+        .ok_or(Error::Overflow)
+}
+
+// What one side of a trade was charged.
+#[derive(Serialize, Deserialize, Debug, Copy, Clone, PartialEq)]
+pub struct Fee {
+    pub trade: Uuid,
+    pub order: Uuid,
+    pub account: Uuid,
+    pub symbol: Symbol,
+    pub liquidity: Liquidity,
+    pub notional: Decimal,
+    pub bps: Decimal,
+    // Negative for a rebate.
+    pub amount: Decimal,
+    // The trade's, milliseconds since the unix epoch.
+    pub timestamp: u64,
+}
+
+// What /order responds with, the order's fill and what it was charged for each trade.
+#[derive(Serialize, Deserialize, Debug, Clone)]
+pub struct FilledOrder {
+    #[serde(flatten)]
+    pub fill: FillResult,
+    pub fees: Vec<Fee>,
+}
+
+// An account's fees, what /accounts/{id}/fees gives.
+#[derive(Serialize, Deserialize, Debug, Clone, PartialEq)]
+pub struct FeeStatement {
+    // Traded over the last VOLUME_WINDOW.
+    pub volume: Decimal,
+    pub paid: Decimal,
+    pub rebated: Decimal,
+    // paid - rebated.
+    pub net: Decimal,
+    // Oldest first.
+    pub fees: Vec<Fee>,
+}
+
+// Every fee each account has been charged, oldest first, which is also where their
+// volume comes from.
+pub struct FeeLedger {
+    fees: HashMap<Uuid, Vec<Fee>>,
+}
+
+impl FeeLedger {
+    pub fn new() -> FeeLedger {
+        FeeLedger {
+            fees: HashMap::new(),
+        }
+    }
+
+    pub fn accrue(&mut self, fee: Fee) {
+        self.fees.entry(fee.account).or_default().push(fee);
+    }
+
+    pub fn fees(&self, account: Uuid) -> &[Fee] {
+        match self.fees.get(&account) {
+            Some(fees) => fees,
+            None => &[],
+        }
+    }
+
+    // What the account traded over the VOLUME_WINDOW before now.
+    pub fn volume(&self, account: Uuid, now: u64) -> Result<Decimal, Error> {
+        let since = now.saturating_sub(VOLUME_WINDOW);
+        self.fees(account)
+            .iter()
+            .rev()
+            .take_while(|fee| fee.timestamp > since)
+            .try_fold(Decimal::ZERO, |volume, fee| {
+                volume.checked_add(fee.notional)
+            })
+            .ok_or(Error::Overflow)
+    }
+
+    pub fn statement(&self, account: Uuid, now: u64) -> Result<FeeStatement, Error> {
+        let fees = self.fees(account);
+        let (mut paid, mut rebated) = (Decimal::ZERO, Decimal::ZERO);
+        for fee in fees {
+            if fee.amount.is_negative() {
+                rebated = rebated.checked_sub(fee.amount).ok_or(Error::Overflow)?;
+            } else {
+                paid = paid.checked_add(fee.amount).ok_or(Error::Overflow)?;
+            }
+        }
+        Ok(FeeStatement {
+            volume: self.volume(account, now)?,
+            paid,
+            rebated,
+            net: paid.checked_sub(rebated).ok_or(Error::Overflow)?,
+            fees: fees.to_vec(),
+        })
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use crate::decimal::Decimal;
+    use crate::error::Error;
+    use crate::fees::{fee, Fee, FeeLedger, FeeSchedule, Rates, VOLUME_WINDOW};
+    use crate::order_book::Symbol;
+    use crate::trade_log::Liquidity;
+    use uuid::Uuid;
+
+    fn d(s: &str) -> Decimal {
+        s.parse().unwrap()
+    }
+
+    fn rates(maker_bps: &str, taker_bps: &str) -> Rates {
+        Rates {
+            maker_bps: d(maker_bps),
+            taker_bps: d(taker_bps),
+        }
+    }
+
+    #[test]
+    fn test_fee_schedule() {
+        let (aapl, msft) = (Symbol::new("AAPL").unwrap(), Symbol::new("MSFT").unwrap());
+        let (alice, bob) = (Uuid::new_v4(), Uuid::new_v4());
+        let json = format!(
+            r#"{{"maker_bps": "-1", "taker_bps": "5",
+                "volume_tiers": [{{"min_volume": "1000", "maker_bps": "-2", "taker_bps": "3"}}],
+                "instruments": {{"MSFT": {{"maker_bps": "0", "taker_bps": "4"}}}},
+                "account_tiers": {{"vip": {{"maker_bps": "-3", "taker_bps": "1"}}}},
+                "accounts": {{"{}": "vip"}}}}"#,
+            alice
+        );
+        let schedule: FeeSchedule = serde_json::from_str(&json).unwrap();
+        assert_eq!(schedule.check(), Ok(()));
+        assert_eq!(schedule.rates(aapl, bob, d("999")), rates("-1", "5"));
+        assert_eq!(schedule.rates(aapl, bob, d("1000")), rates("-2", "3"));
+        assert_eq!(schedule.rates(msft, bob, d("1000")), rates("0", "4"));
+        assert_eq!(schedule.rates(msft, alice, d("0")), rates("-3", "1"));
+        assert_eq!(
+            FeeSchedule::default().rates(aapl, bob, d("0")),
+            Rates::default()
+        );
+        assert_eq!(schedule.max_taker_bps(aapl, bob), d("5"));
+        assert_eq!(schedule.max_taker_bps(msft, bob), d("4"));
+        assert_eq!(schedule.max_taker_bps(aapl, alice), d("1"));
+
+        let mut bad = schedule.clone();
+        bad.accounts.insert(bob, "gold".to_string());
+        assert!(matches!(bad.check(), Err(Error::InvalidFees(_))));
+        let mut bad = schedule.clone();
+        bad.rates.taker_bps = d("10000.1");
+        assert!(matches!(bad.check(), Err(Error::InvalidFees(_))));
+        // Makers can't be charged, there's nothing reserved to charge them from.
+        let mut bad = schedule.clone();
+        bad.volume_tiers[0].rates.maker_bps = d("0.5");
+        assert_eq!(
+            bad.check().unwrap_err().to_string(),
+            "maker_bps can't be positive, makers can only be given rebates"
+        );
+        let mut bad = schedule;
+        bad.volume_tiers.push(bad.volume_tiers[0]);
+        assert!(matches!(bad.check(), Err(Error::InvalidFees(_))));
+
+        assert_eq!(fee(d("187.25"), d("5")).unwrap(), d("0.093625"));
+        assert_eq!(fee(d("187.25"), d("-1")).unwrap(), d("-0.018725"));
+
+        // Volume only counts trades in the window, and rebates come off what was paid.
+        let mut ledger = FeeLedger::new();
+        let charged = |amount: &str, timestamp| Fee {
+            trade: Uuid::new_v4(),
+            order: Uuid::new_v4(),
+            account: bob,
+            symbol: aapl,
+            liquidity: Liquidity::Taker,
+            notional: d("600"),
+            bps: d("5"),
+            amount: d(amount),
+            timestamp,
+        };
+        let now = VOLUME_WINDOW + 100;
+        ledger.accrue(charged("0.3", 100));
+        ledger.accrue(charged("-0.06", 101));
+        ledger.accrue(charged("0.3", now));
+        let statement = ledger.statement(bob, now).unwrap();
+        assert_eq!(statement.volume, d("1200"));
+        assert_eq!(statement.paid, d("0.6"));
+        assert_eq!(statement.rebated, d("0.06"));
+        assert_eq!(statement.net, d("0.54"));
+        assert_eq!(statement.fees.len(), 3);
+        assert_eq!(ledger.volume(alice, now).unwrap(), Decimal::ZERO);
+        ledger.accrue(Fee {
+            notional: Decimal::MAX,
+            ..charged("0", now)
+        });
+        assert_eq!(ledger.volume(bob, now), Err(Error::Overflow));
+    }
+}
@@ -0,0 +1,802 @@
+use crate::decimal::{Decimal, MAX_SCALE};
+use crate::error::Error;
+use crate::order_book::{AmendRequest, OrderBook, OrderRequest, OrderType, Side, Symbol};
+use log::info;
+use serde::{Deserialize, Serialize};
+use std::collections::HashMap;
+use std::fs;
+use std::sync::{RwLock, RwLockReadGuard, RwLockWriteGuard};
+
+// Halted instruments keep their books but don't take new orders or amends,
+// resting orders can still be cancelled. Delisting cancels everything on the books.
+#[derive(Serialize, Deserialize, Debug, Copy, Clone, PartialEq, Default)]
+pub enum InstrumentStatus {
+    #[default]
+    Active,
+    Halted,
+    Delisted,
+}
+
+// Largest price or amount, in units of the smallest step its scale allows.
+// Keeps price * amount, and the average price worked out from it, well inside what a
+// Decimal can hold so matching can never overflow.
+pub const MAX_UNITS: i128 = 1_000_000_000_000_000;
+
+// Reference data for an instrument, every order has to fit within it.
+// Prices can have up to price_scale decimal places and amounts up to amount_scale, and
+// are stored and reported with exactly that many.
+// Prices must be a multiple of tick_size and amounts a multiple of lot_size, both default
+// to the smallest step their scale allows. min_amount defaults to the lot size.
+// An order's notional, its amount times its limit price, can be capped with max_notional.
+#[derive(Serialize, Deserialize, Debug, Copy, Clone)]
+pub struct Instrument {
+    pub symbol: Symbol,
+    #[serde(default)]
+    pub status: InstrumentStatus,
+    #[serde(default)]
+    pub price_scale: u32,
+    #[serde(default)]
+    pub amount_scale: u32,
+    #[serde(default)]
+    pub tick_size: Option<Decimal>,
+    #[serde(default)]
+    pub lot_size: Option<Decimal>,
+    #[serde(default)]
+    pub min_amount: Option<Decimal>,
+    #[serde(default)]
+    pub max_amount: Option<Decimal>,
+    #[serde(default)]
+    pub min_price: Option<Decimal>,
+    #[serde(default)]
+    pub max_price: Option<Decimal>,
+    #[serde(default)]
+    pub max_notional: Option<Decimal>,
+}
+
+// Why an order was rejected, for clients to act on without parsing the message.
+#[derive(Serialize, Deserialize, Debug, Copy, Clone, PartialEq)]
+pub enum RejectReason {
+    ZeroAmount,
+    InvalidAmountPrecision,
+    InvalidLotSize,
+    BelowMinAmount,
+    AboveMaxAmount,
+    NegativePrice,
+    InvalidPricePrecision,
+    InvalidTickSize,
+    BelowMinPrice,
+    AboveMaxPrice,
+    AboveMaxNotional,
+}
+
+#[derive(Serialize, Deserialize, Debug, Clone, PartialEq)]
+pub struct Rejection {
+    pub reason: RejectReason,
+    pub message: String,
+}
+
+fn reject<T>(reason: RejectReason, message: String) -> Result<T, Rejection> {
+    Err(Rejection { reason, message })
+}
+
+impl Instrument {
+    pub fn tick_size(&self) -> Decimal {
+        self.tick_size
+            .unwrap_or_else(|| Decimal::new(1, self.price_scale))
+    }
+
+    pub fn lot_size(&self) -> Decimal {
+        self.lot_size
+            .unwrap_or_else(|| Decimal::new(1, self.amount_scale))
+    }
+
+    pub fn min_amount(&self) -> Decimal {
+        self.min_amount.unwrap_or_else(|| self.lot_size())
+    }
+
+    fn price_limit(&self) -> Decimal {
+        Decimal::new(MAX_UNITS, self.price_scale)
+    }
+
+    pub fn amount_limit(&self) -> Decimal {
+        Decimal::new(MAX_UNITS, self.amount_scale)
+    }
+
+    // Makes sure the reference data itself makes sense.
+    pub fn check(&self) -> Result<(), Error> {
+        // Notionals have both scales added together.
+        if self.price_scale + self.amount_scale > MAX_SCALE {
+            return Err(Error::InvalidInstrument(
+                "price_scale and amount_scale can add up to at most 18",
+            ));
+        }
+        if !self.tick_size().is_positive() || !self.lot_size().is_positive() {
+            return Err(Error::InvalidInstrument(
+                "tick_size and lot_size must be positive",
+            ));
+        }
+        let prices = [Some(self.tick_size()), self.min_price, self.max_price];
+        if prices
+            .iter()
+            .flatten()
+            .any(|price| price.rescale(self.price_scale).is_none())
+        {
+            return Err(Error::InvalidInstrument(
+                "tick_size, min_price and max_price must fit in price_scale",
+            ));
+        }
+        let amounts = [Some(self.lot_size()), self.min_amount, self.max_amount];
+        if amounts
+            .iter()
+            .flatten()
+            .any(|amount| amount.rescale(self.amount_scale).is_none())
+        {
+            return Err(Error::InvalidInstrument(
+                "lot_size, min_amount and max_amount must fit in amount_scale",
+            ));
+        }
+        if self.max_price.is_some_and(|max| max > self.price_limit()) {
+            return Err(Error::InvalidInstrument(
+                "max_price is above the largest supported price",
+            ));
+        }
+        if self.max_amount.is_some_and(|max| max > self.amount_limit()) {
+            return Err(Error::InvalidInstrument(
+                "max_amount is above the largest supported amount",
+            ));
+        }
+        if self.max_notional.is_some_and(|max| !max.is_positive()) {
+            return Err(Error::InvalidInstrument("max_notional must be positive"));
+        }
+        if self.max_amount.is_some_and(|max| max < self.min_amount()) {
+            return Err(Error::InvalidInstrument("max_amount is below min_amount"));
+        }
+        if let (Some(min), Some(max)) = (self.min_price, self.max_price) {
+            if max < min {
+                return Err(Error::InvalidInstrument("max_price is below min_price"));
+            }
+        }
+        Ok(())
+    }
+
+    // Returns the amount with exactly amount_scale decimal places.
+    pub fn validate_amount(&self, amount: Decimal) -> Result<Decimal, Rejection> {
+        if !amount.is_positive() {
+            return reject(
+                RejectReason::ZeroAmount,
+                "amount must be positive".to_string(),
+            );
+        }
+        let amount = match amount.rescale(self.amount_scale) {
+            Some(amount) => amount,
+            None => {
+                return reject(
+                    RejectReason::InvalidAmountPrecision,
+                    format!(
+                        "amount can have at most {} decimal places",
+                        self.amount_scale
+                    ),
+                )
+            }
+        };
+        if !amount.is_multiple_of(self.lot_size()) {
+            return reject(
+                RejectReason::InvalidLotSize,
+                format!(
+                    "amount must be a multiple of the lot size {}",
+                    self.lot_size()
+                ),
+            );
+        }
+        if amount < self.min_amount() {
+            return reject(
+                RejectReason::BelowMinAmount,
+                format!("amount is below the minimum of {}", self.min_amount()),
+            );
+        }
+        let max_amount = self.max_amount.unwrap_or_else(|| self.amount_limit());
+        if amount > max_amount {
+            return reject(
+                RejectReason::AboveMaxAmount,
+                format!("amount is above the maximum of {}", max_amount),
+            );
+        }
+        Ok(amount)
+    }
+
+    // Limit prices have to be on a tick and within the price band.
+    // Returns the price with exactly price_scale decimal places.
+    pub fn validate_price(&self, price: Decimal) -> Result<Decimal, Rejection> {
+        let price = self.validate_tick(price)?;
+        if let Some(min_price) = self.min_price {
+            if price < min_price {
+                return reject(
+                    RejectReason::BelowMinPrice,
+                    format!("price is below the minimum of {}", min_price),
+                );
+            }
+        }
+        if let Some(max_price) = self.max_price {
+            if price > max_price {
+                return reject(
+                    RejectReason::AboveMaxPrice,
+                    format!("price is above the maximum of {}", max_price),
+                );
+            }
+        }
+        Ok(price)
+    }
+
+    fn validate_tick(&self, price: Decimal) -> Result<Decimal, Rejection> {
+        if price.is_negative() {
+            return reject(
+                RejectReason::NegativePrice,
+                "price can't be negative".to_string(),
+            );
+        }
+        let price = match price.rescale(self.price_scale) {
+            Some(price) => price,
+            None => {
+                return reject(
+                    RejectReason::InvalidPricePrecision,
+                    format!("price can have at most {} decimal places", self.price_scale),
+                )
+            }
+        };
+        if price > self.price_limit() {
+            return reject(
+                RejectReason::AboveMaxPrice,
+                format!("price is above the maximum of {}", self.price_limit()),
+            );
+        }
+        if !price.is_multiple_of(self.tick_size()) {
+            return reject(
+                RejectReason::InvalidTickSize,
+                format!(
+                    "price must be a multiple of the tick size {}",
+                    self.tick_size()
+                ),
+            );
+        }
+        Ok(price)
+    }
+
+    // Returns the order with its prices and amount at the instrument's scales,
+    // so that everything on a book has the same number of decimal places.
+    pub fn validate_order(&self, order: OrderRequest) -> Result<OrderRequest, Rejection> {
+        let mut order = order;
+        order.amount = self.validate_amount(order.amount)?;
+        order.price = order
+            .price
+            .map(|price| self.validate_price(price))
+            .transpose()?;
+        // A protection price only limits how far a market order can go,
+        // it doesn't need to be inside the price band.
+        if order.order_type == OrderType::Market {
+            order.protection_price = order
+                .protection_price
+                .map(|price| self.validate_tick(price))
+                .transpose()?;
+        }
+        let max_notional = match self.max_notional {
+            Some(max_notional) => max_notional,
+            None => return Ok(order),
+        };
+        // Market orders can't go past their protection price, or the top of the price band.
+        let price = match order.order_type {
+            OrderType::Limit => order.price,
+            OrderType::Market => order.protection_price.or(self.max_price),
+        };
+        match (order.order_type, price) {
+            (_, Some(price)) => self.validate_notional(price, order.amount)?,
+            // Rejected for not having a price when it's turned into an order.
+            (OrderType::Limit, None) => (),
+            (OrderType::Market, None) => {
+                return reject(
+                    RejectReason::AboveMaxNotional,
+                    format!(
+                        "market orders need a protection_price under a maximum notional of {}",
+                        max_notional
+                    ),
+                )
+            }
+        }
+        Ok(order)
+    }
+
+    // Checks price * amount against max_notional, both already validated.
+    pub fn validate_notional(&self, price: Decimal, amount: Decimal) -> Result<(), Rejection> {
+        let max_notional = match self.max_notional {
+            Some(max_notional) => max_notional,
+            None => return Ok(()),
+        };
+        match price.checked_mul(amount) {
+            Some(notional) if notional <= max_notional => Ok(()),
+            _ => reject(
+                RejectReason::AboveMaxNotional,
+                format!("notional is above the maximum of {}", max_notional),
+            ),
+        }
+    }
+
+    pub fn validate_amend(&self, amend: AmendRequest) -> Result<AmendRequest, Rejection> {
+        let mut amend = amend;
+        amend.amount = amend
+            .amount
+            .map(|amount| self.validate_amount(amount))
+            .transpose()?;
+        amend.price = amend
+            .price
+            .map(|price| self.validate_price(price))
+            .transpose()?;
+        Ok(amend)
+    }
+}
+
+// Both sides of the book for an instrument.
+pub struct Books {
+    pub buy: RwLock<OrderBook>,
+    pub sell: RwLock<OrderBook>,
+}
+
+impl Books {
+    fn new() -> Books {
+        Books {
+            buy: RwLock::new(OrderBook::new(Side::Buy)),
+            sell: RwLock::new(OrderBook::new(Side::Sell)),
+        }
+    }
+
+    // Write locks on both sides.
+    // Always taken buy side first so two requests can't deadlock each other.
+    pub fn lock(
+        &self,
+    ) -> (
+        RwLockWriteGuard<'_, OrderBook>,
+        RwLockWriteGuard<'_, OrderBook>,
+    ) {
+        let buy_book = self.buy.write().unwrap();
+        let sell_book = self.sell.write().unwrap();
+        (buy_book, sell_book)
+    }
+
+    // Read locks on both sides, in the same order as lock().
+    pub fn read(
+        &self,
+    ) -> (
+        RwLockReadGuard<'_, OrderBook>,
+        RwLockReadGuard<'_, OrderBook>,
+    ) {
+        let buy_book = self.buy.read().unwrap();
+        let sell_book = self.sell.read().unwrap();
+        (buy_book, sell_book)
+    }
+}
+
+struct Listing {
+    instrument: Instrument,
+    books: Books,
+}
+
+// Every instrument the exchange knows about and its books.
+pub struct Registry {
+    listings: HashMap<Symbol, Listing>,
+}
+
+impl Registry {
+    pub fn new() -> Registry {
+        Registry {
+            listings: HashMap::new(),
+        }
+    }
+
+    // Reads a JSON list of instruments, e.g. [{"symbol": "AAPL"}].
+    pub fn load(path: &str) -> Result<Registry, String> {
+        let contents =
+            fs::read_to_string(path).map_err(|e| format!("unable to read {}: {}", path, e))?;
+        let instruments: Vec<Instrument> = serde_json::from_str(&contents)
+            .map_err(|e| format!("unable to parse {}: {}", path, e))?;
+        let mut registry = Registry::new();
+        for instrument in instruments {
+            registry
+                .add(instrument)
+                .map_err(|e| format!("{}: {}", instrument.symbol, e))?;
+        }
+        info!(
+            "loaded {} instruments from {}",
+            registry.listings.len(),
+            path
+        );
+        Ok(registry)
+    }
+
+    pub fn add(&mut self, instrument: Instrument) -> Result<Instrument, Error> {
+        instrument.check()?;
+        if self.listings.contains_key(&instrument.symbol) {
+            return Err(Error::SymbolExists);
+        }
+        // Fill in the defaults so they show up when the instrument is looked at.
+        let mut instrument = instrument;
+        instrument.tick_size = Some(instrument.tick_size());
+        instrument.lot_size = Some(instrument.lot_size());
+        instrument.min_amount = Some(instrument.min_amount());
+        self.listings.insert(
+            instrument.symbol,
+            Listing {
+                instrument,
+                books: Books::new(),
+            },
+        );
+        Ok(instrument)
+    }
+
+    // Every instrument, delisted ones included, sorted by symbol.
+    pub fn list(&self) -> Vec<Instrument> {
+        let mut instruments: Vec<Instrument> = self
+            .listings
+            .values()
+            .map(|listing| listing.instrument)
+            .collect();
+        instruments.sort_by_key(|instrument| instrument.symbol);
+        instruments
+    }
+
+    pub fn get(&self, symbol: Symbol) -> Option<Instrument> {
+        self.listings.get(&symbol).map(|listing| listing.instrument)
+    }
+
+    // Books for an instrument which hasn't been delisted.
+    pub fn books(&self, symbol: Symbol) -> Result<&Books, Error> {
+        match self.listings.get(&symbol) {
+            Some(listing) if listing.instrument.status != InstrumentStatus::Delisted => {
+                Ok(&listing.books)
+            }
+            _ => Err(Error::UnknownSymbol),
+        }
+    }
+
+    // Books for an instrument which can take new orders right now.
+    pub fn tradable_books(&self, symbol: Symbol) -> Result<&Books, Error> {
+        let books = self.books(symbol)?;
+        if self.listings[&symbol].instrument.status == InstrumentStatus::Halted {
+            return Err(Error::Halted);
+        }
+        Ok(books)
+    }
+
+    // Books for every instrument which hasn't been delisted.
+    pub fn all_books(&self) -> Vec<(Symbol, &Books)> {
+        self.listings
+            .iter()
+            .filter(|(_, listing)| listing.instrument.status != InstrumentStatus::Delisted)
+            .map(|(&symbol, listing)| (symbol, &listing.books))
+            .collect()
+    }
+
+    // Halt, resume or delist an instrument. Once delisted it stays delisted.
+    pub fn set_status(
+        &mut self,
+        symbol: Symbol,
+        status: InstrumentStatus,
+    ) -> Result<Instrument, Error> {
+        let listing = match self.listings.get_mut(&symbol) {
+            Some(listing) if listing.instrument.status != InstrumentStatus::Delisted => listing,
+            _ => return Err(Error::UnknownSymbol),
+        };
+        if status == InstrumentStatus::Delisted {
+            // Nothing can be left resting on a delisted instrument.
+            listing.books = Books::new();
+        }
+        info!("{} is now {:?}", symbol, status);
+        listing.instrument.status = status;
+        Ok(listing.instrument)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use crate::decimal::Decimal;
+    use crate::error::Error;
+    use crate::instruments::{Instrument, InstrumentStatus, Registry, RejectReason, MAX_UNITS};
+    use crate::order_book::{
+        AmendRequest, OpenLimitOrder, OrderRequest, OrderType, Side, Symbol, TimeInForce,
+    };
+    use proptest::prelude::*;
+    use uuid::Uuid;
+
+    fn d(s: &str) -> Decimal {
+        s.parse().unwrap()
+    }
+
+    fn instrument(symbol: Symbol) -> Instrument {
+        Instrument {
+            symbol,
+            status: InstrumentStatus::Active,
+            price_scale: 0,
+            amount_scale: 0,
+            tick_size: None,
+            lot_size: None,
+            min_amount: None,
+            max_amount: None,
+            min_price: None,
+            max_price: None,
+            max_notional: None,
+        }
+    }
+
+    #[test]
+    fn test_registry() {
+        let aapl = Symbol::new("AAPL").unwrap();
+        let msft = Symbol::new("MSFT").unwrap();
+        let mut registry = Registry::new();
+        for &symbol in [msft, aapl].iter() {
+            let result = registry.add(instrument(symbol));
+            assert!(result.is_ok());
+        }
+        let result = registry.add(instrument(aapl));
+        assert_eq!(result.unwrap_err(), Error::SymbolExists);
+        let symbols: Vec<Symbol> = registry.list().iter().map(|i| i.symbol).collect();
+        assert_eq!(symbols, vec![aapl, msft]);
+        assert!(registry.books(Symbol::new("AMZN").unwrap()).is_err());
+
+        // Halted books can be looked at but not traded
+        let result = registry
+            .books(aapl)
+            .unwrap()
+            .buy
+            .write()
+            .unwrap()
+            .add_order(OpenLimitOrder {
+                id: Uuid::nil(),
+                owner: Uuid::nil(),
+                amount: d("1"),
+                symbol: aapl,
+                price: d("1"),
+                side: Side::Buy,
+            });
+        assert!(result.is_ok());
+        assert!(registry.set_status(aapl, InstrumentStatus::Halted).is_ok());
+        assert!(registry.books(aapl).is_ok());
+        assert_eq!(registry.tradable_books(aapl).err(), Some(Error::Halted));
+        assert!(registry.set_status(aapl, InstrumentStatus::Active).is_ok());
+        assert!(registry.tradable_books(aapl).is_ok());
+
+        // Delisting clears the books and is final
+        assert!(registry
+            .set_status(aapl, InstrumentStatus::Delisted)
+            .is_ok());
+        assert_eq!(registry.books(aapl).err(), Some(Error::UnknownSymbol));
+        assert!(registry.set_status(aapl, InstrumentStatus::Active).is_err());
+        assert_eq!(registry.all_books().len(), 1);
+        assert_eq!(
+            registry.get(aapl).unwrap().status,
+            InstrumentStatus::Delisted
+        );
+    }
+
+    #[test]
+    fn test_validate_order() {
+        let mut aapl = instrument(Symbol::new("AAPL").unwrap());
+        aapl.tick_size = Some(d("5"));
+        aapl.lot_size = Some(d("10"));
+        aapl.min_amount = Some(d("20"));
+        aapl.max_amount = Some(d("1000"));
+        aapl.min_price = Some(d("50"));
+        aapl.max_price = Some(d("500"));
+        assert!(aapl.check().is_ok());
+
+        let order = OrderRequest {
+            amount: d("100"),
+            symbol: aapl.symbol,
+            side: Side::Buy,
+            order_type: OrderType::Limit,
+            time_in_force: TimeInForce::GoodTillCancel,
+            price: Some(d("100")),
+            protection_price: None,
+        };
+        assert!(aapl.validate_order(order).is_ok());
+
+        let rejected = |amount: &str, price: &str| {
+            let mut order = order;
+            order.amount = d(amount);
+            order.price = Some(d(price));
+            aapl.validate_order(order).unwrap_err().reason
+        };
+        assert_eq!(rejected("0", "100"), RejectReason::ZeroAmount);
+        assert_eq!(rejected("-10", "100"), RejectReason::ZeroAmount);
+        assert_eq!(rejected("15", "100"), RejectReason::InvalidLotSize);
+        assert_eq!(rejected("10", "100"), RejectReason::BelowMinAmount);
+        assert_eq!(rejected("1010", "100"), RejectReason::AboveMaxAmount);
+        assert_eq!(rejected("100", "101"), RejectReason::InvalidTickSize);
+        assert_eq!(rejected("100", "45"), RejectReason::BelowMinPrice);
+        assert_eq!(rejected("100", "505"), RejectReason::AboveMaxPrice);
+        assert_eq!(rejected("100", "-5"), RejectReason::NegativePrice);
+        assert_eq!(
+            rejected("100.5", "100"),
+            RejectReason::InvalidAmountPrecision
+        );
+        assert_eq!(
+            rejected("100", "100.5"),
+            RejectReason::InvalidPricePrecision
+        );
+        // Trailing zeros are fine
+        assert!(aapl
+            .validate_order({
+                let mut order = order;
+                order.price = Some(d("100.00"));
+                order
+            })
+            .is_ok());
+
+        // Protection prices only need to be on a tick
+        let mut market = order;
+        market.order_type = OrderType::Market;
+        market.price = None;
+        market.protection_price = Some(d("1000"));
+        assert!(aapl.validate_order(market).is_ok());
+        market.protection_price = Some(d("1001"));
+        assert_eq!(
+            aapl.validate_order(market).unwrap_err().reason,
+            RejectReason::InvalidTickSize
+        );
+
+        aapl.max_amount = Some(d("10"));
+        assert!(aapl.check().is_err());
+    }
+
+    #[test]
+    fn test_scale() {
+        let mut aapl = instrument(Symbol::new("AAPL").unwrap());
+        aapl.price_scale = 2;
+        aapl.amount_scale = 1;
+        assert!(aapl.check().is_ok());
+        assert_eq!(aapl.tick_size(), d("0.01"));
+        assert_eq!(aapl.min_amount(), d("0.1"));
+
+        // Everything comes back at the instrument's scale
+        let order = aapl
+            .validate_order(OrderRequest {
+                amount: d("2"),
+                symbol: aapl.symbol,
+                side: Side::Sell,
+                order_type: OrderType::Limit,
+                time_in_force: TimeInForce::GoodTillCancel,
+                price: Some(d("187.2")),
+                protection_price: None,
+            })
+            .unwrap();
+        assert_eq!(order.price.unwrap().to_string(), "187.20");
+        assert_eq!(order.amount.to_string(), "2.0");
+        let amend = aapl
+            .validate_amend(AmendRequest {
+                price: Some(d("187.25")),
+                amount: None,
+            })
+            .unwrap();
+        assert_eq!(amend.price.unwrap().to_string(), "187.25");
+        assert_eq!(
+            aapl.validate_amend(AmendRequest {
+                price: Some(d("187.255")),
+                amount: None,
+            })
+            .unwrap_err()
+            .reason,
+            RejectReason::InvalidPricePrecision
+        );
+
+        // The reference data has to fit the scale too
+        aapl.tick_size = Some(d("0.005"));
+        assert!(aapl.check().is_err());
+        aapl.tick_size = Some(d("0.05"));
+        assert!(aapl.check().is_ok());
+        aapl.price_scale = 19;
+        assert!(aapl.check().is_err());
+    }
+
+    #[test]
+    fn test_limits() {
+        let mut aapl = instrument(Symbol::new("AAPL").unwrap());
+        aapl.price_scale = 2;
+        let order = OrderRequest {
+            amount: d("1000000000000000"),
+            symbol: aapl.symbol,
+            side: Side::Buy,
+            order_type: OrderType::Limit,
+            time_in_force: TimeInForce::GoodTillCancel,
+            price: Some(d("1")),
+            protection_price: None,
+        };
+        assert!(aapl.validate_order(order).is_ok());
+        let rejected = |order: OrderRequest| aapl.validate_order(order).unwrap_err().reason;
+        let mut too_big = order;
+        too_big.amount = d("1000000000000001");
+        assert_eq!(rejected(too_big), RejectReason::AboveMaxAmount);
+        let mut too_big = order;
+        too_big.price = Some(d("10000000000000.01"));
+        assert_eq!(rejected(too_big), RejectReason::AboveMaxPrice);
+        let mut too_big = order;
+        too_big.order_type = OrderType::Market;
+        too_big.price = None;
+        too_big.protection_price = Some(d("10000000000000.01"));
+        assert_eq!(rejected(too_big), RejectReason::AboveMaxPrice);
+
+        aapl.max_notional = Some(d("1000"));
+        let mut order = order;
+        order.amount = d("100");
+        order.price = Some(d("10"));
+        assert!(aapl.validate_order(order).is_ok());
+        order.price = Some(d("10.01"));
+        assert_eq!(
+            aapl.validate_order(order).unwrap_err().reason,
+            RejectReason::AboveMaxNotional
+        );
+        assert!(aapl.validate_notional(d("10"), d("100")).is_ok());
+        assert!(aapl.validate_notional(d("10"), d("101")).is_err());
+        // Market orders are checked at their protection price or the top of the band
+        let mut market = order;
+        market.order_type = OrderType::Market;
+        market.price = None;
+        assert_eq!(
+            aapl.validate_order(market).unwrap_err().reason,
+            RejectReason::AboveMaxNotional
+        );
+        market.protection_price = Some(d("10"));
+        assert!(aapl.validate_order(market).is_ok());
+        market.protection_price = None;
+        aapl.max_price = Some(d("5"));
+        assert!(aapl.validate_order(market).is_ok());
+
+        aapl.max_amount = Some(d("1000000000000001"));
+        assert!(aapl.check().is_err());
+        aapl.max_amount = None;
+        aapl.amount_scale = 17;
+        assert!(aapl.check().is_err());
+    }
+
+    proptest! {
+        // Anything that gets through validation, at any scale, can be multiplied out and
+        // averaged without overflowing.
+        #[test]
+        fn prop_validated_orders_fit(
+            price_scale in 0..=9u32,
+            amount_scale in 0..=9u32,
+            price: u32,
+            amount: u32,
+            input_price_scale in 0..=18u32,
+            input_amount_scale in 0..=18u32,
+        ) {
+            let mut aapl = instrument(Symbol::new("AAPL").unwrap());
+            aapl.price_scale = price_scale;
+            aapl.amount_scale = amount_scale;
+            prop_assert!(aapl.check().is_ok());
+            let order = OrderRequest {
+                amount: Decimal::new(amount as i128, input_amount_scale),
+                symbol: aapl.symbol,
+                side: Side::Buy,
+                order_type: OrderType::Limit,
+                time_in_force: TimeInForce::GoodTillCancel,
+                price: Some(Decimal::new(price as i128, input_price_scale)),
+                protection_price: None,
+            };
+            if let Ok(order) = aapl.validate_order(order) {
+                let price = order.price.unwrap();
+                prop_assert_eq!(price.scale(), price_scale);
+                prop_assert_eq!(order.amount.scale(), amount_scale);
+                let notional = price.checked_mul(order.amount);
+                prop_assert!(notional.is_some());
+                let average = notional.unwrap().checked_div(order.amount, price_scale.max(8));
+                prop_assert_eq!(average, Some(price));
+            }
+        }
+
+        // The largest amount at the largest price still fits.
+        #[test]
+        fn prop_limits_fit(price_scale in 0..=18u32, amount_scale in 0..=18u32) {
+            prop_assume!(price_scale + amount_scale <= 18);
+            let price = Decimal::new(MAX_UNITS, price_scale);
+            let amount = Decimal::new(MAX_UNITS, amount_scale);
+            let notional = price.checked_mul(amount).unwrap();
+            prop_assert!(notional.checked_div(amount, price_scale.max(8)).is_some());
+        }
+    }
+}
@@ -0,0 +1,569 @@
+use crate::accounts::{Account, Accounts, ApiKey};
+use crate::balances::TransferRequest;
+use crate::error::Error;
+use crate::feed::Feed;
+use crate::fees::Fee;
+use crate::instruments::{Instrument, InstrumentStatus, Registry};
+use crate::order_book::{AmendRequest, OrderEvent, OrderRequest, Side, Symbol, Trade};
+use crate::trade_log::TradeLog;
+use log::{error, warn};
+use serde::{Deserialize, Serialize};
+use std::fs::{File, OpenOptions, Permissions};
+use std::io::{self, Read, Write};
+use std::os::unix::fs::{OpenOptionsExt, PermissionsExt};
+use uuid::Uuid;
+
+// A change to the exchange, journaled before it is acknowledged.
+// Changes to the books carry the order events they made, which is what they are replayed
+// from, so books come back exactly as they were, queue order included.
+#[derive(Serialize, Deserialize, Debug, Clone)]
+#[serde(tag = "type")]
+pub enum Record {
+    Order {
+        id: Uuid,
+        // Orders from before there were accounts have the nil id.
+        #[serde(default)]
+        account: Uuid,
+        request: OrderRequest,
+        events: Vec<OrderEvent>,
+        trades: Vec<Trade>,
+        // What each side of the trades was charged, replayed as it was rather than from
+        // whatever the fee schedule is now.
+        #[serde(default)]
+        fees: Vec<Fee>,
+    },
+    Cancel {
+        id: Uuid,
+        symbol: Symbol,
+        events: Vec<OrderEvent>,
+    },
+    Amend {
+        id: Uuid,
+        symbol: Symbol,
+        amend: AmendRequest,
+        events: Vec<OrderEvent>,
+    },
+    AddInstrument {
+        instrument: Instrument,
+    },
+    SetStatus {
+        symbol: Symbol,
+        status: InstrumentStatus,
+    },
+    OpenAccount {
+        account: Account,
+    },
+    AddKey {
+        key: ApiKey,
+    },
+    RevokeKey {
+        account: Uuid,
+        id: Uuid,
+    },
+    // A deposit, or a withdrawal if the amount is negative.
+    Transfer {
+        account: Uuid,
+        transfer: TransferRequest,
+    },
+}
+
+// An append-only file of records, one JSON object per line.
+pub struct Journal {
+    file: File,
+    records: usize,
+}
+
+impl Journal {
+    // Opens the journal at path, creating it if need be, and reads back every record in it.
+    // A partly written last record, from stopping in the middle of appending it, has no
+    // newline yet. It was never acknowledged and is dropped. Every record with a newline was,
+    // so any of those that can't be read is an error. It has API key secrets in it, so only
+    // its owner can read it, even if it was made before that was the case.
+    pub fn open(path: &str) -> Result<(Journal, Vec<Record>), String> {
+        let mut file = OpenOptions::new()
+            .read(true)
+            .append(true)
+            .create(true)
+            .mode(0o600)
+            .open(path)
+            .map_err(|e| format!("unable to open {}: {}", path, e))?;
+        file.set_permissions(Permissions::from_mode(0o600))
+            .map_err(|e| format!("unable to restrict {}: {}", path, e))?;
+        let mut contents = Vec::new();
+        file.read_to_end(&mut contents)
+            .map_err(|e| format!("unable to read {}: {}", path, e))?;
+        let mut records = Vec::new();
+        let mut complete = 0;
+        for line in contents.split_inclusive(|&b| b == b'\n') {
+            // Only ever the last line.
+            if !line.ends_with(b"\n") {
+                warn!("dropping partly written last record of {}", path);
+                file.set_len(complete as u64)
+                    .map_err(|e| format!("unable to truncate {}: {}", path, e))?;
+                break;
+            }
+            let record = serde_json::from_slice(line)
+                .map_err(|e| format!("{} record {}: {}", path, records.len() + 1, e))?;
+            records.push(record);
+            complete += line.len();
+        }
+        let journal = Journal {
+            file,
+            records: records.len(),
+        };
+        Ok((journal, records))
+    }
+
+    // Only returns once the record is on disk.
+    pub fn append(&mut self, record: &Record) -> io::Result<()> {
+        let mut line = serde_json::to_vec(record)?;
+        line.push(b'\n');
+        self.file.write_all(&line)?;
+        self.file.sync_data()?;
+        self.records += 1;
+        Ok(())
+    }
+
+    // How many records have been written, including those read back when it was opened.
+    pub fn records(&self) -> usize {
+        self.records
+    }
+}
+
+// Rebuilds the exchange from its journal, applying the records from index from onwards in the
+// order they were written. Those before it are already in a snapshot.
+pub fn replay(
+    records: &[Record],
+    from: usize,
+    registry: &mut Registry,
+    accounts: &mut Accounts,
+    trade_log: &mut TradeLog,
+    feed: &mut Feed,
+) -> Result<(), String> {
+    if from > records.len() {
+        return Err(format!(
+            "snapshot includes {} records but there are only {}",
+            from,
+            records.len()
+        ));
+    }
+    for (i, record) in records.iter().enumerate().skip(from) {
+        replay_record(record, registry, accounts, trade_log, feed)
+            .map_err(|e| format!("record {}: {}", i + 1, e))?;
+    }
+    Ok(())
+}
+
+// A change that couldn't be settled when it happened was logged and kept without touching
+// the balances, so it's the same again here rather than stopping startup.
+fn settle(
+    accounts: &mut Accounts,
+    symbol: Symbol,
+    owner: Uuid,
+    events: &[OrderEvent],
+    trades: &[Trade],
+    fees: &[Fee],
+) {
+    if let Err(e) = accounts
+        .ledger_mut()
+        .settle(symbol, owner, events, trades, fees)
+    {
+        error!(
+            "unable to settle {} book change for {}: {}",
+            symbol, owner, e
+        );
+    }
+}
+
+fn replay_record(
+    record: &Record,
+    registry: &mut Registry,
+    accounts: &mut Accounts,
+    trade_log: &mut TradeLog,
+    feed: &mut Feed,
+) -> Result<(), Error> {
+    match record {
+        Record::Order {
+            id,
+            account,
+            request,
+            events,
+            trades,
+            fees,
+        } => {
+            apply_events(registry, feed, request.symbol, *account, events, trades)?;
+            accounts.record_order(*account, *id);
+            settle(accounts, request.symbol, *account, events, trades, fees);
+            for &trade in trades.iter() {
+                trade_log.record(trade);
+            }
+        }
+        Record::Cancel { symbol, events, .. } => {
+            apply_events(registry, feed, *symbol, Uuid::nil(), events, &[])?;
+            settle(accounts, *symbol, Uuid::nil(), events, &[], &[]);
+        }
+        // An amend that requeues the order adds it back for whoever it belonged to.
+        Record::Amend {
+            id, symbol, events, ..
+        } => {
+            let owner = {
+                let (buy_book, sell_book) = registry.books(*symbol)?.read();
+                buy_book
+                    .get_order(*id)
+                    .or_else(|| sell_book.get_order(*id))
+                    .ok_or(Error::NoSuchOrder)?
+                    .owner
+            };
+            apply_events(registry, feed, *symbol, owner, events, &[])?;
+            settle(accounts, *symbol, owner, events, &[], &[]);
+        }
+        Record::AddInstrument { instrument } => match registry.add(*instrument) {
+            Err(Error::SymbolExists) => {
+                warn!(
+                    "{} was listed while running and is now in the instruments config too, \
+                     keeping the config's",
+                    instrument.symbol
+                );
+            }
+            result => {
+                result?;
+            }
+        },
+        Record::SetStatus { symbol, status } => {
+            registry.set_status(*symbol, *status)?;
+            if *status == InstrumentStatus::Delisted {
+                feed.delist(*symbol);
+                accounts.ledger_mut().delist(*symbol)?;
+            }
+        }
+        Record::OpenAccount { account } => accounts.insert(account.clone())?,
+        Record::AddKey { key } => accounts.insert_key(key.clone())?,
+        Record::RevokeKey { account, id } => {
+            accounts.revoke_key(*account, *id)?;
+        }
+        Record::Transfer { account, transfer } => {
+            accounts.ledger_mut().transfer(*account, *transfer)?;
+        }
+    }
+    Ok(())
+}
+
+// Remakes the changes to a symbol's books, and publishes them so the feed carries on with
+// the same sequence numbers. Any orders added belong to owner.
+fn apply_events(
+    registry: &Registry,
+    feed: &mut Feed,
+    symbol: Symbol,
+    owner: Uuid,
+    events: &[OrderEvent],
+    trades: &[Trade],
+) -> Result<(), Error> {
+    let (mut buy_book, mut sell_book) = registry.books(symbol)?.lock();
+    for &event in events.iter() {
+        let book = if event.level().0 == Side::Buy {
+            &mut buy_book
+        } else {
+            &mut sell_book
+        };
+        book.apply_event(symbol, owner, event)?;
+    }
+    buy_book.take_events();
+    sell_book.take_events();
+    feed.publish(symbol, &buy_book, &sell_book, events, trades)
+}
+
+#[cfg(test)]
+mod tests {
+    use crate::accounts::{AccountRequest, Accounts, Scope};
+    use crate::balances::TransferRequest;
+    use crate::feed::Feed;
+    use crate::fees::Fee;
+    use crate::instruments::{InstrumentStatus, Registry};
+    use crate::journal::{replay, Journal, Record};
+    use crate::order_book::{AmendRequest, OpenLimitOrder, OrderBook, OrderRequest, Side, Symbol};
+    use crate::trade_log::{Liquidity, TradeLog};
+    use std::fs;
+    use std::io::Write;
+    use std::os::unix::fs::PermissionsExt;
+    use uuid::Uuid;
+
+    fn temp_path() -> String {
+        let path = std::env::temp_dir().join(format!("journal-{}.jsonl", Uuid::new_v4()));
+        path.to_str().unwrap().to_string()
+    }
+
+    fn order_request(side: &str, price: u32, amount: u32) -> OrderRequest {
+        let json = format!(
+            r#"{{"symbol": "AAPL", "side": "{}", "price": {}, "amount": {}}}"#,
+            side, price, amount
+        );
+        serde_json::from_str(&json).unwrap()
+    }
+
+    // What submitting an order does to the books, as journaled.
+    fn submit(registry: &Registry, account: Uuid, request: OrderRequest) -> Record {
+        let (mut buy_book, mut sell_book) = registry.books(request.symbol).unwrap().lock();
+        let (book, opposing_book) = if request.side == Side::Buy {
+            (&mut buy_book, &mut sell_book)
+        } else {
+            (&mut sell_book, &mut buy_book)
+        };
+        let order = request.to_order(Uuid::new_v4(), account).unwrap();
+        let fr = opposing_book
+            .fill_order(order, request.effective_time_in_force())
+            .unwrap();
+        if fr.remaining.is_positive() {
+            let mut rest = order;
+            rest.amount = fr.remaining;
+            book.add_order(rest).unwrap();
+        }
+        let mut events = opposing_book.take_events();
+        events.extend(book.take_events());
+        Record::Order {
+            id: order.id,
+            account,
+            request,
+            events,
+            trades: fr.trades,
+            fees: Vec::new(),
+        }
+    }
+
+    fn orders(book: &OrderBook) -> Vec<(Uuid, Uuid, String, String)> {
+        book.get_book()
+            .into_iter()
+            .flatten()
+            .map(|order: OpenLimitOrder| {
+                (
+                    order.id,
+                    order.owner,
+                    order.price.to_string(),
+                    order.amount.to_string(),
+                )
+            })
+            .collect()
+    }
+
+    #[test]
+    fn test_journal() {
+        let path = temp_path();
+        let (mut journal, records) = Journal::open(&path).unwrap();
+        assert!(records.is_empty());
+        let mode = fs::metadata(&path).unwrap().permissions().mode();
+        assert_eq!(mode & 0o777, 0o600);
+        let aapl = Symbol::new("AAPL").unwrap();
+        let halt = Record::SetStatus {
+            symbol: aapl,
+            status: InstrumentStatus::Halted,
+        };
+        journal.append(&halt).unwrap();
+        journal.append(&halt).unwrap();
+
+        // Stopping partway through writing a record.
+        let mut file = fs::OpenOptions::new().append(true).open(&path).unwrap();
+        file.write_all(br#"{"type": "SetSta"#).unwrap();
+        let (mut journal, records) = Journal::open(&path).unwrap();
+        assert_eq!(records.len(), 2);
+        journal.append(&halt).unwrap();
+        let (journal, records) = Journal::open(&path).unwrap();
+        assert_eq!(records.len(), 3);
+        assert_eq!(journal.records(), 3);
+
+        // Anything else that can't be read is a problem, even at the end.
+        fs::write(&path, "{}\n{}\n").unwrap();
+        assert!(Journal::open(&path).is_err());
+        let mut contents = serde_json::to_vec(&halt).unwrap();
+        contents.extend_from_slice(b"\n{\"type\": \"SetSta\n");
+        fs::write(&path, contents).unwrap();
+        let e = Journal::open(&path).err().unwrap();
+        assert!(e.contains("record 2"), "{}", e);
+        assert_eq!(fs::read(&path).unwrap().last(), Some(&b'\n'));
+        fs::remove_file(&path).unwrap();
+    }
+
+    #[test]
+    fn test_replay() {
+        let aapl = Symbol::new("AAPL").unwrap();
+        let mut registry = Registry::new();
+        let mut records = vec![Record::AddInstrument {
+            instrument: serde_json::from_str(r#"{"symbol": "AAPL"}"#).unwrap(),
+        }];
+        registry
+            .add(serde_json::from_str(r#"{"symbol": "AAPL"}"#).unwrap())
+            .unwrap();
+        let mut accounts = Accounts::new();
+        let mut owners = Vec::new();
+        for name in ["alice", "bob"].iter() {
+            let request = AccountRequest {
+                name: name.to_string(),
+            };
+            let account = accounts.open(request, 0).unwrap();
+            owners.push(account.id);
+            records.push(Record::OpenAccount { account });
+        }
+        for (i, &(side, price, amount)) in [
+            ("Sell", 10, 5),
+            ("Sell", 10, 3),
+            ("Sell", 11, 4),
+            ("Buy", 8, 2),
+            ("Buy", 9, 1),
+            ("Buy", 10, 6),
+            ("Buy", 8, 7),
+        ]
+        .iter()
+        .enumerate()
+        {
+            let request = order_request(side, price, amount);
+            records.push(submit(&registry, owners[i % 2], request));
+        }
+        // Bob paid 10 basis points on the 50 he bought from Alice, whatever the fee schedule
+        // says now.
+        if let Record::Order { trades, fees, .. } = &mut records[8] {
+            let trade = trades[0];
+            fees.push(Fee {
+                trade: trade.id,
+                order: trade.aggressor_id,
+                account: owners[1],
+                symbol: aapl,
+                liquidity: Liquidity::Taker,
+                notional: "50".parse().unwrap(),
+                bps: "10".parse().unwrap(),
+                amount: "0.05".parse().unwrap(),
+                timestamp: trade.timestamp,
+            });
+        }
+        {
+            let (mut buy_book, _sell_book) = registry.books(aapl).unwrap().lock();
+            let first_at_8 = buy_book.get_book()[1][0].id;
+            buy_book.remove_order(first_at_8).unwrap();
+            records.push(Record::Cancel {
+                id: first_at_8,
+                symbol: aapl,
+                events: buy_book.take_events(),
+            });
+            // Moving the order that's left at 8 up to 9 puts it behind the one already there.
+            let second_at_8 = buy_book.get_book()[1][0].id;
+            let amend = AmendRequest {
+                price: Some("9".parse().unwrap()),
+                amount: None,
+            };
+            buy_book.amend_order(second_at_8, amend).unwrap();
+            records.push(Record::Amend {
+                id: second_at_8,
+                symbol: aapl,
+                amend,
+                events: buy_book.take_events(),
+            });
+        }
+        records.push(Record::SetStatus {
+            symbol: aapl,
+            status: InstrumentStatus::Halted,
+        });
+        let mut keys = Vec::new();
+        for &scope in [Scope::ReadOnly, Scope::Trade].iter() {
+            let key = accounts.add_key(owners[1], scope, 0).unwrap();
+            keys.push(key.clone());
+            records.push(Record::AddKey { key });
+        }
+        accounts.revoke_key(owners[1], keys[0].id).unwrap();
+        records.push(Record::RevokeKey {
+            account: owners[1],
+            id: keys[0].id,
+        });
+        records.push(Record::Transfer {
+            account: owners[1],
+            transfer: TransferRequest {
+                symbol: None,
+                amount: "1000".parse().unwrap(),
+            },
+        });
+
+        // Through the journal and back.
+        let path = temp_path();
+        let (mut journal, _) = Journal::open(&path).unwrap();
+        for record in records.iter() {
+            journal.append(record).unwrap();
+        }
+        let (_, records) = Journal::open(&path).unwrap();
+        fs::remove_file(&path).unwrap();
+
+        let mut recovered = Registry::new();
+        let mut recovered_accounts = Accounts::new();
+        let mut trade_log = TradeLog::new();
+        let mut feed = Feed::new();
+        replay(
+            &records,
+            0,
+            &mut recovered,
+            &mut recovered_accounts,
+            &mut trade_log,
+            &mut feed,
+        )
+        .unwrap();
+
+        let (buy_book, sell_book) = registry.books(aapl).unwrap().read();
+        let (recovered_buy_book, recovered_sell_book) = recovered.books(aapl).unwrap().read();
+        assert_eq!(buy_book.get_book()[0].len(), 2);
+        assert!(!orders(&sell_book).is_empty());
+        assert_eq!(orders(&recovered_buy_book), orders(&buy_book));
+        assert_eq!(orders(&recovered_sell_book), orders(&sell_book));
+        let trades: Vec<_> = records
+            .iter()
+            .flat_map(|record| match record {
+                Record::Order { trades, .. } => trades.clone(),
+                _ => Vec::new(),
+            })
+            .collect();
+        assert_eq!(trades.len(), 2);
+        assert_eq!(trade_log.get_trades(), trades);
+        assert_eq!(
+            recovered.get(aapl).unwrap().status,
+            InstrumentStatus::Halted
+        );
+        assert_eq!(recovered_accounts.list(), accounts.list());
+        assert_eq!(recovered_accounts.orders(owners[1]).len(), 3);
+        assert_eq!(recovered_accounts.keys(owners[1]), accounts.keys(owners[1]));
+        assert!(recovered_accounts.key(keys[0].id).is_none());
+        // Bob bought 6 at 10, one of them from himself, and still has 2 on offer. Alice sold
+        // him the other 5 and still has bids for 8 at 9.
+        let bob = recovered_accounts.ledger().balances(owners[1]);
+        assert_eq!(bob.cash.total, "949.95".parse().unwrap());
+        assert_eq!(bob.cash.reserved, "0".parse().unwrap());
+        assert_eq!(bob.instruments[&aapl].total, "5".parse().unwrap());
+        assert_eq!(bob.instruments[&aapl].reserved, "2".parse().unwrap());
+        let alice = recovered_accounts.ledger().balances(owners[0]);
+        assert_eq!(alice.cash.total, "50".parse().unwrap());
+        assert_eq!(alice.cash.reserved, "72".parse().unwrap());
+        for (owner, net) in [(owners[1], "5"), (owners[0], "-5")].iter() {
+            let position = recovered_accounts.ledger().positions(*owner)[&aapl];
+            assert_eq!(position.traded_net, net.parse().unwrap());
+            assert_eq!(position.avg_price, Some("10".parse().unwrap()));
+            assert!(position.realized_pnl.is_zero());
+        }
+        // The feed carries on from where it was.
+        let events: usize = records
+            .iter()
+            .map(|record| match record {
+                Record::Order { events, .. }
+                | Record::Cancel { events, .. }
+                | Record::Amend { events, .. } => events.len(),
+                _ => 0,
+            })
+            .sum();
+        let snapshot = feed.orders_snapshot(aapl, &recovered_buy_book, &recovered_sell_book);
+        assert_eq!(snapshot.seq, events as u64);
+
+        // Events that don't match the book can't be replayed.
+        let mut bad = records.clone();
+        bad.push(records[5].clone());
+        let result = replay(
+            &bad,
+            0,
+            &mut Registry::new(),
+            &mut Accounts::new(),
+            &mut TradeLog::new(),
+            &mut Feed::new(),
+        );
+        assert!(result.unwrap_err().starts_with("record 18:"));
+    }
+}
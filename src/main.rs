@@ -1,3 +1,7 @@
+#[macro_use]
+extern crate diesel;
+#[macro_use]
+extern crate diesel_migrations;
 extern crate env_logger;
 extern crate futures;
 extern crate hyper;
@@ -6,195 +10,1224 @@ extern crate lazy_static;
 extern crate log;
 
 use hyper::client::HttpConnector;
+use hyper::header::{CONNECTION, UPGRADE};
 use hyper::rt::Future;
 use hyper::service::service_fn;
 use hyper::StatusCode;
 use hyper::{Body, Client, Method, Request, Response};
 
 use std::collections::VecDeque;
+use std::env;
+use std::path::Path;
 use std::string::String;
-use std::sync::{Arc, RwLock, RwLockWriteGuard};
+use std::sync::{Mutex, RwLock};
+use std::time::Duration;
 use std::vec::Vec;
 
-use futures::{future, Stream};
-use log::info;
-use serde_json::Result;
+use futures::sync::mpsc;
+use futures::{future, Sink, Stream};
+use log::{error, info, warn};
+use serde::de::DeserializeOwned;
 use std::collections::HashMap;
+use tokio::timer::Interval;
+use tokio_codec::Decoder;
+use tokio_signal::unix::{Signal, SIGTERM};
+use uuid::Uuid;
+use websocket_base::codec::ws::{Context, MessageCodec};
+use websocket_base::header::{WebSocketAccept, WebSocketKey};
+use websocket_base::OwnedMessage;
 
+use crate::decimal::Decimal;
+use crate::error::Error;
+
+mod accounts;
+mod auth;
+mod balances;
+// diesel 1's macros put impls inside functions, which newer compilers warn about.
+#[allow(non_local_definitions)]
+mod db;
+mod decimal;
+mod error;
+mod feed;
+mod fees;
+mod instruments;
+mod journal;
 mod order_book;
+mod positions;
+#[allow(non_local_definitions)]
+mod schema;
+mod snapshot;
+mod ticker;
+mod trade_log;
 
-type OrderBookRef = Arc<RwLock<order_book::OrderBook>>;
 lazy_static! {
-    static ref BUY: HashMap<order_book::Symbol, OrderBookRef> = {
-        let mut buy = HashMap::new();
-        buy.insert(
-            order_book::Symbol::AAPL,
-            Arc::new(RwLock::new(order_book::OrderBook::new(
-                order_book::Side::Buy,
-            ))),
-        );
-        buy.insert(
-            order_book::Symbol::MSFT,
-            Arc::new(RwLock::new(order_book::OrderBook::new(
-                order_book::Side::Buy,
-            ))),
-        );
-        buy.insert(
-            order_book::Symbol::AMZN,
-            Arc::new(RwLock::new(order_book::OrderBook::new(
-                order_book::Side::Buy,
-            ))),
+    static ref INSTRUMENTS: RwLock<instruments::Registry> = {
+        let path =
+            env::var("INSTRUMENTS_CONFIG").unwrap_or_else(|_| "instruments.json".to_string());
+        if !Path::new(&path).exists() {
+            warn!(
+                "no instruments config at {}, starting with no instruments",
+                path
+            );
+            return RwLock::new(instruments::Registry::new());
+        }
+        match instruments::Registry::load(&path) {
+            Ok(registry) => RwLock::new(registry),
+            Err(e) => panic!("{}", e),
+        }
+    };
+    static ref ACCOUNTS: RwLock<accounts::Accounts> = RwLock::new(accounts::Accounts::new());
+    // Never changes once it's loaded, so it doesn't need a lock.
+    static ref FEES: fees::FeeSchedule = {
+        let path = env::var("FEES_CONFIG").unwrap_or_else(|_| "fees.json".to_string());
+        if !Path::new(&path).exists() {
+            warn!("no fees config at {}, trades are free", path);
+            return fees::FeeSchedule::default();
+        }
+        match fees::FeeSchedule::load(&path) {
+            Ok(schedule) => schedule,
+            Err(e) => panic!("{}", e),
+        }
+    };
+    // Taken after ACCOUNTS. The admin key, if ADMIN_KEY and ADMIN_SECRET are set, can open
+    // accounts and manage instruments before any account has a key of its own.
+    static ref AUTH: Mutex<auth::Authenticator> = {
+        let admin = match (env::var("ADMIN_KEY"), env::var("ADMIN_SECRET")) {
+            (Ok(key), Ok(secret)) => match Uuid::parse_str(&key) {
+                Ok(key) if !secret.is_empty() => Some((key, secret)),
+                _ => panic!("ADMIN_KEY must be a uuid and ADMIN_SECRET can't be empty"),
+            },
+            (Err(_), Err(_)) => None,
+            _ => panic!("ADMIN_KEY and ADMIN_SECRET have to be set together"),
+        };
+        Mutex::new(auth::Authenticator::new(admin))
+    };
+    static ref TRADES: RwLock<trade_log::TradeLog> = RwLock::new(trade_log::TradeLog::new());
+    static ref FEED: Mutex<feed::Feed> = Mutex::new(feed::Feed::new());
+    // Opening the journal restores the last snapshot and replays the journal after it, which
+    // needs write access to everything else, so it has to be initialized before taking any
+    // requests.
+    static ref JOURNAL: Mutex<journal::Journal> = {
+        let path = env::var("JOURNAL").unwrap_or_else(|_| "journal.jsonl".to_string());
+        let (journal, records) = match journal::Journal::open(&path) {
+            Ok(opened) => opened,
+            Err(e) => panic!("{}", e),
+        };
+        let mut registry = INSTRUMENTS.write().unwrap();
+        let mut accounts = ACCOUNTS.write().unwrap();
+        let mut trades = TRADES.write().unwrap();
+        let mut feed = FEED.lock().unwrap();
+        let from = match snapshot::Snapshot::load(&snapshot_path()) {
+            Ok(Some(snapshot)) => {
+                let restored =
+                    snapshot.restore(&mut registry, &mut accounts, &mut trades, &mut feed);
+                if let Err(e) = restored {
+                    panic!("unable to restore {}: {}", snapshot_path(), e);
+                }
+                info!("restored {:?}", snapshot.summary());
+                snapshot.journal_records
+            }
+            Ok(None) => 0,
+            Err(e) => panic!("{}", e),
+        };
+        let replayed = journal::replay(
+            &records,
+            from,
+            &mut registry,
+            &mut accounts,
+            &mut trades,
+            &mut feed,
         );
+        if let Err(e) = replayed {
+            panic!("unable to recover from {}: {}", path, e);
+        }
+        info!("recovered {} records from {}", records.len() - from, path);
+        if let Some(database) = DATABASE.as_ref() {
+            if let Err(e) = database.catch_up(&records) {
+                panic!("unable to bring the database up to date: {}", e);
+            }
+        }
+        Mutex::new(journal)
+    };
+    // Order and trade history, kept if DATABASE_URL is set.
+    static ref DATABASE: Option<db::Database> = env::var("DATABASE_URL").ok().map(|url| {
+        match db::Database::connect(&url) {
+            Ok(database) => database,
+            Err(e) => panic!("{}", e),
+        }
+    });
+    // Held while taking and saving a snapshot so they're saved in the order they were taken.
+    static ref SNAPSHOTS: Mutex<()> = Mutex::new(());
+}
+
+fn snapshot_path() -> String {
+    env::var("SNAPSHOT").unwrap_or_else(|_| "snapshot.json".to_string())
+}
+
+type GenericError = Box<dyn std::error::Error + Send + Sync>;
+type ResponseFuture = Box<dyn Future<Item = Response<Body>, Error = GenericError> + Send>;
+
+fn json_response<T: serde::Serialize>(status: StatusCode, body: &T) -> ResponseFuture {
+    Box::new(future::ok(
+        Response::builder()
+            .status(status)
+            .body(Body::from(serde_json::to_string(body).unwrap()))
+            .unwrap(),
+    ))
+}
+
+fn empty_response(status: StatusCode) -> ResponseFuture {
+    Box::new(future::ok(
+        Response::builder()
+            .status(status)
+            .body(Body::empty())
+            .unwrap(),
+    ))
+}
 
-        buy
+// Errors get a JSON body and the status for their kind.
+fn error_response(e: Error) -> ResponseFuture {
+    let status = match e {
+        Error::InvalidRequest(_)
+        | Error::InvalidSymbol(_)
+        | Error::InvalidOrder(_)
+        | Error::InvalidInstrument(_)
+        | Error::InvalidFees(_)
+        | Error::InvalidAccount(_)
+        | Error::InsufficientBalance(_)
+        | Error::Rejected(_)
+        | Error::Halted
+        | Error::AmendWouldCross => StatusCode::BAD_REQUEST,
+        Error::Unauthenticated(_) => StatusCode::UNAUTHORIZED,
+        Error::Forbidden => StatusCode::FORBIDDEN,
+        Error::UnknownSymbol | Error::NoSuchOrder | Error::NoSuchAccount | Error::NoSuchKey => {
+            StatusCode::NOT_FOUND
+        }
+        Error::SymbolExists | Error::DuplicateOrder | Error::AccountExists => StatusCode::CONFLICT,
+        Error::EventsExpired => StatusCode::GONE,
+        Error::HistoryUnavailable => StatusCode::SERVICE_UNAVAILABLE,
+        Error::WrongSide
+        | Error::Overflow
+        | Error::SnapshotFailed(_)
+        | Error::DatabaseFailed(_) => StatusCode::INTERNAL_SERVER_ERROR,
     };
-    static ref SELL: HashMap<order_book::Symbol, OrderBookRef> = {
-        let mut sell = HashMap::new();
+    json_response(status, &e)
+}
 
-        sell.insert(
-            order_book::Symbol::AAPL,
-            Arc::new(RwLock::new(order_book::OrderBook::new(
-                order_book::Side::Sell,
-            ))),
-        );
-        sell.insert(
-            order_book::Symbol::MSFT,
-            Arc::new(RwLock::new(order_book::OrderBook::new(
-                order_book::Side::Sell,
-            ))),
-        );
-        sell.insert(
-            order_book::Symbol::AMZN,
-            Arc::new(RwLock::new(order_book::OrderBook::new(
-                order_book::Side::Sell,
-            ))),
+fn result_response<T: serde::Serialize>(
+    status: StatusCode,
+    result: Result<T, Error>,
+) -> ResponseFuture {
+    match result {
+        Ok(body) => json_response(status, &body),
+        Err(e) => error_response(e),
+    }
+}
+
+fn parse_body<T: DeserializeOwned>(body: &[u8]) -> Result<T, Error> {
+    serde_json::from_slice(body).map_err(|e| Error::InvalidRequest(e.to_string()))
+}
+
+fn parse_id(id: &str) -> Result<Uuid, Error> {
+    Uuid::parse_str(id).map_err(|e| Error::InvalidRequest(e.to_string()))
+}
+
+// The named parameter from a URL query string, if it's there.
+fn parse_query_param<T: std::str::FromStr>(
+    query: Option<&str>,
+    name: &str,
+) -> Result<Option<T>, Error> {
+    let query = query.unwrap_or("");
+    for (key, value) in url::form_urlencoded::parse(query.as_bytes()) {
+        if key == name {
+            return value.parse().map(Some).map_err(|_| {
+                Error::InvalidRequest(format!("{} must be a whole number, got {}", name, value))
+            });
+        }
+    }
+    Ok(None)
+}
+
+// Who signed the request, who needs a key with at least the given scope.
+fn authenticate(
+    head: &Request<()>,
+    body: &[u8],
+    scope: accounts::Scope,
+) -> Result<auth::Caller, Error> {
+    let accounts = ACCOUNTS.read().unwrap();
+    let caller =
+        AUTH.lock()
+            .unwrap()
+            .authenticate(&accounts, head, body, order_book::now_millis())?;
+    caller.require(scope)?;
+    Ok(caller)
+}
+
+// The account an /accounts/{id} path is for, which the caller has to be allowed to see.
+fn path_account(caller: &auth::Caller, id: &str) -> Result<Uuid, Error> {
+    let account = caller.for_account(parse_id(id)?)?;
+    if ACCOUNTS.read().unwrap().get(account).is_none() {
+        return Err(Error::NoSuchAccount);
+    }
+    Ok(account)
+}
+
+// The result of handling a request signed with a key which has at least the given scope.
+fn authorized<T, F>(
+    status: StatusCode,
+    head: &Request<()>,
+    body: &[u8],
+    scope: accounts::Scope,
+    handle: F,
+) -> ResponseFuture
+where
+    T: serde::Serialize,
+    F: FnOnce(auth::Caller) -> Result<T, Error>,
+{
+    result_response(status, authenticate(head, body, scope).and_then(handle))
+}
+
+// Finds which book one of the account's resting orders is on, searching every symbol if
+// none is given. Returns the books for its symbol along with the symbol and side it is on.
+// Other accounts' orders aren't found.
+fn locate_order(
+    registry: &instruments::Registry,
+    account: Uuid,
+    id: Uuid,
+    symbol: Option<order_book::Symbol>,
+) -> Result<(&instruments::Books, order_book::Symbol, order_book::Side), Error> {
+    let books: Vec<(order_book::Symbol, &instruments::Books)> = match symbol {
+        Some(symbol) => vec![(symbol, registry.books(symbol)?)],
+        None => registry.all_books(),
+    };
+    let owned = |order: Option<order_book::OpenLimitOrder>| {
+        order.is_some_and(|order| order.owner == account)
+    };
+    for (symbol, books) in books {
+        if owned(books.buy.read().unwrap().get_order(id)) {
+            return Ok((books, symbol, order_book::Side::Buy));
+        }
+        if owned(books.sell.read().unwrap().get_order(id)) {
+            return Ok((books, symbol, order_book::Side::Sell));
+        }
+    }
+    Err(Error::NoSuchOrder)
+}
+
+// Nothing is acknowledged until it's in the journal. If it can't be written, carrying on
+// would acknowledge changes that wouldn't survive a restart.
+fn write_journal(record: journal::Record) {
+    let mut journal = JOURNAL.lock().unwrap();
+    if let Err(e) = journal.append(&record) {
+        error!("unable to write {:?} to the journal: {}", record, e);
+        std::process::abort();
+    }
+    // Queued with the journal still locked so the database gets records in the same order.
+    if let Some(database) = DATABASE.as_ref() {
+        database.write(journal.records(), record);
+    }
+}
+
+// Tells feed subscribers about a change to a symbol's books.
+// Called with both books still locked so changes are published in the order they happened.
+fn publish(
+    symbol: order_book::Symbol,
+    buy_book: &order_book::OrderBook,
+    sell_book: &order_book::OrderBook,
+    events: &[order_book::OrderEvent],
+    trades: &[order_book::Trade],
+) {
+    let mut feed = FEED.lock().unwrap();
+    if let Err(e) = feed.publish(symbol, buy_book, sell_book, events, trades) {
+        warn!("unable to publish {} book change: {}", symbol, e);
+    }
+}
+
+// Keeps balances in step with a change to a symbol's books. The change is already
+// journaled, so this can't fail without a bug, which it logs rather than lose the change.
+// Replaying the journal does the same, and a failed settlement changes nothing, so
+// restarting comes back to the same balances.
+fn settle(
+    symbol: order_book::Symbol,
+    owner: Uuid,
+    events: &[order_book::OrderEvent],
+    trades: &[order_book::Trade],
+    fees: &[fees::Fee],
+    release: Option<balances::Hold>,
+) {
+    let mut accounts = ACCOUNTS.write().unwrap();
+    let ledger = accounts.ledger_mut();
+    // Whatever was reserved up front is released in the same go, what's left resting holds
+    // its own share once settled.
+    let released = match release {
+        Some(hold) => ledger.release(owner, hold),
+        None => Ok(()),
+    };
+    if let Err(e) = released.and_then(|_| ledger.settle(symbol, owner, events, trades, fees)) {
+        error!(
+            "unable to settle {} book change for {}: {}",
+            symbol, owner, e
         );
-        sell
+    }
+}
+
+// Matches an incoming order for the account and leaves whatever is left of a GTC order on
+// the book. Everything it could need, taker fee included, is reserved from the account's
+// balances first.
+fn submit_order(
+    account: Uuid,
+    order_request: order_book::OrderRequest,
+) -> Result<fees::FilledOrder, Error> {
+    let registry = INSTRUMENTS.read().unwrap();
+    let books = registry.tradable_books(order_request.symbol)?;
+    let instrument = registry.get(order_request.symbol).unwrap();
+    let order_request = instrument
+        .validate_order(order_request)
+        .map_err(|rejection| {
+            info!("rejected order {:?}: {:?}", order_request, rejection);
+            rejection
+        })?;
+    let mut order = order_request.to_order(Uuid::new_v4(), account)?;
+    // Nothing can be bought above the top of the price band, and without one or a
+    // protection price there's no telling what a market buy needs reserved.
+    if order_request.order_type == order_book::OrderType::Market
+        && order_request.protection_price.is_none()
+        && order.side == order_book::Side::Buy
+    {
+        order.price = instrument.max_price.ok_or(Error::InvalidOrder(
+            "market buys need a protection_price to reserve funds for",
+        ))?;
+    }
+    let hold = {
+        let mut accounts = ACCOUNTS.write().unwrap();
+        let ledger = accounts.ledger_mut();
+        let hold = balances::Hold::for_order(&order)?;
+        let hold = ledger.with_taker_fee(&FEES, account, hold)?;
+        ledger.reserve(account, hold).map_err(|e| {
+            info!("rejected order {:?}: {}", order_request, e);
+            e
+        })?;
+        hold
+    };
+    let (mut buy_book, mut sell_book) = books.lock();
+    let (book, opposing_book) = if order.side == order_book::Side::Buy {
+        (&mut buy_book, &mut sell_book)
+    } else {
+        (&mut sell_book, &mut buy_book)
     };
+
+    // Match as much of the order as we can right away.
+    // Whatever is left over of a GTC order is left on the book,
+    // anything else has already been cancelled.
+    // Probably want to publish the matched order somewhere.
+    // A fill that fails leaves the book as it was.
+    let fr = match opposing_book.fill_order(order, order_request.effective_time_in_force()) {
+        Ok(fr) => fr,
+        Err(e) => {
+            ACCOUNTS
+                .write()
+                .unwrap()
+                .ledger_mut()
+                .release(account, hold)?;
+            return Err(e);
+        }
+    };
+    if fr.remaining.is_positive() {
+        info!("{} left unfilled, leaving on the book", fr.remaining);
+        let mut rest = order;
+        rest.amount = fr.remaining;
+        // The trades have already happened so this can't be allowed to fail, and it can't:
+        // it's for this book's side, has some left and its id is new.
+        book.add_order(rest)
+            .expect("the rest of a new order couldn't be left on the book");
+    }
+    // Executions against the other side happened first.
+    let mut events = opposing_book.take_events();
+    events.extend(book.take_events());
+    // Worked out while the makers' orders are still known, the book has already changed so
+    // anything wrong is logged and the trades go free.
+    let charged = ACCOUNTS
+        .read()
+        .unwrap()
+        .ledger()
+        .fees(&FEES, account, &fr.trades)
+        .unwrap_or_else(|e| {
+            error!("unable to work out the fees for {}: {}", order.id, e);
+            Vec::new()
+        });
+    write_journal(journal::Record::Order {
+        id: order.id,
+        account,
+        request: order_request,
+        events: events.clone(),
+        trades: fr.trades.clone(),
+        fees: charged.clone(),
+    });
+    ACCOUNTS.write().unwrap().record_order(account, order.id);
+    settle(
+        order.symbol,
+        account,
+        &events,
+        &fr.trades,
+        &charged,
+        Some(hold),
+    );
+    let mut trades = TRADES.write().unwrap();
+    for &trade in fr.trades.iter() {
+        trades.record(trade);
+    }
+    publish(order.symbol, &buy_book, &sell_book, &events, &fr.trades);
+    Ok(fees::FilledOrder {
+        fill: fr,
+        fees: charged
+            .into_iter()
+            .filter(|fee| fee.order == order.id)
+            .collect(),
+    })
 }
 
-type GenericError = Box<dyn std::error::Error + Send + Sync>;
-type ResponseFuture = Box<dyn Future<Item = Response<Body>, Error = GenericError> + Send>;
+// Pulls one of the account's resting orders off whichever book it is on.
+fn cancel_order(
+    account: Uuid,
+    id: Uuid,
+    symbol: Option<order_book::Symbol>,
+) -> Result<order_book::OpenLimitOrder, Error> {
+    let registry = INSTRUMENTS.read().unwrap();
+    let (books, symbol, side) = locate_order(&registry, account, id, symbol)?;
+    // Hold both sides so the order can't be matched while we cancel it.
+    let (mut buy_book, mut sell_book) = books.lock();
+    let book = if side == order_book::Side::Buy {
+        &mut buy_book
+    } else {
+        &mut sell_book
+    };
+    // Could have been filled since we found it
+    let order = book.remove_order(id)?;
+    info!("cancelled order {:?}", order);
+    let events = book.take_events();
+    write_journal(journal::Record::Cancel {
+        id,
+        symbol,
+        events: events.clone(),
+    });
+    settle(symbol, account, &events, &[], &[], None);
+    publish(symbol, &buy_book, &sell_book, &events, &[]);
+    Ok(order)
+}
 
-pub fn router(req: Request<Body>, _client: &Client<HttpConnector>) -> ResponseFuture {
-    match (req.method(), req.uri().path()) {
-        (&Method::POST, "/order") => {
-            Box::new(req.into_body().concat2().from_err().and_then(|whole_body| {
-                let str_body = String::from_utf8(whole_body.to_vec()).unwrap();
-                info!("order requested {:?}", str_body);
-                let order_request: Result<order_book::OpenLimitOrder> =
-                    serde_json::from_str(&str_body);
-
-                match order_request {
-                    Ok(order_request) => {
-                        let mut book: RwLockWriteGuard<order_book::OrderBook>;
-                        let mut opposing_book: RwLockWriteGuard<order_book::OrderBook>;
-                        if order_request.side == order_book::Side::Buy {
-                            book = BUY.get(&order_request.symbol).unwrap().write().unwrap();
-                            opposing_book =
-                                SELL.get(&order_request.symbol).unwrap().write().unwrap();
-                        } else {
-                            book = SELL.get(&order_request.symbol).unwrap().write().unwrap();
-                            opposing_book =
-                                BUY.get(&order_request.symbol).unwrap().write().unwrap();
-                        }
+// Amends one of the account's resting orders in place.
+// A new price that would match against the other side is rejected, cancel and
+// send a new order instead. Anything more it needs reserved has to be available.
+fn amend_order(
+    account: Uuid,
+    id: Uuid,
+    amend: order_book::AmendRequest,
+) -> Result<order_book::OpenLimitOrder, Error> {
+    let registry = INSTRUMENTS.read().unwrap();
+    let (books, symbol, side) = locate_order(&registry, account, id, None)?;
+    registry.tradable_books(symbol)?;
+    let instrument = registry.get(symbol).unwrap();
+    let amend = instrument.validate_amend(amend).map_err(|rejection| {
+        info!("rejected amend of {}: {:?}", id, rejection);
+        rejection
+    })?;
+    let (mut buy_book, mut sell_book) = books.lock();
+    let (book, opposing_book) = if side == order_book::Side::Buy {
+        (&mut buy_book, &mut sell_book)
+    } else {
+        (&mut sell_book, &mut buy_book)
+    };
+    // Could have been filled since we found it
+    let old = book.get_order(id).ok_or(Error::NoSuchOrder)?;
+    let price = amend.price.unwrap_or(old.price);
+    let amount = amend.amount.unwrap_or(old.amount);
+    instrument.validate_notional(price, amount)?;
+    if amend.price.is_some() && opposing_book.crosses(price) {
+        return Err(Error::AmendWouldCross);
+    }
+    let held = balances::Hold::new(symbol, side, old.price, old.amount)?;
+    let mut extra = balances::Hold::new(symbol, side, price, amount)?;
+    extra.amount = extra
+        .amount
+        .checked_sub(held.amount)
+        .ok_or(Error::Overflow)?
+        .max(Decimal::ZERO);
+    ACCOUNTS
+        .write()
+        .unwrap()
+        .ledger_mut()
+        .reserve(account, extra)?;
+    let order = match book.amend_order(id, amend) {
+        Ok(order) => order,
+        Err(e) => {
+            ACCOUNTS
+                .write()
+                .unwrap()
+                .ledger_mut()
+                .release(account, extra)?;
+            return Err(e);
+        }
+    };
+    info!("amended order {:?}", order);
+    let events = book.take_events();
+    write_journal(journal::Record::Amend {
+        id,
+        symbol,
+        amend,
+        events: events.clone(),
+    });
+    settle(symbol, account, &events, &[], &[], Some(extra));
+    publish(symbol, &buy_book, &sell_book, &events, &[]);
+    Ok(order)
+}
 
-                        // If we can instantly match the order, then lets match it.
-                        // If not, just leave it on the order book.
-                        // Probably want to publish the matched order somewhere.
-                        match opposing_book.fill_order(order_request) {
-                            Ok(fr) => Box::new(future::ok(
-                                Response::builder()
-                                    .status(StatusCode::OK)
-                                    .body(Body::from(serde_json::to_string(&fr).unwrap()))
-                                    .unwrap(),
-                            )),
-                            Err(e) => {
-                                info!("unable to fill order, leaving on the book");
-                                match e {
-                                    order_book::ERR_CANT_FILL_PRICE
-                                    | order_book::ERR_CANT_FILL_SIZE => {
-                                        match book.add_order(order_request) {
-                                            Ok(order) => Box::new(future::ok(
-                                                Response::builder()
-                                                    .status(StatusCode::OK)
-                                                    .body(Body::from(
-                                                        serde_json::to_string(&order).unwrap(),
-                                                    ))
-                                                    .unwrap(),
-                                            )),
-                                            Err(order) => Box::new(future::ok(
-                                                Response::builder()
-                                                    .status(StatusCode::INTERNAL_SERVER_ERROR)
-                                                    .body(Body::from(
-                                                        serde_json::to_string(&order).unwrap(),
-                                                    ))
-                                                    .unwrap(),
-                                            )),
-                                        }
-                                    }
-                                    e => Box::new(future::ok(
-                                        Response::builder()
-                                            .status(StatusCode::INTERNAL_SERVER_ERROR)
-                                            .body(Body::from(serde_json::to_string(e).unwrap()))
-                                            .unwrap(),
-                                    )),
-                                }
-                            }
-                        }
-                    }
-                    Err(order_request) => Box::new(future::ok(
-                        Response::builder()
-                            .status(StatusCode::BAD_REQUEST)
-                            .body(Body::empty())
-                            .unwrap(),
-                    )),
+// Any of the account's resting orders, whichever book it is on.
+fn get_order(account: Uuid, id: Uuid) -> Result<order_book::OpenLimitOrder, Error> {
+    for (_, books) in INSTRUMENTS.read().unwrap().all_books() {
+        for book in [&books.buy, &books.sell].iter() {
+            if let Some(order) = book.read().unwrap().get_order(id) {
+                if order.owner == account {
+                    return Ok(order);
                 }
-            }))
-        }
-        (&Method::GET, "/sells") => {
-            let payload = String::new();
-            let mut to_serialize: HashMap<
-                order_book::Symbol,
-                Vec<VecDeque<order_book::OpenLimitOrder>>,
-            > = HashMap::new();
-            for (symbol, book) in SELL.iter() {
-                to_serialize.insert(*symbol, book.read().unwrap().get_book());
             }
-            Box::new(future::ok(
-                Response::builder()
-                    .status(StatusCode::OK)
-                    .body(Body::from(serde_json::to_string(&to_serialize).unwrap()))
-                    .unwrap(),
-            ))
         }
-        (&Method::GET, "/buys") => {
-            let payload = String::new();
-            let mut to_serialize: HashMap<
-                order_book::Symbol,
-                Vec<VecDeque<order_book::OpenLimitOrder>>,
-            > = HashMap::new();
-            for (symbol, book) in BUY.iter() {
-                to_serialize.insert(*symbol, book.read().unwrap().get_book());
+    }
+    Err(Error::NoSuchOrder)
+}
+
+// Every resting order on one side of each book, without who they belong to.
+fn book_side(side: order_book::Side) -> ResponseFuture {
+    let registry = INSTRUMENTS.read().unwrap();
+    let mut to_serialize: HashMap<order_book::Symbol, Vec<VecDeque<order_book::PublicOrder>>> =
+        HashMap::new();
+    for (symbol, books) in registry.all_books() {
+        let book = if side == order_book::Side::Buy {
+            &books.buy
+        } else {
+            &books.sell
+        };
+        let levels = book
+            .read()
+            .unwrap()
+            .get_book()
+            .into_iter()
+            .map(|level| {
+                level
+                    .into_iter()
+                    .map(order_book::PublicOrder::from)
+                    .collect()
+            })
+            .collect();
+        to_serialize.insert(symbol, levels);
+    }
+    json_response(StatusCode::OK, &to_serialize)
+}
+
+// Every order the account has resting, by symbol and then as they are on the books.
+fn open_orders(account: Uuid) -> Vec<order_book::OpenLimitOrder> {
+    let registry = INSTRUMENTS.read().unwrap();
+    let mut all_books = registry.all_books();
+    all_books.sort_by_key(|&(symbol, _)| symbol);
+    let mut orders = Vec::new();
+    for (_, books) in all_books {
+        let (buy_book, sell_book) = books.read();
+        for book in [&buy_book, &sell_book].iter() {
+            orders.extend(
+                book.get_book()
+                    .into_iter()
+                    .flatten()
+                    .filter(|order| order.owner == account),
+            );
+        }
+    }
+    orders
+}
+
+// Every fill of any order the account has placed, oldest first.
+fn get_fills(account: Uuid) -> Vec<trade_log::Fill> {
+    let accounts = ACCOUNTS.read().unwrap();
+    TRADES.read().unwrap().fills(accounts.orders(account))
+}
+
+// The account's position in every instrument it has traded, by symbol, each marked to its
+// book's mid as it is now.
+fn get_positions(account: Uuid) -> Result<Vec<positions::MarkedPosition>, Error> {
+    let registry = INSTRUMENTS.read().unwrap();
+    // Not held while the books are locked, they come first.
+    let positions = ACCOUNTS.read().unwrap().ledger().positions(account);
+    positions
+        .into_iter()
+        .map(|(symbol, position)| {
+            let mid = match registry.books(symbol) {
+                Ok(books) => {
+                    let (buy_book, sell_book) = books.read();
+                    order_book::Depth::new(symbol, &buy_book, &sell_book, 0)?.mid
+                }
+                // Delisted.
+                Err(_) => None,
+            };
+            positions::MarkedPosition::new(symbol, position, mid)
+        })
+        .collect()
+}
+
+fn open_account(request: accounts::AccountRequest) -> Result<accounts::Account, Error> {
+    let mut accounts = ACCOUNTS.write().unwrap();
+    let account = accounts.open(request, order_book::now_millis())?;
+    write_journal(journal::Record::OpenAccount {
+        account: account.clone(),
+    });
+    Ok(account)
+}
+
+// Makes a key for the account, this is the only time its secret is given out. Nobody can
+// make a key that can do more than the one they're using.
+fn add_key(
+    caller: &auth::Caller,
+    account: Uuid,
+    request: accounts::KeyRequest,
+) -> Result<accounts::ApiKey, Error> {
+    caller.require(request.scope)?;
+    let mut accounts = ACCOUNTS.write().unwrap();
+    let key = accounts.add_key(account, request.scope, order_book::now_millis())?;
+    info!("added {:?} key {} for {}", key.scope, key.id, account);
+    write_journal(journal::Record::AddKey { key: key.clone() });
+    Ok(key)
+}
+
+// Moves cash or an instrument into the account, or out of it for a withdrawal.
+fn transfer(
+    account: Uuid,
+    request: balances::TransferRequest,
+    withdrawal: bool,
+) -> Result<balances::Balances, Error> {
+    let instrument = match request.symbol {
+        Some(symbol) => Some(
+            INSTRUMENTS
+                .read()
+                .unwrap()
+                .get(symbol)
+                .ok_or(Error::UnknownSymbol)?,
+        ),
+        None => None,
+    };
+    let mut transfer = request.validate(instrument.as_ref())?;
+    if withdrawal {
+        transfer.amount = -transfer.amount;
+    }
+    let mut accounts = ACCOUNTS.write().unwrap();
+    // Only journaled once it's known to apply.
+    accounts.ledger().check_transfer(account, transfer)?;
+    write_journal(journal::Record::Transfer { account, transfer });
+    info!("transferred {:?} for {}", transfer, account);
+    accounts.ledger_mut().transfer(account, transfer)
+}
+
+fn revoke_key(account: Uuid, id: &str) -> Result<accounts::KeyInfo, Error> {
+    let id = parse_id(id)?;
+    let mut accounts = ACCOUNTS.write().unwrap();
+    let key = accounts.revoke_key(account, id)?;
+    info!("revoked key {} of {}", id, account);
+    write_journal(journal::Record::RevokeKey { account, id });
+    Ok(key)
+}
+
+// Price levels shown on each side of /depth when the request doesn't say.
+const DEFAULT_DEPTH_LEVELS: usize = 10;
+
+// Aggregated price levels for both sides of one instrument's book.
+// The levels query parameter limits how many are returned from each side.
+fn get_depth(symbol: &str, query: Option<&str>) -> Result<order_book::Depth, Error> {
+    let symbol = order_book::Symbol::new(symbol).map_err(|_| Error::UnknownSymbol)?;
+    let levels = parse_query_param(query, "levels")?.unwrap_or(DEFAULT_DEPTH_LEVELS);
+    let registry = INSTRUMENTS.read().unwrap();
+    let books = registry.books(symbol)?;
+    // Both sides at once so the snapshot is consistent.
+    let (buy_book, sell_book) = books.read();
+    order_book::Depth::new(symbol, &buy_book, &sell_book, levels)
+}
+
+// Every order on an instrument's books, for rebuilding it from the order events after it.
+fn get_orders_snapshot(symbol: &str) -> Result<feed::OrdersSnapshot, Error> {
+    let symbol = order_book::Symbol::new(symbol).map_err(|_| Error::UnknownSymbol)?;
+    let registry = INSTRUMENTS.read().unwrap();
+    let (buy_book, sell_book) = registry.books(symbol)?.read();
+    let feed = FEED.lock().unwrap();
+    Ok(feed.orders_snapshot(symbol, &buy_book, &sell_book))
+}
+
+// An instrument's order events after the since query parameter, for catching up after
+// missing some on the feed.
+fn get_order_events(symbol: &str, query: Option<&str>) -> Result<Vec<feed::SequencedEvent>, Error> {
+    let symbol = order_book::Symbol::new(symbol).map_err(|_| Error::UnknownSymbol)?;
+    let since = parse_query_param(query, "since")?.unwrap_or(0);
+    let registry = INSTRUMENTS.read().unwrap();
+    registry.books(symbol)?;
+    FEED.lock().unwrap().replay(symbol, since)
+}
+
+fn get_ticker(symbol: &str) -> Result<ticker::Ticker, Error> {
+    let symbol = order_book::Symbol::new(symbol).map_err(|_| Error::UnknownSymbol)?;
+    let registry = INSTRUMENTS.read().unwrap();
+    let (buy_book, sell_book) = registry.books(symbol)?.read();
+    let trades = TRADES.read().unwrap();
+    ticker::Ticker::new(
+        symbol,
+        &buy_book,
+        &sell_book,
+        &trades,
+        order_book::now_millis(),
+    )
+}
+
+// A ticker for every instrument which hasn't been delisted, sorted by symbol.
+fn get_tickers() -> Result<Vec<ticker::Ticker>, Error> {
+    let registry = INSTRUMENTS.read().unwrap();
+    let now = order_book::now_millis();
+    let mut all_books = registry.all_books();
+    all_books.sort_by_key(|&(symbol, _)| symbol);
+    all_books
+        .into_iter()
+        .map(|(symbol, books)| {
+            let (buy_book, sell_book) = books.read();
+            let trades = TRADES.read().unwrap();
+            ticker::Ticker::new(symbol, &buy_book, &sell_book, &trades, now)
+        })
+        .collect()
+}
+
+fn get_instrument(symbol: &str) -> Result<instruments::Instrument, Error> {
+    // Nothing can be listed under a symbol that isn't valid.
+    let symbol = order_book::Symbol::new(symbol).map_err(|_| Error::UnknownSymbol)?;
+    INSTRUMENTS
+        .read()
+        .unwrap()
+        .get(symbol)
+        .ok_or(Error::UnknownSymbol)
+}
+
+fn add_instrument(instrument: instruments::Instrument) -> Result<instruments::Instrument, Error> {
+    let mut instrument = instrument;
+    instrument.status = instruments::InstrumentStatus::Active;
+    let mut registry = INSTRUMENTS.write().unwrap();
+    let instrument = registry.add(instrument)?;
+    write_journal(journal::Record::AddInstrument { instrument });
+    Ok(instrument)
+}
+
+fn set_instrument_status(
+    symbol: &str,
+    status: instruments::InstrumentStatus,
+) -> Result<instruments::Instrument, Error> {
+    let symbol = order_book::Symbol::new(symbol).map_err(|_| Error::UnknownSymbol)?;
+    let mut registry = INSTRUMENTS.write().unwrap();
+    let instrument = registry.set_status(symbol, status)?;
+    write_journal(journal::Record::SetStatus { symbol, status });
+    if status == instruments::InstrumentStatus::Delisted {
+        FEED.lock().unwrap().delist(symbol);
+        // Already journaled, like settling.
+        if let Err(e) = ACCOUNTS.write().unwrap().ledger_mut().delist(symbol) {
+            error!("unable to release what's held on {}: {}", symbol, e);
+        }
+    }
+    Ok(instrument)
+}
+
+// The history database, if one was configured.
+fn database() -> Result<&'static db::Database, Error> {
+    DATABASE.as_ref().ok_or(Error::HistoryUnavailable)
+}
+
+// One of the account's orders as the database has it.
+fn historical_order(account: Uuid, id: &str) -> Result<db::HistoricalOrder, Error> {
+    let order = database()?.order(parse_id(id)?)?;
+    if order.account != Some(account) {
+        return Err(Error::NoSuchOrder);
+    }
+    Ok(order)
+}
+
+// Filters for an account's order history and /history/trades.
+fn history_query(account: Option<Uuid>, query: Option<&str>) -> Result<db::HistoryQuery, Error> {
+    let symbol = match parse_query_param::<String>(query, "symbol")? {
+        Some(symbol) => Some(order_book::Symbol::new(&symbol)?),
+        None => None,
+    };
+    let order = match parse_query_param::<String>(query, "order")? {
+        Some(id) => Some(parse_id(&id)?),
+        None => None,
+    };
+    let limit = parse_query_param(query, "limit")?.unwrap_or(db::DEFAULT_HISTORY_LIMIT);
+    if limit > db::MAX_HISTORY_LIMIT {
+        return Err(Error::InvalidRequest(format!(
+            "limit can be at most {}",
+            db::MAX_HISTORY_LIMIT
+        )));
+    }
+    Ok(db::HistoryQuery {
+        account,
+        symbol,
+        order,
+        before: parse_query_param(query, "before")?,
+        limit,
+    })
+}
+
+// Snapshots every book, replacing the last snapshot.
+fn save_snapshot() -> Result<snapshot::SnapshotSummary, Error> {
+    let _saving = SNAPSHOTS.lock().unwrap();
+    let snapshot = {
+        // With the registry locked for writing there can't be any orders, cancels or amends
+        // part way through.
+        let registry = INSTRUMENTS.write().unwrap();
+        let accounts = ACCOUNTS.read().unwrap();
+        let journal = JOURNAL.lock().unwrap();
+        let trades = TRADES.read().unwrap();
+        let feed = FEED.lock().unwrap();
+        snapshot::Snapshot::take(&registry, &accounts, journal.records(), &trades, &feed)
+    };
+    let path = snapshot_path();
+    snapshot.save(&path).map_err(Error::SnapshotFailed)?;
+    let summary = snapshot.summary();
+    info!("saved {:?} to {}", summary, path);
+    Ok(summary)
+}
+
+fn feed_request(client: feed::ClientId, text: &str) -> Result<(), Error> {
+    let registry = INSTRUMENTS.read().unwrap();
+    match parse_body(text.as_bytes())? {
+        feed::FeedRequest::Subscribe { symbol, channels } => {
+            // Hold both sides so nothing can change between the snapshot and the updates after it.
+            let (buy_book, sell_book) = registry.books(symbol)?.read();
+            let mut feed = FEED.lock().unwrap();
+            for channel in channels {
+                feed.subscribe(client, symbol, channel, &buy_book, &sell_book)?;
             }
-            Box::new(future::ok(
-                Response::builder()
-                    .status(StatusCode::OK)
-                    .body(Body::from(serde_json::to_string(&to_serialize).unwrap()))
-                    .unwrap(),
+        }
+        feed::FeedRequest::Unsubscribe { symbol, channels } => {
+            let mut feed = FEED.lock().unwrap();
+            for channel in channels {
+                feed.unsubscribe(client, symbol, channel);
+            }
+        }
+    }
+    Ok(())
+}
+
+// Runs a market data feed connection until the client closes it.
+fn serve_feed(upgraded: hyper::upgrade::Upgraded) {
+    let (sink, stream) = MessageCodec::default(Context::Server)
+        .framed(upgraded)
+        .split();
+    let (sender, receiver) = mpsc::unbounded();
+    let client = FEED.lock().unwrap().connect(sender.clone());
+    info!("feed client {} connected", client);
+
+    // Everything for the client goes through the channel, so this finishes once the
+    // connection below is done with it and the feed has forgotten the client.
+    hyper::rt::spawn(
+        receiver
+            .forward(sink.sink_map_err(|e| warn!("feed write failed: {}", e)))
+            .map(|_| ()),
+    );
+    hyper::rt::spawn(
+        stream
+            .map_err(|e| warn!("feed read failed: {}", e))
+            .take_while(|message| Ok(!message.is_close()))
+            .for_each(move |message| {
+                match message {
+                    OwnedMessage::Text(text) => {
+                        if let Err(e) = feed_request(client, &text) {
+                            FEED.lock().unwrap().send_error(client, &e);
+                        }
+                    }
+                    OwnedMessage::Ping(data) => {
+                        let _ = sender.unbounded_send(OwnedMessage::Pong(data));
+                    }
+                    _ => {}
+                }
+                Ok(())
+            })
+            .then(move |_| {
+                FEED.lock().unwrap().disconnect(client);
+                info!("feed client {} disconnected", client);
+                Ok(())
+            }),
+    );
+}
+
+// Upgrades a GET /feed request to a WebSocket connection serving market data.
+fn feed_upgrade(req: Request<Body>) -> ResponseFuture {
+    let is_websocket = req
+        .headers()
+        .get(UPGRADE)
+        .and_then(|upgrade| upgrade.to_str().ok())
+        .is_some_and(|upgrade| upgrade.eq_ignore_ascii_case("websocket"));
+    let key = req
+        .headers()
+        .get("Sec-WebSocket-Key")
+        .and_then(|key| key.to_str().ok())
+        .and_then(|key| key.parse::<WebSocketKey>().ok());
+    let key = match key {
+        Some(key) if is_websocket => key,
+        _ => {
+            return error_response(Error::InvalidRequest(
+                "expected a websocket upgrade".to_string(),
             ))
         }
-        _ => Box::new(future::ok(
-            Response::builder()
-                .status(StatusCode::METHOD_NOT_ALLOWED)
-                .body(Body::empty())
-                .unwrap(),
-        )),
+    };
+    hyper::rt::spawn(
+        req.into_body()
+            .on_upgrade()
+            .map(serve_feed)
+            .map_err(|e| warn!("feed upgrade failed: {}", e)),
+    );
+    Box::new(future::ok(
+        Response::builder()
+            .status(StatusCode::SWITCHING_PROTOCOLS)
+            .header(UPGRADE, "websocket")
+            .header(CONNECTION, "Upgrade")
+            .header(
+                "Sec-WebSocket-Accept",
+                WebSocketAccept::new(&key).serialize(),
+            )
+            .body(Body::empty())
+            .unwrap(),
+    ))
+}
+
+pub fn router(req: Request<Body>, _client: &Client<HttpConnector>) -> ResponseFuture {
+    if req.method() == Method::GET && req.uri().path() == "/feed" {
+        return feed_upgrade(req);
+    }
+    // Signatures cover the body, so it's needed before anything can be authenticated.
+    let (parts, body) = req.into_parts();
+    Box::new(
+        body.concat2()
+            .from_err()
+            .and_then(move |whole_body| route(&Request::from_parts(parts, ()), &whole_body)),
+    )
+}
+
+// Anything which touches an account's orders or keys, or changes the exchange, is
+// authenticated before doing anything else. Market data is public.
+fn route(req: &Request<()>, body: &[u8]) -> ResponseFuture {
+    use crate::accounts::Scope;
+    match (req.method(), req.uri().path()) {
+        (&Method::POST, "/order") => {
+            info!("order requested {:?}", String::from_utf8_lossy(body));
+            authorized(StatusCode::OK, req, body, Scope::Trade, |caller| {
+                submit_order(caller.account()?, parse_body(body)?)
+            })
+        }
+        (&Method::DELETE, "/order") => {
+            info!("cancel requested {:?}", String::from_utf8_lossy(body));
+            authorized(StatusCode::OK, req, body, Scope::Trade, |caller| {
+                let cancel_request: order_book::CancelRequest = parse_body(body)?;
+                cancel_order(caller.account()?, cancel_request.id, cancel_request.symbol)
+            })
+        }
+        (&Method::DELETE, path) if path.starts_with("/order/") => {
+            authorized(StatusCode::OK, req, body, Scope::Trade, |caller| {
+                cancel_order(caller.account()?, parse_id(&path["/order/".len()..])?, None)
+            })
+        }
+        (&Method::GET, path) if path.starts_with("/order/") => {
+            authorized(StatusCode::OK, req, body, Scope::ReadOnly, |caller| {
+                get_order(caller.account()?, parse_id(&path["/order/".len()..])?)
+            })
+        }
+        (&Method::PATCH, path) if path.starts_with("/order/") => {
+            info!("amend requested {:?}", String::from_utf8_lossy(body));
+            authorized(StatusCode::OK, req, body, Scope::Trade, |caller| {
+                let id = parse_id(&path["/order/".len()..])?;
+                amend_order(caller.account()?, id, parse_body(body)?)
+            })
+        }
+        (&Method::GET, "/trades") => {
+            json_response(StatusCode::OK, &TRADES.read().unwrap().get_trades())
+        }
+        (&Method::GET, path) if path.starts_with("/trades/") => {
+            let result =
+                parse_id(&path["/trades/".len()..]).map(|id| TRADES.read().unwrap().for_order(id));
+            result_response(StatusCode::OK, result)
+        }
+        (&Method::GET, "/sells") => book_side(order_book::Side::Sell),
+        (&Method::GET, "/buys") => book_side(order_book::Side::Buy),
+        (&Method::GET, path) if path.starts_with("/depth/") => result_response(
+            StatusCode::OK,
+            get_depth(&path["/depth/".len()..], req.uri().query()),
+        ),
+        (&Method::GET, path) if path.starts_with("/mbo/") && path.ends_with("/events") => {
+            let symbol = &path["/mbo/".len()..path.len() - "/events".len()];
+            result_response(StatusCode::OK, get_order_events(symbol, req.uri().query()))
+        }
+        (&Method::GET, path) if path.starts_with("/mbo/") => {
+            result_response(StatusCode::OK, get_orders_snapshot(&path["/mbo/".len()..]))
+        }
+        (&Method::GET, "/tickers") => result_response(StatusCode::OK, get_tickers()),
+        (&Method::GET, path) if path.starts_with("/ticker/") => {
+            result_response(StatusCode::OK, get_ticker(&path["/ticker/".len()..]))
+        }
+        (&Method::POST, "/snapshot") => {
+            authorized(StatusCode::OK, req, body, Scope::Admin, |_| save_snapshot())
+        }
+        (&Method::GET, path)
+            if path.starts_with("/history/orders/") && path.ends_with("/transitions") =>
+        {
+            let id = &path["/history/orders/".len()..path.len() - "/transitions".len()];
+            authorized(StatusCode::OK, req, body, Scope::ReadOnly, |caller| {
+                let order = historical_order(caller.account()?, id)?;
+                database()?.transitions(order.id)
+            })
+        }
+        (&Method::GET, path) if path.starts_with("/history/orders/") => {
+            authorized(StatusCode::OK, req, body, Scope::ReadOnly, |caller| {
+                historical_order(caller.account()?, &path["/history/orders/".len()..])
+            })
+        }
+        (&Method::GET, "/history/trades") => {
+            let result =
+                history_query(None, req.uri().query()).and_then(|query| database()?.trades(&query));
+            result_response(StatusCode::OK, result)
+        }
+        (&Method::POST, "/accounts") => {
+            info!("account requested {:?}", String::from_utf8_lossy(body));
+            authorized(StatusCode::CREATED, req, body, Scope::Admin, |_| {
+                open_account(parse_body(body)?)
+            })
+        }
+        (&Method::GET, path) if path.starts_with("/accounts/") && path.ends_with("/orders") => {
+            let id = &path["/accounts/".len()..path.len() - "/orders".len()];
+            authorized(StatusCode::OK, req, body, Scope::ReadOnly, |caller| {
+                Ok(open_orders(path_account(&caller, id)?))
+            })
+        }
+        (&Method::GET, path) if path.starts_with("/accounts/") && path.ends_with("/fills") => {
+            let id = &path["/accounts/".len()..path.len() - "/fills".len()];
+            authorized(StatusCode::OK, req, body, Scope::ReadOnly, |caller| {
+                Ok(get_fills(path_account(&caller, id)?))
+            })
+        }
+        (&Method::GET, path) if path.starts_with("/accounts/") && path.ends_with("/history") => {
+            let id = &path["/accounts/".len()..path.len() - "/history".len()];
+            authorized(StatusCode::OK, req, body, Scope::ReadOnly, |caller| {
+                let account = path_account(&caller, id)?;
+                database()?.orders(&history_query(Some(account), req.uri().query())?)
+            })
+        }
+        (&Method::GET, path) if path.starts_with("/accounts/") && path.ends_with("/balances") => {
+            let id = &path["/accounts/".len()..path.len() - "/balances".len()];
+            authorized(StatusCode::OK, req, body, Scope::ReadOnly, |caller| {
+                let account = path_account(&caller, id)?;
+                Ok(ACCOUNTS.read().unwrap().ledger().balances(account))
+            })
+        }
+        (&Method::GET, path) if path.starts_with("/accounts/") && path.ends_with("/positions") => {
+            let id = &path["/accounts/".len()..path.len() - "/positions".len()];
+            authorized(StatusCode::OK, req, body, Scope::ReadOnly, |caller| {
+                get_positions(path_account(&caller, id)?)
+            })
+        }
+        (&Method::GET, path) if path.starts_with("/accounts/") && path.ends_with("/fees") => {
+            let id = &path["/accounts/".len()..path.len() - "/fees".len()];
+            authorized(StatusCode::OK, req, body, Scope::ReadOnly, |caller| {
+                let account = path_account(&caller, id)?;
+                let accounts = ACCOUNTS.read().unwrap();
+                let fee_ledger = accounts.ledger().fee_ledger();
+                fee_ledger.statement(account, order_book::now_millis())
+            })
+        }
+        (&Method::POST, path) if path.starts_with("/accounts/") && path.ends_with("/deposits") => {
+            let id = &path["/accounts/".len()..path.len() - "/deposits".len()];
+            info!("deposit requested {:?}", String::from_utf8_lossy(body));
+            authorized(StatusCode::OK, req, body, Scope::Admin, |caller| {
+                transfer(path_account(&caller, id)?, parse_body(body)?, false)
+            })
+        }
+        (&Method::POST, path)
+            if path.starts_with("/accounts/") && path.ends_with("/withdrawals") =>
+        {
+            let id = &path["/accounts/".len()..path.len() - "/withdrawals".len()];
+            info!("withdrawal requested {:?}", String::from_utf8_lossy(body));
+            authorized(StatusCode::OK, req, body, Scope::Admin, |caller| {
+                transfer(path_account(&caller, id)?, parse_body(body)?, true)
+            })
+        }
+        (&Method::POST, path) if path.starts_with("/accounts/") && path.ends_with("/keys") => {
+            let id = &path["/accounts/".len()..path.len() - "/keys".len()];
+            authorized(StatusCode::CREATED, req, body, Scope::Trade, |caller| {
+                add_key(&caller, path_account(&caller, id)?, parse_body(body)?)
+            })
+        }
+        (&Method::GET, path) if path.starts_with("/accounts/") && path.ends_with("/keys") => {
+            let id = &path["/accounts/".len()..path.len() - "/keys".len()];
+            authorized(StatusCode::OK, req, body, Scope::ReadOnly, |caller| {
+                let account = path_account(&caller, id)?;
+                let keys = ACCOUNTS.read().unwrap().keys(account);
+                Ok(keys.iter().map(accounts::ApiKey::info).collect::<Vec<_>>())
+            })
+        }
+        (&Method::DELETE, path) if path.starts_with("/accounts/") && path.contains("/keys/") => {
+            let (id, key) = path["/accounts/".len()..].split_once("/keys/").unwrap();
+            authorized(StatusCode::OK, req, body, Scope::Trade, |caller| {
+                revoke_key(path_account(&caller, id)?, key)
+            })
+        }
+        (&Method::GET, path) if path.starts_with("/accounts/") => {
+            authorized(StatusCode::OK, req, body, Scope::ReadOnly, |caller| {
+                let account = path_account(&caller, &path["/accounts/".len()..])?;
+                ACCOUNTS
+                    .read()
+                    .unwrap()
+                    .get(account)
+                    .ok_or(Error::NoSuchAccount)
+            })
+        }
+        (&Method::GET, "/instruments") => {
+            json_response(StatusCode::OK, &INSTRUMENTS.read().unwrap().list())
+        }
+        (&Method::GET, path) if path.starts_with("/instruments/") => result_response(
+            StatusCode::OK,
+            get_instrument(&path["/instruments/".len()..]),
+        ),
+        (&Method::POST, "/instruments") => {
+            info!("instrument requested {:?}", String::from_utf8_lossy(body));
+            authorized(StatusCode::CREATED, req, body, Scope::Admin, |_| {
+                add_instrument(parse_body(body)?)
+            })
+        }
+        (&Method::POST, path) if path.starts_with("/instruments/") && path.ends_with("/halt") => {
+            let symbol = &path["/instruments/".len()..path.len() - "/halt".len()];
+            authorized(StatusCode::OK, req, body, Scope::Admin, |_| {
+                set_instrument_status(symbol, instruments::InstrumentStatus::Halted)
+            })
+        }
+        (&Method::POST, path) if path.starts_with("/instruments/") && path.ends_with("/resume") => {
+            let symbol = &path["/instruments/".len()..path.len() - "/resume".len()];
+            authorized(StatusCode::OK, req, body, Scope::Admin, |_| {
+                set_instrument_status(symbol, instruments::InstrumentStatus::Active)
+            })
+        }
+        (&Method::DELETE, path) if path.starts_with("/instruments/") => {
+            let symbol = &path["/instruments/".len()..];
+            authorized(StatusCode::OK, req, body, Scope::Admin, |_| {
+                set_instrument_status(symbol, instruments::InstrumentStatus::Delisted)
+            })
+        }
+        _ => empty_response(StatusCode::METHOD_NOT_ALLOWED),
     }
 }
 
 fn main() {
     env_logger::init();
+    // Fail on a bad instruments or fees config, database, journal or admin key now rather
+    // than on the first request.
+    lazy_static::initialize(&INSTRUMENTS);
+    lazy_static::initialize(&FEES);
+    lazy_static::initialize(&DATABASE);
+    lazy_static::initialize(&JOURNAL);
+    lazy_static::initialize(&AUTH);
     let address = "127.0.0.1:3000".parse().unwrap();
 
     let client = Client::new();
@@ -206,7 +1239,42 @@ fn main() {
     };
     let server = hyper::server::Server::bind(&address).serve(new_service);
 
-    hyper::rt::run(server.map_err(|e| {
-        eprintln!("server error: {}", e);
+    // Snapshots are taken every SNAPSHOT_INTERVAL seconds if it's set, and on shutdown.
+    let interval = env::var("SNAPSHOT_INTERVAL")
+        .ok()
+        .map(|seconds| match seconds.parse() {
+            Ok(seconds) if seconds > 0 => Duration::from_secs(seconds),
+            _ => panic!("SNAPSHOT_INTERVAL must be a positive number of seconds"),
+        });
+    let shutdown = tokio_signal::ctrl_c()
+        .flatten_stream()
+        .select(Signal::new(SIGTERM).flatten_stream().map(|_| ()))
+        .into_future()
+        .map_err(|(e, _)| error!("unable to listen for signals: {}", e))
+        .map(|_| {
+            info!("shutting down");
+            if let Err(e) = save_snapshot() {
+                error!("{}", e);
+            }
+            std::process::exit(0);
+        });
+
+    hyper::rt::run(future::lazy(move || {
+        if let Some(interval) = interval {
+            hyper::rt::spawn(
+                Interval::new_interval(interval)
+                    .map_err(|e| error!("snapshot timer failed: {}", e))
+                    .for_each(|_| {
+                        if let Err(e) = save_snapshot() {
+                            error!("{}", e);
+                        }
+                        Ok(())
+                    }),
+            );
+        }
+        hyper::rt::spawn(shutdown);
+        server.map_err(|e| {
+            eprintln!("server error: {}", e);
+        })
     }));
 }
@@ -1,7 +1,10 @@
+use crate::decimal::{Decimal, MAX_SCALE};
+use crate::error::Error;
 use log::debug;
-use serde::{Deserialize, Serialize};
-use std::boxed::Box;
-use std::collections::VecDeque;
+use serde::{de, Deserialize, Deserializer, Serialize, Serializer};
+use std::collections::{BTreeMap, HashMap, VecDeque};
+use std::fmt;
+use std::time::{SystemTime, UNIX_EPOCH};
 use uuid::Uuid;
 
 #[derive(Serialize, Deserialize, Debug, Copy, Clone, PartialEq)]
@@ -10,255 +13,892 @@ pub enum Side {
     Sell,
 }
 
-#[derive(Serialize, Deserialize, Debug, Copy, Clone, Eq, PartialEq, Hash)]
-pub enum Symbol {
-    AAPL,
-    MSFT,
-    AMZN,
+const MAX_SYMBOL_LEN: usize = 12;
+
+// A ticker such as AAPL.
+// Stored inline rather than as a String so that orders can stay Copy.
+#[derive(Copy, Clone, Eq, PartialEq, Hash, Ord, PartialOrd)]
+pub struct Symbol {
+    len: u8,
+    bytes: [u8; MAX_SYMBOL_LEN],
+}
+
+impl Symbol {
+    pub fn new(symbol: &str) -> Result<Symbol, Error> {
+        if symbol.is_empty() || symbol.len() > MAX_SYMBOL_LEN {
+            return Err(Error::InvalidSymbol(
+                "symbol must be between 1 and 12 characters",
+            ));
+        }
+        if !symbol
+            .bytes()
+            .all(|b| b.is_ascii_alphanumeric() || b == b'.' || b == b'-')
+        {
+            return Err(Error::InvalidSymbol(
+                "symbol can only contain letters, digits, '.' and '-'",
+            ));
+        }
+        let mut bytes = [0; MAX_SYMBOL_LEN];
+        bytes[..symbol.len()].copy_from_slice(symbol.as_bytes());
+        Ok(Symbol {
+            len: symbol.len() as u8,
+            bytes,
+        })
+    }
+
+    pub fn as_str(&self) -> &str {
+        std::str::from_utf8(&self.bytes[..self.len as usize]).unwrap()
+    }
+}
+
+impl fmt::Display for Symbol {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        f.write_str(self.as_str())
+    }
+}
+
+impl fmt::Debug for Symbol {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        f.write_str(self.as_str())
+    }
+}
+
+impl Serialize for Symbol {
+    fn serialize<S: Serializer>(&self, serializer: S) -> Result<S::Ok, S::Error> {
+        serializer.serialize_str(self.as_str())
+    }
+}
+
+impl<'de> Deserialize<'de> for Symbol {
+    fn deserialize<D: Deserializer<'de>>(deserializer: D) -> Result<Symbol, D::Error> {
+        let symbol = String::deserialize(deserializer)?;
+        Symbol::new(&symbol).map_err(de::Error::custom)
+    }
 }
 
 #[derive(Serialize, Deserialize, Debug, Copy, Clone)]
 pub struct OpenLimitOrder {
     #[serde(skip_deserializing)]
     pub id: uuid::Uuid,
-    pub amount: u32,
+    // The account that placed it.
+    #[serde(skip_deserializing)]
+    pub owner: uuid::Uuid,
+    pub amount: Decimal,
     pub symbol: Symbol,
-    pub price: u32,
+    pub price: Decimal,
     pub side: Side,
 }
 
+// An order as anyone can see it, without who it belongs to.
+#[derive(Serialize, Deserialize, Debug, Copy, Clone, PartialEq)]
+pub struct PublicOrder {
+    pub id: uuid::Uuid,
+    pub amount: Decimal,
+    pub symbol: Symbol,
+    pub price: Decimal,
+    pub side: Side,
+}
+
+impl From<OpenLimitOrder> for PublicOrder {
+    fn from(order: OpenLimitOrder) -> PublicOrder {
+        PublicOrder {
+            id: order.id,
+            amount: order.amount,
+            symbol: order.symbol,
+            price: order.price,
+            side: order.side,
+        }
+    }
+}
+
+#[derive(Serialize, Deserialize, Debug, Copy, Clone, PartialEq, Default)]
+pub enum OrderType {
+    #[default]
+    Limit,
+    Market,
+}
+
+// How long an order stays live for.
+// GTC leaves whatever isn't filled on the book, IOC fills what it can
+// and cancels the rest, FOK fills the whole order at once or nothing at all.
+#[derive(Serialize, Deserialize, Debug, Copy, Clone, PartialEq, Default)]
+pub enum TimeInForce {
+    #[default]
+    #[serde(rename = "GTC")]
+    GoodTillCancel,
+    #[serde(rename = "IOC")]
+    ImmediateOrCancel,
+    #[serde(rename = "FOK")]
+    FillOrKill,
+}
+
+// What a client submits to /order.
+// Limit orders need a price, market orders take whatever is on the book
+// unless a protection_price is given, in which case they won't fill any
+// worse than it.
 #[derive(Serialize, Deserialize, Debug, Copy, Clone)]
+pub struct OrderRequest {
+    pub amount: Decimal,
+    pub symbol: Symbol,
+    pub side: Side,
+    #[serde(default)]
+    pub order_type: OrderType,
+    #[serde(default)]
+    pub time_in_force: TimeInForce,
+    #[serde(default)]
+    pub price: Option<Decimal>,
+    #[serde(default)]
+    pub protection_price: Option<Decimal>,
+}
+
+impl OrderRequest {
+    // Market orders never rest on the book, so a GTC market order is treated as IOC.
+    pub fn effective_time_in_force(&self) -> TimeInForce {
+        if self.order_type == OrderType::Market && self.time_in_force == TimeInForce::GoodTillCancel
+        {
+            return TimeInForce::ImmediateOrCancel;
+        }
+        self.time_in_force
+    }
+
+    // The order to match against the book with, placed by owner.
+    // A market order is a limit order at its protection price, or at the
+    // worst possible price if it has none.
+    pub fn to_order(self, id: Uuid, owner: Uuid) -> Result<OpenLimitOrder, Error> {
+        let price = match self.order_type {
+            OrderType::Limit => {
+                if self.protection_price.is_some() {
+                    return Err(Error::InvalidOrder(
+                        "protection_price is only valid for market orders",
+                    ));
+                }
+                match self.price {
+                    Some(price) => price,
+                    None => return Err(Error::InvalidOrder("limit order requires a price")),
+                }
+            }
+            OrderType::Market => {
+                if self.price.is_some() {
+                    return Err(Error::InvalidOrder(
+                        "market order cannot have a price, use protection_price",
+                    ));
+                }
+                match (self.protection_price, self.side) {
+                    (Some(price), _) => price,
+                    (None, Side::Buy) => Decimal::MAX,
+                    (None, Side::Sell) => Decimal::ZERO,
+                }
+            }
+        };
+        Ok(OpenLimitOrder {
+            id,
+            owner,
+            amount: self.amount,
+            symbol: self.symbol,
+            price,
+            side: self.side,
+        })
+    }
+}
+
+#[derive(Serialize, Deserialize, Debug, Copy, Clone, PartialEq)]
+pub enum OrderStatus {
+    New,
+    PartiallyFilled,
+    Filled,
+    Cancelled,
+}
+
+// Amount taken from a single price level of the book.
+#[derive(Serialize, Deserialize, Debug, Copy, Clone, PartialEq)]
+pub struct LevelFill {
+    pub price: Decimal,
+    pub amount: Decimal,
+}
+
+// A single match between an incoming (aggressor) order and a resting (passive) one.
+#[derive(Serialize, Deserialize, Debug, Copy, Clone, PartialEq)]
+pub struct Trade {
+    pub id: uuid::Uuid,
+    pub symbol: Symbol,
+    pub aggressor_id: uuid::Uuid,
+    pub passive_id: uuid::Uuid,
+    pub aggressor_side: Side,
+    // Always the passive order's price.
+    pub price: Decimal,
+    pub amount: Decimal,
+    // Milliseconds since the unix epoch.
+    pub timestamp: u64,
+}
+
+#[derive(Serialize, Deserialize, Debug, Clone)]
 pub struct FillResult {
-    pub avg_price: f64,
+    pub id: uuid::Uuid,
+    // Volume weighted average price, None if nothing was filled.
+    pub avg_price: Option<Decimal>,
+    // Exact total value traded, the sum of price * amount over every trade.
+    pub notional: Decimal,
+    pub filled: Decimal,
+    // Left resting on the book.
+    pub remaining: Decimal,
+    // Unfilled and not left on the book.
+    pub cancelled: Decimal,
+    pub status: OrderStatus,
+    // Every price level used to fill the order, best price first.
+    pub levels: Vec<LevelFill>,
+    // Every trade made filling the order, in the order they happened.
+    pub trades: Vec<Trade>,
+}
+
+impl FillResult {
+    // Cancel whatever wasn't filled rather than leaving it on the book.
+    pub fn cancel_remaining(&mut self) {
+        if self.remaining.is_zero() {
+            return;
+        }
+        self.cancelled += self.remaining;
+        self.remaining = Decimal::ZERO;
+        self.status = OrderStatus::Cancelled;
+    }
+}
+
+// Everything resting at one price on one side of the book.
+#[derive(Serialize, Deserialize, Debug, Copy, Clone, PartialEq)]
+pub struct PriceLevel {
+    pub price: Decimal,
+    // Total unfilled amount of the orders at this price.
+    pub amount: Decimal,
+    pub orders: usize,
+}
+
+// Aggregated view of both sides of an instrument's book, best prices first.
+#[derive(Serialize, Deserialize, Debug, Clone, PartialEq)]
+pub struct Depth {
+    pub symbol: Symbol,
+    pub bids: Vec<PriceLevel>,
+    pub asks: Vec<PriceLevel>,
+    pub best_bid: Option<Decimal>,
+    pub best_ask: Option<Decimal>,
+    // Best ask minus best bid, None unless both sides have orders.
+    pub spread: Option<Decimal>,
+    // Halfway between the best bid and ask, None unless both sides have orders.
+    pub mid: Option<Decimal>,
+}
+
+impl Depth {
+    // Up to levels price levels from each side. Both books should be locked together
+    // so the two sides are from the same moment.
+    pub fn new(
+        symbol: Symbol,
+        buy_book: &OrderBook,
+        sell_book: &OrderBook,
+        levels: usize,
+    ) -> Result<Depth, Error> {
+        let best_bid = buy_book.best_price();
+        let best_ask = sell_book.best_price();
+        let (spread, mid) = match (best_bid, best_ask) {
+            (Some(bid), Some(ask)) => {
+                let spread = ask.checked_sub(bid).ok_or(Error::Overflow)?;
+                let sum = ask.checked_add(bid).ok_or(Error::Overflow)?;
+                // Halving needs at most one more decimal place.
+                let scale = (sum.scale() + 1).min(MAX_SCALE);
+                let mid = sum
+                    .checked_div(Decimal::from(2u32), scale)
+                    .ok_or(Error::Overflow)?;
+                (Some(spread), Some(mid.normalize()))
+            }
+            _ => (None, None),
+        };
+        Ok(Depth {
+            symbol,
+            bids: buy_book.depth(levels)?,
+            asks: sell_book.depth(levels)?,
+            best_bid,
+            best_ask,
+            spread,
+            mid,
+        })
+    }
+}
+
+// Best bid and ask and the total amount resting at each.
+#[derive(Serialize, Deserialize, Debug, Copy, Clone, PartialEq, Default)]
+pub struct TopOfBook {
+    pub best_bid: Option<Decimal>,
+    pub bid_amount: Option<Decimal>,
+    pub best_ask: Option<Decimal>,
+    pub ask_amount: Option<Decimal>,
+}
+
+impl TopOfBook {
+    // Both books should be locked together so the bid and ask are from the same moment.
+    pub fn new(buy_book: &OrderBook, sell_book: &OrderBook) -> Result<TopOfBook, Error> {
+        let bid = buy_book.depth(1)?.pop();
+        let ask = sell_book.depth(1)?.pop();
+        Ok(TopOfBook {
+            best_bid: bid.map(|level| level.price),
+            bid_amount: bid.map(|level| level.amount),
+            best_ask: ask.map(|level| level.price),
+            ask_amount: ask.map(|level| level.amount),
+        })
+    }
+}
+
+// Cancel a resting order. The symbol is optional, without it every book is searched.
+#[derive(Serialize, Deserialize, Debug, Copy, Clone)]
+pub struct CancelRequest {
+    pub id: uuid::Uuid,
+    #[serde(default)]
+    pub symbol: Option<Symbol>,
+}
+
+// Change a resting order's price and/or remaining amount, anything left out stays as is.
+#[derive(Serialize, Deserialize, Debug, Copy, Clone)]
+pub struct AmendRequest {
+    #[serde(default)]
+    pub price: Option<Decimal>,
+    #[serde(default)]
+    pub amount: Option<Decimal>,
+}
+
+// A change to a single order on the book, what a market-by-order feed is made of.
+// An amend that loses the order its place in the queue is a cancel followed by an add.
+#[derive(Serialize, Deserialize, Debug, Copy, Clone, PartialEq)]
+#[serde(tag = "event", rename_all = "lowercase")]
+pub enum OrderEvent {
+    // Joined the back of the queue at its price.
+    Add {
+        id: Uuid,
+        side: Side,
+        price: Decimal,
+        amount: Decimal,
+    },
+    // Taken off the book with amount still unfilled.
+    Cancel {
+        id: Uuid,
+        side: Side,
+        price: Decimal,
+        amount: Decimal,
+    },
+    // Reduced to amount, keeping its place in the queue.
+    Modify {
+        id: Uuid,
+        side: Side,
+        price: Decimal,
+        amount: Decimal,
+    },
+    // Traded amount against an incoming order.
+    Execute {
+        id: Uuid,
+        side: Side,
+        price: Decimal,
+        amount: Decimal,
+    },
+}
+
+impl OrderEvent {
+    // The side and price level it changed.
+    pub fn level(&self) -> (Side, Decimal) {
+        match *self {
+            OrderEvent::Add { side, price, .. }
+            | OrderEvent::Cancel { side, price, .. }
+            | OrderEvent::Modify { side, price, .. }
+            | OrderEvent::Execute { side, price, .. } => (side, price),
+        }
+    }
+}
+
+// An order sitting in a price level queue, tagged with when it joined the queue.
+// Queues are always in seq order so an order can be found in its queue by binary search.
+#[derive(Debug, Copy, Clone)]
+struct QueuedOrder {
+    seq: u64,
+    order: OpenLimitOrder,
 }
 
 pub struct OrderBook {
-    book: Box<Vec<VecDeque<OpenLimitOrder>>>,
+    // A queue of orders for each price, in arrival order.
+    // Buys are matched from the highest price down and sells from the lowest up,
+    // use levels() to walk the book best price first.
+    book: BTreeMap<Decimal, VecDeque<QueuedOrder>>,
+    // Where every order on the book is: its price level and seq.
+    index: HashMap<Uuid, (Decimal, u64)>,
+    next_seq: u64,
     side: Side,
+    // Every change made to the book since take_events was last called, oldest first.
+    events: Vec<OrderEvent>,
+}
+
+fn price_level(price: Decimal, order_queue: &VecDeque<QueuedOrder>) -> Result<PriceLevel, Error> {
+    let amount = order_queue
+        .iter()
+        .try_fold(Decimal::ZERO, |sum, queued| {
+            sum.checked_add(queued.order.amount)
+        })
+        .ok_or(Error::Overflow)?;
+    Ok(PriceLevel {
+        price,
+        amount,
+        orders: order_queue.len(),
+    })
+}
+
+// Milliseconds since the unix epoch, what trades are timestamped with.
+pub fn now_millis() -> u64 {
+    SystemTime::now()
+        .duration_since(UNIX_EPOCH)
+        .unwrap()
+        .as_millis() as u64
 }
 
-pub const ERR_CANT_FILL_PRICE: &str = "Can't fill order, nothing available for that price";
-pub const ERR_CANT_FILL_SIZE: &str = "Can't fill order, order too large";
+// Decimal places average prices are given to.
+pub const AVG_PRICE_SCALE: u32 = 8;
 
 impl OrderBook {
     pub fn new(side: Side) -> OrderBook {
         OrderBook {
-            book: Box::new(Vec::new()),
-            side: side,
+            book: BTreeMap::new(),
+            index: HashMap::new(),
+            next_seq: 0,
+            side,
+            events: Vec::new(),
+        }
+    }
+
+    // Every change made to the book since this was last called, oldest first.
+    pub fn take_events(&mut self) -> Vec<OrderEvent> {
+        std::mem::take(&mut self.events)
+    }
+
+    // Price level queues, best price first.
+    fn levels<'a>(&'a self) -> Box<dyn Iterator<Item = &'a VecDeque<QueuedOrder>> + 'a> {
+        if self.side == Side::Buy {
+            Box::new(self.book.values().rev())
+        } else {
+            Box::new(self.book.values())
         }
     }
 
     pub fn get_book(&self) -> Vec<VecDeque<OpenLimitOrder>> {
-        return (*self.book).clone();
+        self.levels()
+            .map(|order_queue| order_queue.iter().map(|queued| queued.order).collect())
+            .collect()
     }
 
-    pub fn find_order(&self, t: OpenLimitOrder) -> (Option<usize>, Option<usize>) {
-        // TODO: optimize - can binary search to find the order
-        for (i, order_queue) in self.book.iter().enumerate() {
-            for (j, order) in order_queue.iter().enumerate() {
-                if order.id == t.id {
-                    debug!("found order, id {}", t.id);
-                    return (Some(i as usize), Some(j as usize));
-                }
+    // Up to levels price levels, best price first.
+    pub fn depth(&self, levels: usize) -> Result<Vec<PriceLevel>, Error> {
+        self.levels()
+            .take(levels)
+            .map(|order_queue| price_level(order_queue.front().unwrap().order.price, order_queue))
+            .collect()
+    }
+
+    // Everything resting at price, no orders and a zero amount if there are none.
+    pub fn level(&self, price: Decimal) -> Result<PriceLevel, Error> {
+        match self.book.get(&price) {
+            Some(order_queue) => price_level(price, order_queue),
+            None => Ok(PriceLevel {
+                price,
+                amount: Decimal::ZERO,
+                orders: 0,
+            }),
+        }
+    }
+
+    // The price level and position within its queue of an order.
+    pub fn find_order(&self, id: Uuid) -> Option<(Decimal, usize)> {
+        let &(price, seq) = self.index.get(&id)?;
+        let order_queue = &self.book[&price];
+        match order_queue.binary_search_by_key(&seq, |queued| queued.seq) {
+            Ok(i) => {
+                debug!("found order, id {}", id);
+                Some((price, i))
             }
+            Err(_) => panic!("order {} is indexed but not in its queue", id),
         }
-        return (None, None);
     }
 
-    pub fn remove_order(&mut self, t: OpenLimitOrder) -> Result<&'static str, &'static str> {
-        let (queue_index, order_index) = self.find_order(t);
-        if queue_index.is_none() || order_index.is_none() {
-            return Err("no such order");
+    // The current state of an order on the book, its amount is whatever is still unfilled.
+    pub fn get_order(&self, id: Uuid) -> Option<OpenLimitOrder> {
+        let (price, i) = self.find_order(id)?;
+        Some(self.book[&price][i].order)
+    }
+
+    // Returns the removed order, its amount is whatever was still unfilled.
+    pub fn remove_order(&mut self, id: Uuid) -> Result<OpenLimitOrder, Error> {
+        let (price, order_index) = match self.find_order(id) {
+            Some(location) => location,
+            None => return Err(Error::NoSuchOrder),
+        };
+        let order_queue = self.book.get_mut(&price).unwrap();
+        let order = match order_queue.remove(order_index) {
+            Some(queued) => queued.order,
+            None => return Err(Error::NoSuchOrder),
+        };
+        self.index.remove(&id);
+        if order_queue.is_empty() {
+            debug!("no more orders at price point {}", price);
+            self.book.remove(&price);
+        }
+        self.events.push(OrderEvent::Cancel {
+            id,
+            side: self.side,
+            price,
+            amount: order.amount,
+        });
+        Ok(order)
+    }
+
+    // Reducing the amount at the same price keeps the order's place in its queue.
+    // Any other change sends it to the back of the queue for its (new) price.
+    pub fn amend_order(&mut self, id: Uuid, amend: AmendRequest) -> Result<OpenLimitOrder, Error> {
+        let (queue_price, order_index) = match self.find_order(id) {
+            Some(location) => location,
+            None => return Err(Error::NoSuchOrder),
+        };
+        let queued = &mut self.book.get_mut(&queue_price).unwrap()[order_index].order;
+        let mut order = *queued;
+        let price = amend.price.unwrap_or(order.price);
+        let amount = amend.amount.unwrap_or(order.amount);
+        if !amount.is_positive() {
+            return Err(Error::InvalidOrder("amount must be positive"));
         }
-        let res = self.book[queue_index.unwrap()].remove(order_index.unwrap());
-        if res.is_none() {
-            return Err("error removing");
+        if price == order.price && amount <= order.amount {
+            debug!("reducing order {} to {} in place", id, amount);
+            queued.amount = amount;
+            let order = *queued;
+            self.events.push(OrderEvent::Modify {
+                id,
+                side: self.side,
+                price,
+                amount,
+            });
+            return Ok(order);
+        }
+        debug!("re-queueing order {} at {} for {}", id, price, amount);
+        self.remove_order(id)?;
+        order.price = price;
+        order.amount = amount;
+        self.add_order(order)
+    }
+
+    // Best price on the book, highest for buys and lowest for sells.
+    pub fn best_price(&self) -> Option<Decimal> {
+        if self.side == Side::Buy {
+            self.book.keys().next_back().cloned()
+        } else {
+            self.book.keys().next().cloned()
         }
-        if self.book[queue_index.unwrap()].len() == 0 {
-            debug!("no more orders at price point {}", t.price);
-            self.book.remove(queue_index.unwrap());
+    }
+
+    // Whether an order from the other side at that price would match anything.
+    pub fn crosses(&self, price: Decimal) -> bool {
+        match self.best_price() {
+            Some(best) => self.valid_price(price, best),
+            None => false,
         }
-        return Ok("successfully removed order");
     }
 
-    pub fn add_order(&mut self, t: OpenLimitOrder) -> Result<OpenLimitOrder, &'static str> {
+    pub fn add_order(&mut self, t: OpenLimitOrder) -> Result<OpenLimitOrder, Error> {
         if t.side != self.side {
-            return Err("wrong side");
+            return Err(Error::WrongSide);
+        }
+        if !t.amount.is_positive() {
+            return Err(Error::InvalidOrder("amount must be positive"));
         }
-        let mut order = t.clone();
+        let mut order = t;
         if t.id.is_nil() {
             order.id = Uuid::new_v4();
         }
         debug!("adding order {:?}", order);
-        // If we find an entry at that price point, add it to the queue
-        // Otherwise create a queue at that price point.
-        let mut queue_index = None;
-        let mut insert_index = None;
-
-        for (index, order_queue) in self.book.iter().enumerate() {
-            debug!("index {:?} order queue {:?}", index, order_queue);
-            if order_queue.front().unwrap().price == t.price {
-                queue_index = Some(index);
-                break;
-            } else if order_queue.front().unwrap().price < t.price && self.side == Side::Buy {
-                insert_index = Some(index);
-                break;
-            } else if order_queue.front().unwrap().price > t.price && self.side == Side::Sell {
-                insert_index = Some(index);
-                break;
-            }
+        if self.index.contains_key(&order.id) {
+            return Err(Error::DuplicateOrder);
         }
-
-        match queue_index {
-            Some(queue_index) => {
-                // Existing orders at that price
-                self.book[queue_index].push_back(order);
-            }
-            None => {
-                // No existing orders at the price, create a new queue
-                let mut orders: VecDeque<OpenLimitOrder> = VecDeque::new();
-                orders.push_back(order);
-                // Put the queue in the right place
-                match insert_index {
-                    Some(insert_index) => {
-                        // We know the spot to put this new queue
-                        self.book.insert(insert_index, orders);
-                    }
-                    None => {
-                        // Order book must be empty, just push the queue into the first spot
-                        self.book.push(orders);
-                    }
-                }
-            }
-        };
-        return Ok(order);
+        // Join the back of the queue at that price, creating it if need be.
+        let seq = self.next_seq;
+        self.next_seq += 1;
+        self.book
+            .entry(order.price)
+            .or_default()
+            .push_back(QueuedOrder { seq, order });
+        self.index.insert(order.id, (order.price, seq));
+        self.events.push(OrderEvent::Add {
+            id: order.id,
+            side: self.side,
+            price: order.price,
+            amount: order.amount,
+        });
+        Ok(order)
     }
 
-    pub fn valid_price(&self, to_fill_price: u32, candidate_order_price: u32) -> bool {
+    pub fn valid_price(&self, to_fill_price: Decimal, candidate_order_price: Decimal) -> bool {
         if self.side == Side::Buy {
             return to_fill_price <= candidate_order_price;
         }
-        return to_fill_price >= candidate_order_price;
+        to_fill_price >= candidate_order_price
     }
 
-    // Returns orders on the other side that were used to fill the order.
-    // Removes any orders that were used to fill from the book.
-    // If sum(orders returns) > to_fill, then the last order was only partially used to fill.
-    pub fn fill_order_helper(
-        &mut self,
-        to_fill: OpenLimitOrder,
-    ) -> Result<Vec<OpenLimitOrder>, &'static str> {
-        if to_fill.side == Side::Buy && self.side != Side::Sell {
-            return Err("cannot fill buy order with sell book");
-        }
-        if to_fill.side == Side::Sell && self.side != Side::Buy {
-            return Err("cannot fill sell order with buy  book");
+    // The orders on this book that to_fill would trade against, best price first, each with
+    // the amount that would be traded against it. Doesn't touch the book.
+    // Matching stops once the order is filled or nothing is left at a valid price, so the
+    // returned orders may cover less than to_fill.amount.
+    fn match_orders(&self, to_fill: OpenLimitOrder) -> Result<Vec<OpenLimitOrder>, Error> {
+        if to_fill.side == self.side {
+            return Err(Error::WrongSide);
         }
 
         debug!("orderbook size {}", self.book.len());
-        if self.book.len() == 0 {
-            return Err(ERR_CANT_FILL_SIZE);
+        let mut remaining = to_fill.amount;
+        let mut orders = Vec::new();
+        for order_queue in self.levels() {
+            // If the current price is no good we are done
+            if !self.valid_price(to_fill.price, order_queue.front().unwrap().order.price) {
+                debug!("nothing left in book at valid price");
+                break;
+            }
+            for queued in order_queue.iter() {
+                let mut used = queued.order;
+                if used.amount > remaining {
+                    // Make sure what's left of it can be worked out when it's split.
+                    used.amount.checked_sub(remaining).ok_or(Error::Overflow)?;
+                    used.amount = remaining;
+                }
+                remaining = remaining.checked_sub(used.amount).ok_or(Error::Overflow)?;
+                debug!("selecting {:?}", used);
+                orders.push(used);
+                if remaining.is_zero() {
+                    return Ok(orders);
+                }
+            }
         }
+        Ok(orders)
+    }
 
-        // If the current price is no good break
-        if !self.valid_price(to_fill.price, self.book[0].front().unwrap().price) {
-            debug!("nothing available in book at valid price");
-            return Err(ERR_CANT_FILL_PRICE);
+    // Takes the orders returned by match_orders off the book.
+    // Orders that were completely used are removed. If the last order was only partially
+    // used, it keeps its place in the queue with the traded amount taken off.
+    fn remove_matched(&mut self, orders: &[OpenLimitOrder]) {
+        for used in orders.iter() {
+            let order_queue = self.book.get_mut(&used.price).unwrap();
+            let front = &mut order_queue.front_mut().unwrap().order;
+            if used.amount < front.amount {
+                // Split the order, the rest of it stays at the front of the queue.
+                front.amount = front.amount.checked_sub(used.amount).unwrap();
+            } else {
+                order_queue.pop_front();
+                self.index.remove(&used.id);
+            }
+            if order_queue.is_empty() {
+                debug!(
+                    "drained the whole queue at {}, moving to next price",
+                    used.price
+                );
+                self.book.remove(&used.price);
+            }
+            self.events.push(OrderEvent::Execute {
+                id: used.id,
+                side: self.side,
+                price: used.price,
+                amount: used.amount,
+            });
         }
+    }
 
-        let mut remaining: i32 = to_fill.amount as i32;
-        let mut orders = Vec::new();
-
-        // Drain each queue one by one as needed
-        while self.valid_price(to_fill.price, self.book[0].front().unwrap().price) {
-            let order = self.book[0].pop_front();
-            match order {
-                Some(order) => {
-                    orders.push(order);
-                    debug!("selecting order {:?}", order);
-                    remaining = remaining - order.amount as i32;
-                }
-                None => {
-                    debug!("drained the whole queue at current price, moving to next price");
-                }
+    // Makes the change an event describes, for rebuilding a book from the events it emitted.
+    // Events don't say who orders belong to, so an order it adds is given to owner.
+    // The event is emitted again, so take_events afterwards if it isn't wanted.
+    pub fn apply_event(
+        &mut self,
+        symbol: Symbol,
+        owner: Uuid,
+        event: OrderEvent,
+    ) -> Result<(), Error> {
+        match event {
+            OrderEvent::Add {
+                id,
+                side,
+                price,
+                amount,
+            } => {
+                self.add_order(OpenLimitOrder {
+                    id,
+                    owner,
+                    amount,
+                    symbol,
+                    price,
+                    side,
+                })?;
             }
-            if self.book[0].len() == 0 {
-                self.book.remove(0);
+            OrderEvent::Cancel { id, .. } => {
+                self.remove_order(id)?;
             }
-            if remaining <= 0 {
-                debug!("filled the order");
-                break;
+            OrderEvent::Modify { id, amount, .. } => {
+                let amend = AmendRequest {
+                    price: None,
+                    amount: Some(amount),
+                };
+                self.amend_order(id, amend)?;
             }
-            if self.book.len() == 0 {
-                debug!("drained the whole book without filling the order");
-                // Add all the order back if we fail to fill
-                for &i in orders.iter() {
-                    let result = self.add_order(i);
-                    if result.is_err() {
-                        panic!(result);
-                    }
-                }
-                return Err(ERR_CANT_FILL_SIZE);
+            OrderEvent::Execute {
+                id, price, amount, ..
+            } => {
+                // Executions always take from the front of a queue.
+                let mut used = match self.book.get(&price).and_then(|queue| queue.front()) {
+                    Some(front) if front.order.id == id => front.order,
+                    _ => return Err(Error::NoSuchOrder),
+                };
+                used.amount = amount;
+                self.remove_matched(&[used]);
             }
         }
+        Ok(())
+    }
 
-        if remaining > 0 {
-            return Err(ERR_CANT_FILL_PRICE);
-        }
+    // Exact total value of the orders, the sum of price * amount.
+    pub fn notional(&self, orders: &[OpenLimitOrder]) -> Result<Decimal, Error> {
+        orders.iter().try_fold(Decimal::ZERO, |sum, order| {
+            order
+                .price
+                .checked_mul(order.amount)
+                .and_then(|value| sum.checked_add(value))
+                .ok_or(Error::Overflow)
+        })
+    }
 
-        if remaining == 0 {
-            // Exact fill
-            return Ok(orders);
+    // Volume weighted, to AVG_PRICE_SCALE decimal places (or the prices' own scale if that's
+    // finer) rounding half to even, with any trailing zeros dropped.
+    pub fn average_price(&self, orders: &[OpenLimitOrder]) -> Result<Decimal, Error> {
+        let total_shares = orders
+            .iter()
+            .try_fold(Decimal::ZERO, |sum, order| sum.checked_add(order.amount))
+            .ok_or(Error::Overflow)?;
+        if total_shares.is_zero() {
+            return Err(Error::InvalidOrder("no orders to average"));
         }
+        let scale = orders
+            .iter()
+            .map(|order| order.price.scale())
+            .fold(AVG_PRICE_SCALE, u32::max);
+        let average = self
+            .notional(orders)?
+            .checked_div(total_shares, scale)
+            .ok_or(Error::Overflow)?;
+        Ok(average.normalize())
+    }
 
-        // We have some set of orders which covers the requested amount, but not exactly.
-        // Add a new order which is this leftover to the book.
-        let last_order = orders[orders.len() - 1];
-        self.add_order(OpenLimitOrder {
-            id: last_order.id,
-            price: last_order.price,
-            side: last_order.side,
-            amount: remaining.abs() as u32,
-            symbol: last_order.symbol,
-        });
-        return Ok(orders);
+    // Matches as much of to_fill as possible against this book.
+    // For GTC orders whatever is left over is reported in FillResult.remaining, it is up to
+    // the caller to rest it on the book for the other side. IOC and FOK orders never leave
+    // anything remaining, and a FOK order that can't be completely filled doesn't touch
+    // the book at all.
+    // The result is worked out before anything is taken off the book, so if any of it
+    // would overflow the book is left as it was.
+    pub fn fill_order(
+        &mut self,
+        to_fill: OpenLimitOrder,
+        time_in_force: TimeInForce,
+    ) -> Result<FillResult, Error> {
+        let orders_used = self.match_orders(to_fill)?;
+        let mut fr = self.fill_result(to_fill, &orders_used)?;
+        if time_in_force == TimeInForce::FillOrKill && !fr.remaining.is_zero() {
+            debug!("not enough available to fill or kill {:?}", to_fill);
+            let mut fr = self.fill_result(to_fill, &[])?;
+            fr.cancel_remaining();
+            return Ok(fr);
+        }
+        self.remove_matched(&orders_used);
+        if time_in_force != TimeInForce::GoodTillCancel {
+            fr.cancel_remaining();
+        }
+        Ok(fr)
     }
 
-    pub fn average_price(&self, orders: Vec<OpenLimitOrder>) -> f64 {
-        let total_shares = orders.iter().fold(0, |sum, order| sum + order.amount);
-        orders
+    fn fill_result(
+        &self,
+        to_fill: OpenLimitOrder,
+        orders_used: &[OpenLimitOrder],
+    ) -> Result<FillResult, Error> {
+        let filled = orders_used
             .iter()
-            .fold(0, |sum, order| sum + order.price * order.amount) as f64
-            / total_shares as f64
-    }
-
-    pub fn fill_order(&mut self, to_fill: OpenLimitOrder) -> Result<FillResult, &'static str> {
-        let orders_used = self.fill_order_helper(to_fill)?;
-        let extra = orders_used.iter().fold(0, |sum, order| sum + order.amount) - to_fill.amount;
-        if extra > 0 {
-            // If the orders used is > then the desired fill, we had to split an order.
-            // Include the appropriate portion of the split order in the average price calculation.
-            let (orders_used, extra_order) = orders_used.split_at(orders_used.len() - 1);
-            let mut orders_used = orders_used.clone().to_vec();
-            let mut extra_order = extra_order[0].clone();
-            extra_order.amount = extra;
-            orders_used.push(extra_order);
-            return Ok(FillResult {
-                avg_price: self.average_price(orders_used),
-            });
+            .try_fold(Decimal::ZERO, |sum, order| sum.checked_add(order.amount))
+            .ok_or(Error::Overflow)?;
+        let remaining = to_fill.amount.checked_sub(filled).ok_or(Error::Overflow)?;
+        let status = if filled.is_zero() {
+            OrderStatus::New
+        } else if remaining.is_zero() {
+            OrderStatus::Filled
+        } else {
+            OrderStatus::PartiallyFilled
+        };
+        let mut levels: Vec<LevelFill> = Vec::new();
+        for order in orders_used.iter() {
+            match levels.last_mut() {
+                Some(level) if level.price == order.price => {
+                    level.amount = level
+                        .amount
+                        .checked_add(order.amount)
+                        .ok_or(Error::Overflow)?
+                }
+                _ => levels.push(LevelFill {
+                    price: order.price,
+                    amount: order.amount,
+                }),
+            }
         }
+        let timestamp = now_millis();
+        let trades = orders_used
+            .iter()
+            .map(|order| Trade {
+                id: Uuid::new_v4(),
+                symbol: to_fill.symbol,
+                aggressor_id: to_fill.id,
+                passive_id: order.id,
+                aggressor_side: to_fill.side,
+                price: order.price,
+                amount: order.amount,
+                timestamp,
+            })
+            .collect();
+        let notional = self.notional(orders_used)?;
+        let avg_price = if filled.is_zero() {
+            None
+        } else {
+            Some(self.average_price(orders_used)?)
+        };
         Ok(FillResult {
-            avg_price: self.average_price(orders_used),
+            id: to_fill.id,
+            avg_price,
+            notional,
+            filled,
+            remaining,
+            cancelled: Decimal::ZERO,
+            status,
+            levels,
+            trades,
         })
     }
 }
 
 #[cfg(test)]
 mod tests {
-    use crate::order_book::{FillResult, OpenLimitOrder, OrderBook, Side, Symbol};
+    use crate::decimal::Decimal;
+    use crate::error::Error;
+    use crate::order_book::{
+        AmendRequest, Depth, LevelFill, OpenLimitOrder, OrderBook, OrderEvent, OrderRequest,
+        OrderStatus, OrderType, PriceLevel, Side, Symbol, TimeInForce,
+    };
     use crate::VecDeque;
+    use proptest::prelude::*;
     use uuid::Uuid;
 
+    fn d(n: u32) -> Decimal {
+        Decimal::from(n)
+    }
+
+    fn aapl() -> Symbol {
+        Symbol::new("AAPL").unwrap()
+    }
+
     fn assert_order(expected: &OpenLimitOrder, actual: &OpenLimitOrder) {
         assert_eq!(expected.amount, actual.amount);
         assert_eq!(expected.price, actual.price);
@@ -303,30 +943,33 @@ mod tests {
             expected_after_add: Vec<VecDeque<OpenLimitOrder>>,
             remove: Vec<OpenLimitOrder>,
             expected_after_remove: Vec<VecDeque<OpenLimitOrder>>,
-        };
-        let test_cases = vec![
+        }
+        let test_cases = [
             // Single add remove
             TestCase {
                 add: vec![OpenLimitOrder {
                     id: Uuid::parse_str("00000000-0000-0000-0000-000000000010").unwrap(),
-                    amount: 10,
-                    symbol: Symbol::AAPL,
+                    owner: Uuid::nil(),
+                    amount: d(10),
+                    symbol: aapl(),
                     side: Side::Buy,
-                    price: 5,
+                    price: d(5),
                 }],
                 expected_after_add: vec![VecDeque::from(vec![OpenLimitOrder {
                     id: Uuid::parse_str("00000000-0000-0000-0000-000000000010").unwrap(),
-                    amount: 10,
-                    symbol: Symbol::AAPL,
+                    owner: Uuid::nil(),
+                    amount: d(10),
+                    symbol: aapl(),
                     side: Side::Buy,
-                    price: 5,
+                    price: d(5),
                 }])],
                 remove: vec![OpenLimitOrder {
                     id: Uuid::parse_str("00000000-0000-0000-0000-000000000010").unwrap(),
-                    amount: 10,
-                    symbol: Symbol::AAPL,
+                    owner: Uuid::nil(),
+                    amount: d(10),
+                    symbol: aapl(),
                     side: Side::Buy,
-                    price: 5,
+                    price: d(5),
                 }],
                 expected_after_remove: Vec::new(),
             },
@@ -335,48 +978,54 @@ mod tests {
                 add: vec![
                     OpenLimitOrder {
                         id: Uuid::parse_str("00000000-0000-0000-0000-000000000010").unwrap(),
-                        amount: 10,
-                        symbol: Symbol::AAPL,
+                        owner: Uuid::nil(),
+                        amount: d(10),
+                        symbol: aapl(),
                         side: Side::Buy,
-                        price: 5,
+                        price: d(5),
                     },
                     OpenLimitOrder {
                         id: Uuid::parse_str("00000000-0000-0000-0000-000000000001").unwrap(),
-                        amount: 10,
-                        symbol: Symbol::AAPL,
+                        owner: Uuid::nil(),
+                        amount: d(10),
+                        symbol: aapl(),
                         side: Side::Buy,
-                        price: 5,
+                        price: d(5),
                     },
                 ],
                 expected_after_add: vec![VecDeque::from(vec![
                     OpenLimitOrder {
                         id: Uuid::parse_str("00000000-0000-0000-0000-000000000010").unwrap(),
-                        amount: 10,
-                        symbol: Symbol::AAPL,
+                        owner: Uuid::nil(),
+                        amount: d(10),
+                        symbol: aapl(),
                         side: Side::Buy,
-                        price: 5,
+                        price: d(5),
                     },
                     OpenLimitOrder {
                         id: Uuid::parse_str("00000000-0000-0000-0000-000000000001").unwrap(),
-                        amount: 10,
-                        symbol: Symbol::AAPL,
+                        owner: Uuid::nil(),
+                        amount: d(10),
+                        symbol: aapl(),
                         side: Side::Buy,
-                        price: 5,
+                        price: d(5),
                     },
                 ])],
                 remove: vec![OpenLimitOrder {
                     id: Uuid::parse_str("00000000-0000-0000-0000-000000000010").unwrap(),
-                    amount: 10,
-                    symbol: Symbol::AAPL,
+                    owner: Uuid::nil(),
+                    amount: d(10),
+                    symbol: aapl(),
                     side: Side::Buy,
-                    price: 5,
+                    price: d(5),
                 }],
                 expected_after_remove: vec![VecDeque::from(vec![OpenLimitOrder {
                     id: Uuid::parse_str("00000000-0000-0000-0000-000000000001").unwrap(),
-                    amount: 10,
-                    symbol: Symbol::AAPL,
+                    owner: Uuid::nil(),
+                    amount: d(10),
+                    symbol: aapl(),
                     side: Side::Buy,
-                    price: 5,
+                    price: d(5),
                 }])],
             },
             // Maintain sort
@@ -384,50 +1033,56 @@ mod tests {
                 add: vec![
                     OpenLimitOrder {
                         id: Uuid::parse_str("00000000-0000-0000-0000-000000000010").unwrap(),
-                        amount: 10,
-                        symbol: Symbol::AAPL,
+                        owner: Uuid::nil(),
+                        amount: d(10),
+                        symbol: aapl(),
                         side: Side::Buy,
-                        price: 4,
+                        price: d(4),
                     },
                     OpenLimitOrder {
                         id: Uuid::parse_str("00000000-0000-0000-0000-000000000001").unwrap(),
-                        amount: 10,
-                        symbol: Symbol::AAPL,
+                        owner: Uuid::nil(),
+                        amount: d(10),
+                        symbol: aapl(),
                         side: Side::Buy,
-                        price: 5,
+                        price: d(5),
                     },
                 ],
                 expected_after_add: vec![
                     VecDeque::from(vec![OpenLimitOrder {
                         id: Uuid::parse_str("00000000-0000-0000-0000-000000000001").unwrap(),
-                        amount: 10,
-                        symbol: Symbol::AAPL,
+                        owner: Uuid::nil(),
+                        amount: d(10),
+                        symbol: aapl(),
                         side: Side::Buy,
-                        price: 5,
+                        price: d(5),
                     }]),
                     VecDeque::from(vec![OpenLimitOrder {
                         id: Uuid::parse_str("00000000-0000-0000-0000-000000000010").unwrap(),
-                        amount: 10,
-                        symbol: Symbol::AAPL,
+                        owner: Uuid::nil(),
+                        amount: d(10),
+                        symbol: aapl(),
                         side: Side::Buy,
-                        price: 4,
+                        price: d(4),
                     }]),
                 ],
                 remove: Vec::new(),
                 expected_after_remove: vec![
                     VecDeque::from(vec![OpenLimitOrder {
                         id: Uuid::parse_str("00000000-0000-0000-0000-000000000001").unwrap(),
-                        amount: 10,
-                        symbol: Symbol::AAPL,
+                        owner: Uuid::nil(),
+                        amount: d(10),
+                        symbol: aapl(),
                         side: Side::Buy,
-                        price: 5,
+                        price: d(5),
                     }]),
                     VecDeque::from(vec![OpenLimitOrder {
                         id: Uuid::parse_str("00000000-0000-0000-0000-000000000010").unwrap(),
-                        amount: 10,
-                        symbol: Symbol::AAPL,
+                        owner: Uuid::nil(),
+                        amount: d(10),
+                        symbol: aapl(),
                         side: Side::Buy,
-                        price: 4,
+                        price: d(4),
                     }]),
                 ],
             },
@@ -436,24 +1091,25 @@ mod tests {
             let mut buy_ob = OrderBook::new(Side::Buy);
             for &to_add in tc.add.iter() {
                 let result = buy_ob.add_order(to_add);
-                assert!(!result.is_err());
+                assert!(result.is_ok());
             }
             assert_order_book(buy_ob.get_book(), tc.expected_after_add.clone());
             for &to_remove in tc.remove.iter() {
-                let result = buy_ob.remove_order(to_remove);
-                assert!(!result.is_err());
+                let result = buy_ob.remove_order(to_remove.id);
+                assert!(result.is_ok());
+                assert_order(&to_remove, &result.unwrap());
             }
             assert_order_book(buy_ob.get_book(), tc.expected_after_remove.clone());
         }
     }
 
     fn create_order_book(side: Side, orders: Vec<OpenLimitOrder>) -> OrderBook {
-        let mut buy_ob = OrderBook::new(Side::Buy);
+        let mut ob = OrderBook::new(side);
         for &order in orders.iter() {
-            let result = buy_ob.add_order(order);
-            assert!(!result.is_err());
+            let result = ob.add_order(order);
+            assert!(result.is_ok());
         }
-        return buy_ob;
+        ob
     }
 
     #[test]
@@ -463,58 +1119,66 @@ mod tests {
             vec![
                 OpenLimitOrder {
                     id: Uuid::parse_str("00000000-0000-0000-0000-000000000010").unwrap(),
-                    amount: 10,
-                    symbol: Symbol::AAPL,
+                    owner: Uuid::nil(),
+                    amount: d(10),
+                    symbol: aapl(),
                     side: Side::Buy,
-                    price: 4,
+                    price: d(4),
                 },
                 OpenLimitOrder {
                     id: Uuid::parse_str("00000000-0000-0000-0000-000000000001").unwrap(),
-                    amount: 10,
-                    symbol: Symbol::AAPL,
+                    owner: Uuid::nil(),
+                    amount: d(10),
+                    symbol: aapl(),
                     side: Side::Buy,
-                    price: 5,
+                    price: d(5),
                 },
             ],
         );
 
-        let result = buy_ob.fill_order_helper(OpenLimitOrder {
+        let result = buy_ob.match_orders(OpenLimitOrder {
             id: Uuid::parse_str("00000000-0000-0000-0000-000000000001").unwrap(),
-            amount: 10,
-            symbol: Symbol::AAPL,
+            owner: Uuid::nil(),
+            amount: d(10),
+            symbol: aapl(),
             side: Side::Buy,
-            price: 5,
+            price: d(5),
         });
         // Must be opposite side
-        assert!(result.is_err());
+        assert_eq!(result.unwrap_err(), Error::WrongSide);
 
         // Sell for 3, should take any bids >= 3, best price first
-        let result = buy_ob.fill_order_helper(OpenLimitOrder {
+        let result = buy_ob.match_orders(OpenLimitOrder {
             id: Uuid::parse_str("00000000-0000-0000-0000-000000000002").unwrap(),
-            amount: 10,
-            symbol: Symbol::AAPL,
+            owner: Uuid::nil(),
+            amount: d(10),
+            symbol: aapl(),
             side: Side::Sell,
-            price: 3,
+            price: d(3),
         });
-        assert!(!result.is_err());
+        assert!(result.is_ok());
+        let matched = result.unwrap();
         assert_orders(
             vec![OpenLimitOrder {
                 id: Uuid::parse_str("00000000-0000-0000-0000-000000000001").unwrap(),
-                amount: 10,
-                symbol: Symbol::AAPL,
+                owner: Uuid::nil(),
+                amount: d(10),
+                symbol: aapl(),
                 side: Side::Buy,
-                price: 5,
+                price: d(5),
             }],
-            result.unwrap(),
+            matched.clone(),
         );
+        buy_ob.remove_matched(&matched);
         // Only the 4 should be left in the book
         assert_order_book(
             vec![VecDeque::from(vec![OpenLimitOrder {
                 id: Uuid::parse_str("00000000-0000-0000-0000-000000000010").unwrap(),
-                amount: 10,
-                symbol: Symbol::AAPL,
+                owner: Uuid::nil(),
+                amount: d(10),
+                symbol: aapl(),
                 side: Side::Buy,
-                price: 4,
+                price: d(4),
             }])],
             buy_ob.get_book(),
         )
@@ -531,38 +1195,43 @@ mod tests {
             vec![
                 OpenLimitOrder {
                     id: Uuid::parse_str("00000000-0000-0000-0000-000000000010").unwrap(),
-                    amount: 10,
-                    symbol: Symbol::AAPL,
+                    owner: Uuid::nil(),
+                    amount: d(10),
+                    symbol: aapl(),
                     side: Side::Buy,
-                    price: 4,
+                    price: d(4),
                 },
                 OpenLimitOrder {
                     id: Uuid::parse_str("00000000-0000-0000-0000-000000000001").unwrap(),
-                    amount: 11,
-                    symbol: Symbol::AAPL,
+                    owner: Uuid::nil(),
+                    amount: d(11),
+                    symbol: aapl(),
                     side: Side::Buy,
-                    price: 5,
+                    price: d(5),
                 },
                 OpenLimitOrder {
                     id: Uuid::parse_str("00000000-0000-0000-0000-000000000002").unwrap(),
-                    amount: 6,
-                    symbol: Symbol::AAPL,
+                    owner: Uuid::nil(),
+                    amount: d(6),
+                    symbol: aapl(),
                     side: Side::Buy,
-                    price: 3,
+                    price: d(3),
                 },
                 OpenLimitOrder {
                     id: Uuid::parse_str("00000000-0000-0000-0000-000000000003").unwrap(),
-                    amount: 6,
-                    symbol: Symbol::AAPL,
+                    owner: Uuid::nil(),
+                    amount: d(6),
+                    symbol: aapl(),
                     side: Side::Buy,
-                    price: 7,
+                    price: d(7),
                 },
                 OpenLimitOrder {
                     id: Uuid::parse_str("00000000-0000-0000-0000-000000000004").unwrap(),
-                    amount: 3,
-                    symbol: Symbol::AAPL,
+                    owner: Uuid::nil(),
+                    amount: d(3),
+                    symbol: aapl(),
                     side: Side::Buy,
-                    price: 3,
+                    price: d(3),
                 },
             ],
         );
@@ -570,22 +1239,25 @@ mod tests {
         // Sell for 3, should take any bids >= 3, best price first
         // This order should eat the whole book except for the last buy
         // which it splits.
-        let result = buy_ob.fill_order_helper(OpenLimitOrder {
+        let result = buy_ob.match_orders(OpenLimitOrder {
             id: Uuid::parse_str("00000000-0000-0000-0000-000000000005").unwrap(),
-            amount: 35,
-            symbol: Symbol::AAPL,
+            owner: Uuid::nil(),
+            amount: d(35),
+            symbol: aapl(),
             side: Side::Sell,
-            price: 3,
+            price: d(3),
         });
-        assert!(!result.is_err());
+        assert!(result.is_ok());
+        buy_ob.remove_matched(&result.unwrap());
         // We ate 35 shares of the total 36 on the book.
         assert_order_book(
             vec![VecDeque::from(vec![OpenLimitOrder {
                 id: Uuid::nil(),
-                amount: 1,
-                symbol: Symbol::AAPL,
+                owner: Uuid::nil(),
+                amount: d(1),
+                symbol: aapl(),
                 side: Side::Buy,
-                price: 3,
+                price: d(3),
             }])],
             buy_ob.get_book(),
         )
@@ -596,21 +1268,764 @@ mod tests {
         let orders = vec![
             OpenLimitOrder {
                 id: Uuid::parse_str("00000000-0000-0000-0000-000000000010").unwrap(),
-                amount: 10,
-                symbol: Symbol::AAPL,
+                owner: Uuid::nil(),
+                amount: d(10),
+                symbol: aapl(),
                 side: Side::Buy,
-                price: 4,
+                price: d(4),
             },
             OpenLimitOrder {
                 id: Uuid::parse_str("00000000-0000-0000-0000-000000000001").unwrap(),
-                amount: 11,
-                symbol: Symbol::AAPL,
+                owner: Uuid::nil(),
+                amount: d(11),
+                symbol: aapl(),
                 side: Side::Buy,
-                price: 5,
+                price: d(5),
             },
         ];
         let ob = OrderBook::new(Side::Buy);
-        assert_eq!(ob.average_price(orders), 4.523809523809524);
+        assert_eq!(ob.average_price(&orders).unwrap().to_string(), "4.52380952");
+        assert_eq!(ob.notional(&orders).unwrap(), d(95));
+    }
+
+    #[test]
+    fn test_partial_fill() {
+        // 5 -> [4, 3]
+        // 6 -> [2]
+        let mut sell_ob = create_order_book(
+            Side::Sell,
+            vec![
+                OpenLimitOrder {
+                    id: Uuid::parse_str("00000000-0000-0000-0000-000000000001").unwrap(),
+                    owner: Uuid::nil(),
+                    amount: d(4),
+                    symbol: aapl(),
+                    side: Side::Sell,
+                    price: d(5),
+                },
+                OpenLimitOrder {
+                    id: Uuid::parse_str("00000000-0000-0000-0000-000000000002").unwrap(),
+                    owner: Uuid::nil(),
+                    amount: d(3),
+                    symbol: aapl(),
+                    side: Side::Sell,
+                    price: d(5),
+                },
+                OpenLimitOrder {
+                    id: Uuid::parse_str("00000000-0000-0000-0000-000000000003").unwrap(),
+                    owner: Uuid::nil(),
+                    amount: d(2),
+                    symbol: aapl(),
+                    side: Side::Sell,
+                    price: d(6),
+                },
+            ],
+        );
+
+        // Nothing at a valid price, the book is left alone.
+        let result = sell_ob
+            .fill_order(
+                OpenLimitOrder {
+                    id: Uuid::parse_str("00000000-0000-0000-0000-000000000004").unwrap(),
+                    owner: Uuid::nil(),
+                    amount: d(5),
+                    symbol: aapl(),
+                    side: Side::Buy,
+                    price: d(4),
+                },
+                TimeInForce::GoodTillCancel,
+            )
+            .unwrap();
+        assert_eq!(result.status, OrderStatus::New);
+        assert_eq!(result.filled, d(0));
+        assert_eq!(result.remaining, d(5));
+        assert_eq!(result.avg_price, None);
+        assert_eq!(sell_ob.get_book().len(), 2);
+
+        // Buy 5 at 5: eats the first order and splits the second,
+        // which keeps its place at the front of the queue.
+        let result = sell_ob
+            .fill_order(
+                OpenLimitOrder {
+                    id: Uuid::parse_str("00000000-0000-0000-0000-000000000005").unwrap(),
+                    owner: Uuid::nil(),
+                    amount: d(5),
+                    symbol: aapl(),
+                    side: Side::Buy,
+                    price: d(5),
+                },
+                TimeInForce::GoodTillCancel,
+            )
+            .unwrap();
+        assert_eq!(result.status, OrderStatus::Filled);
+        assert_eq!(result.filled, d(5));
+        assert_eq!(result.remaining, d(0));
+        assert_eq!(result.avg_price, Some(d(5)));
+        assert_eq!(result.notional, d(25));
+        assert_eq!(result.trades.len(), 2);
+        for (trade, (passive_id, amount)) in result.trades.iter().zip(vec![
+            ("00000000-0000-0000-0000-000000000001", 4),
+            ("00000000-0000-0000-0000-000000000002", 1),
+        ]) {
+            assert_eq!(trade.aggressor_id, result.id);
+            assert_eq!(trade.passive_id, Uuid::parse_str(passive_id).unwrap());
+            assert_eq!(trade.aggressor_side, Side::Buy);
+            assert_eq!(trade.price, d(5));
+            assert_eq!(trade.amount, d(amount));
+        }
+
+        // Buy 10 at 5: only the 2 left at 5 are marketable, the 6 is too expensive.
+        let result = sell_ob
+            .fill_order(
+                OpenLimitOrder {
+                    id: Uuid::parse_str("00000000-0000-0000-0000-000000000006").unwrap(),
+                    owner: Uuid::nil(),
+                    amount: d(10),
+                    symbol: aapl(),
+                    side: Side::Buy,
+                    price: d(5),
+                },
+                TimeInForce::GoodTillCancel,
+            )
+            .unwrap();
+        assert_eq!(result.status, OrderStatus::PartiallyFilled);
+        assert_eq!(result.filled, d(2));
+        assert_eq!(result.remaining, d(8));
+        assert_order_book(
+            vec![VecDeque::from(vec![OpenLimitOrder {
+                id: Uuid::parse_str("00000000-0000-0000-0000-000000000003").unwrap(),
+                owner: Uuid::nil(),
+                amount: d(2),
+                symbol: aapl(),
+                side: Side::Sell,
+                price: d(6),
+            }])],
+            sell_ob.get_book(),
+        );
+    }
+
+    #[test]
+    fn test_market_order() {
+        // 5 -> [4]
+        // 6 -> [2]
+        // 50 -> [10]
+        let sell_orders = vec![
+            OpenLimitOrder {
+                id: Uuid::parse_str("00000000-0000-0000-0000-000000000001").unwrap(),
+                owner: Uuid::nil(),
+                amount: d(4),
+                symbol: aapl(),
+                side: Side::Sell,
+                price: d(5),
+            },
+            OpenLimitOrder {
+                id: Uuid::parse_str("00000000-0000-0000-0000-000000000002").unwrap(),
+                owner: Uuid::nil(),
+                amount: d(2),
+                symbol: aapl(),
+                side: Side::Sell,
+                price: d(6),
+            },
+            OpenLimitOrder {
+                id: Uuid::parse_str("00000000-0000-0000-0000-000000000003").unwrap(),
+                owner: Uuid::nil(),
+                amount: d(10),
+                symbol: aapl(),
+                side: Side::Sell,
+                price: d(50),
+            },
+        ];
+        let market_buy = OrderRequest {
+            amount: d(10),
+            symbol: aapl(),
+            side: Side::Buy,
+            order_type: OrderType::Market,
+            price: None,
+            protection_price: None,
+            time_in_force: TimeInForce::GoodTillCancel,
+        };
+        let id = Uuid::parse_str("00000000-0000-0000-0000-000000000004").unwrap();
+
+        // Market orders need a protection price rather than a price
+        let mut with_price = market_buy;
+        with_price.price = Some(d(5));
+        assert!(with_price.to_order(id, Uuid::nil()).is_err());
+
+        // No protection, sweeps up to the 50
+        let mut sell_ob = create_order_book(Side::Sell, sell_orders.clone());
+        let result = sell_ob
+            .fill_order(
+                market_buy.to_order(id, Uuid::nil()).unwrap(),
+                market_buy.effective_time_in_force(),
+            )
+            .unwrap();
+        assert_eq!(result.status, OrderStatus::Filled);
+        assert_eq!(
+            result.levels,
+            vec![
+                LevelFill {
+                    price: d(5),
+                    amount: d(4)
+                },
+                LevelFill {
+                    price: d(6),
+                    amount: d(2)
+                },
+                LevelFill {
+                    price: d(50),
+                    amount: d(4)
+                },
+            ]
+        );
+
+        // Protected at 6, the rest is cancelled rather than left on the book
+        let mut protected = market_buy;
+        protected.protection_price = Some(d(6));
+        let mut sell_ob = create_order_book(Side::Sell, sell_orders);
+        let result = sell_ob
+            .fill_order(
+                protected.to_order(id, Uuid::nil()).unwrap(),
+                protected.effective_time_in_force(),
+            )
+            .unwrap();
+        assert_eq!(result.status, OrderStatus::Cancelled);
+        assert_eq!(result.filled, d(6));
+        assert_eq!(result.remaining, d(0));
+        assert_eq!(result.cancelled, d(4));
+        assert_eq!(
+            result.levels,
+            vec![
+                LevelFill {
+                    price: d(5),
+                    amount: d(4)
+                },
+                LevelFill {
+                    price: d(6),
+                    amount: d(2)
+                },
+            ]
+        );
+        assert_eq!(sell_ob.get_book().len(), 1);
+    }
+
+    #[test]
+    fn test_time_in_force() {
+        // 5 -> [4]
+        // 6 -> [2]
+        let sell_orders = vec![
+            OpenLimitOrder {
+                id: Uuid::parse_str("00000000-0000-0000-0000-000000000001").unwrap(),
+                owner: Uuid::nil(),
+                amount: d(4),
+                symbol: aapl(),
+                side: Side::Sell,
+                price: d(5),
+            },
+            OpenLimitOrder {
+                id: Uuid::parse_str("00000000-0000-0000-0000-000000000002").unwrap(),
+                owner: Uuid::nil(),
+                amount: d(2),
+                symbol: aapl(),
+                side: Side::Sell,
+                price: d(6),
+            },
+        ];
+        let buy = OpenLimitOrder {
+            id: Uuid::parse_str("00000000-0000-0000-0000-000000000003").unwrap(),
+            owner: Uuid::nil(),
+            amount: d(5),
+            symbol: aapl(),
+            side: Side::Buy,
+            price: d(5),
+        };
+
+        // IOC takes the 4 at 5 and cancels the rest
+        let mut sell_ob = create_order_book(Side::Sell, sell_orders.clone());
+        let result = sell_ob
+            .fill_order(buy, TimeInForce::ImmediateOrCancel)
+            .unwrap();
+        assert_eq!(result.status, OrderStatus::Cancelled);
+        assert_eq!(result.filled, d(4));
+        assert_eq!(result.remaining, d(0));
+        assert_eq!(result.cancelled, d(1));
+        assert_eq!(sell_ob.get_book().len(), 1);
+
+        // FOK can't get 5 at 5, nothing is touched
+        let mut sell_ob = create_order_book(Side::Sell, sell_orders.clone());
+        let result = sell_ob.fill_order(buy, TimeInForce::FillOrKill).unwrap();
+        assert_eq!(result.status, OrderStatus::Cancelled);
+        assert_eq!(result.filled, d(0));
+        assert_eq!(result.cancelled, d(5));
+        assert_order_book(
+            vec![
+                VecDeque::from(vec![sell_orders[0]]),
+                VecDeque::from(vec![sell_orders[1]]),
+            ],
+            sell_ob.get_book(),
+        );
+
+        // FOK at 6 has enough across both levels
+        let mut at_six = buy;
+        at_six.price = d(6);
+        let result = sell_ob.fill_order(at_six, TimeInForce::FillOrKill).unwrap();
+        assert_eq!(result.status, OrderStatus::Filled);
+        assert_eq!(result.filled, d(5));
+        assert_eq!(result.cancelled, d(0));
+        assert_eq!(sell_ob.get_book().len(), 1);
+    }
+
+    #[test]
+    fn test_amend_order() {
+        // 5 -> [1: 4, 2: 3]
+        let mut buy_ob = create_order_book(
+            Side::Buy,
+            vec![
+                OpenLimitOrder {
+                    id: Uuid::parse_str("00000000-0000-0000-0000-000000000001").unwrap(),
+                    owner: Uuid::nil(),
+                    amount: d(4),
+                    symbol: aapl(),
+                    side: Side::Buy,
+                    price: d(5),
+                },
+                OpenLimitOrder {
+                    id: Uuid::parse_str("00000000-0000-0000-0000-000000000002").unwrap(),
+                    owner: Uuid::nil(),
+                    amount: d(3),
+                    symbol: aapl(),
+                    side: Side::Buy,
+                    price: d(5),
+                },
+            ],
+        );
+        let first = Uuid::parse_str("00000000-0000-0000-0000-000000000001").unwrap();
+        let second = Uuid::parse_str("00000000-0000-0000-0000-000000000002").unwrap();
+
+        // Reducing keeps priority
+        let result = buy_ob.amend_order(
+            first,
+            AmendRequest {
+                price: None,
+                amount: Some(d(2)),
+            },
+        );
+        assert_eq!(result.unwrap().amount, d(2));
+        assert_eq!(buy_ob.get_book()[0][0].id, first);
+        assert_eq!(buy_ob.get_book()[0][0].amount, d(2));
+
+        // Increasing goes to the back of the queue
+        let result = buy_ob.amend_order(
+            first,
+            AmendRequest {
+                price: None,
+                amount: Some(d(6)),
+            },
+        );
+        assert!(result.is_ok());
+        assert_eq!(buy_ob.get_book()[0][0].id, second);
+        assert_eq!(buy_ob.get_book()[0][1].id, first);
+        assert_eq!(buy_ob.get_book()[0][1].amount, d(6));
+
+        // Changing price moves it to its new level
+        let result = buy_ob.amend_order(
+            second,
+            AmendRequest {
+                price: Some(d(4)),
+                amount: None,
+            },
+        );
+        assert!(result.is_ok());
+        assert_eq!(buy_ob.get_book().len(), 2);
+        assert_eq!(buy_ob.get_book()[1][0].id, second);
+        assert_eq!(buy_ob.get_book()[1][0].amount, d(3));
+        assert_eq!(buy_ob.best_price(), Some(d(5)));
+
+        let result = buy_ob.amend_order(
+            second,
+            AmendRequest {
+                price: None,
+                amount: Some(d(0)),
+            },
+        );
+        assert!(matches!(result, Err(Error::InvalidOrder(_))));
+        let result = buy_ob.amend_order(
+            Uuid::parse_str("00000000-0000-0000-0000-000000000003").unwrap(),
+            AmendRequest {
+                price: None,
+                amount: Some(d(1)),
+            },
+        );
+        assert_eq!(result.unwrap_err(), Error::NoSuchOrder);
+    }
+
+    #[test]
+    fn test_sell_book_order() {
+        // Sells are best at the lowest price
+        let sell_ob = create_order_book(
+            Side::Sell,
+            [7, 3, 5, 3]
+                .iter()
+                .map(|&price| OpenLimitOrder {
+                    id: Uuid::nil(),
+                    owner: Uuid::nil(),
+                    amount: d(1),
+                    symbol: aapl(),
+                    side: Side::Sell,
+                    price: d(price),
+                })
+                .collect(),
+        );
+        let book = sell_ob.get_book();
+        let prices: Vec<Decimal> = book.iter().map(|queue| queue[0].price).collect();
+        assert_eq!(prices, vec![d(3), d(5), d(7)]);
+        assert_eq!(book[0].len(), 2);
+        assert_eq!(sell_ob.best_price(), Some(d(3)));
+        assert!(sell_ob.crosses(d(3)));
+        assert!(!sell_ob.crosses(d(2)));
+    }
+
+    #[test]
+    fn test_get_order() {
+        let first = Uuid::parse_str("00000000-0000-0000-0000-000000000001").unwrap();
+        let second = Uuid::parse_str("00000000-0000-0000-0000-000000000002").unwrap();
+        let mut sell_ob = create_order_book(
+            Side::Sell,
+            vec![
+                OpenLimitOrder {
+                    id: first,
+                    owner: Uuid::nil(),
+                    amount: d(4),
+                    symbol: aapl(),
+                    side: Side::Sell,
+                    price: d(5),
+                },
+                OpenLimitOrder {
+                    id: second,
+                    owner: Uuid::nil(),
+                    amount: d(3),
+                    symbol: aapl(),
+                    side: Side::Sell,
+                    price: d(5),
+                },
+            ],
+        );
+        assert_eq!(sell_ob.find_order(second), Some((d(5), 1)));
+        // Can't add the same order twice
+        assert_eq!(
+            sell_ob
+                .add_order(sell_ob.get_order(first).unwrap())
+                .unwrap_err(),
+            Error::DuplicateOrder
+        );
+
+        // Fill all of the first and some of the second
+        let result = sell_ob.fill_order(
+            OpenLimitOrder {
+                id: Uuid::parse_str("00000000-0000-0000-0000-000000000003").unwrap(),
+                owner: Uuid::nil(),
+                amount: d(5),
+                symbol: aapl(),
+                side: Side::Buy,
+                price: d(5),
+            },
+            TimeInForce::GoodTillCancel,
+        );
+        assert!(result.is_ok());
+        assert!(sell_ob.get_order(first).is_none());
+        assert_eq!(sell_ob.get_order(second).unwrap().amount, d(2));
+        assert_eq!(sell_ob.find_order(second), Some((d(5), 0)));
+
+        // Moving it keeps it findable at its new price
+        let result = sell_ob.amend_order(
+            second,
+            AmendRequest {
+                price: Some(d(6)),
+                amount: None,
+            },
+        );
+        assert!(result.is_ok());
+        assert_eq!(sell_ob.get_order(second).unwrap().price, d(6));
+        assert_eq!(sell_ob.find_order(second), Some((d(6), 0)));
+
+        assert!(sell_ob.remove_order(second).is_ok());
+        assert!(sell_ob.get_order(second).is_none());
+        assert_eq!(
+            sell_ob.remove_order(second).unwrap_err(),
+            Error::NoSuchOrder
+        );
+    }
+
+    #[test]
+    fn test_depth() {
+        let order = |side, price, amount| OpenLimitOrder {
+            id: Uuid::new_v4(),
+            owner: Uuid::nil(),
+            amount: d(amount),
+            symbol: aapl(),
+            price: d(price),
+            side,
+        };
+        let buy_ob = create_order_book(
+            Side::Buy,
+            vec![
+                order(Side::Buy, 4, 3),
+                order(Side::Buy, 5, 1),
+                order(Side::Buy, 5, 2),
+                order(Side::Buy, 3, 7),
+            ],
+        );
+        let sell_ob = create_order_book(Side::Sell, vec![order(Side::Sell, 8, 1)]);
+        let level = |price, amount, orders| PriceLevel {
+            price: d(price),
+            amount: d(amount),
+            orders,
+        };
+        assert_eq!(
+            buy_ob.depth(10).unwrap(),
+            vec![level(5, 3, 2), level(4, 3, 1), level(3, 7, 1)]
+        );
+        assert_eq!(
+            buy_ob.depth(2).unwrap(),
+            vec![level(5, 3, 2), level(4, 3, 1)]
+        );
+        assert!(buy_ob.depth(0).unwrap().is_empty());
+
+        let depth = Depth::new(aapl(), &buy_ob, &sell_ob, 1).unwrap();
+        assert_eq!(depth.bids, vec![level(5, 3, 2)]);
+        assert_eq!(depth.asks, vec![level(8, 1, 1)]);
+        assert_eq!(depth.best_bid, Some(d(5)));
+        assert_eq!(depth.best_ask, Some(d(8)));
+        assert_eq!(depth.spread, Some(d(3)));
+        assert_eq!(depth.mid, Some("6.5".parse().unwrap()));
+
+        // No spread or mid without both sides.
+        let empty_ob = OrderBook::new(Side::Sell);
+        let depth = Depth::new(aapl(), &buy_ob, &empty_ob, 10).unwrap();
+        assert!(depth.asks.is_empty());
+        assert_eq!(depth.best_ask, None);
+        assert_eq!(depth.spread, None);
+        assert_eq!(depth.mid, None);
+    }
+
+    #[test]
+    fn test_events() {
+        let order = |side, price, amount| OpenLimitOrder {
+            id: Uuid::new_v4(),
+            owner: Uuid::nil(),
+            amount: d(amount),
+            symbol: aapl(),
+            price: d(price),
+            side,
+        };
+        let mut sell_ob = OrderBook::new(Side::Sell);
+        let first = sell_ob.add_order(order(Side::Sell, 5, 3)).unwrap().id;
+        let second = sell_ob.add_order(order(Side::Sell, 6, 4)).unwrap().id;
+        let event = |id, price, amount| (id, d(price), d(amount));
+        let add = |(id, price, amount)| OrderEvent::Add {
+            id,
+            side: Side::Sell,
+            price,
+            amount,
+        };
+        assert_eq!(
+            sell_ob.take_events(),
+            vec![add(event(first, 5, 3)), add(event(second, 6, 4))]
+        );
+        assert!(sell_ob.take_events().is_empty());
+
+        // Reducing in place is a modify, anything else a cancel and an add.
+        let amend = |price: Option<u32>, amount: Option<u32>| AmendRequest {
+            price: price.map(d),
+            amount: amount.map(d),
+        };
+        sell_ob.amend_order(first, amend(None, Some(2))).unwrap();
+        sell_ob.amend_order(second, amend(Some(7), None)).unwrap();
+        assert_eq!(
+            sell_ob.take_events(),
+            vec![
+                OrderEvent::Modify {
+                    id: first,
+                    side: Side::Sell,
+                    price: d(5),
+                    amount: d(2),
+                },
+                OrderEvent::Cancel {
+                    id: second,
+                    side: Side::Sell,
+                    price: d(6),
+                    amount: d(4),
+                },
+                add(event(second, 7, 4)),
+            ]
+        );
+
+        // A fill or kill that can't fill doesn't touch the book.
+        let fr = sell_ob
+            .fill_order(order(Side::Buy, 7, 10), TimeInForce::FillOrKill)
+            .unwrap();
+        assert_eq!(fr.status, OrderStatus::Cancelled);
+        assert!(sell_ob.take_events().is_empty());
+
+        let execute = |(id, price, amount)| OrderEvent::Execute {
+            id,
+            side: Side::Sell,
+            price,
+            amount,
+        };
+        sell_ob
+            .fill_order(order(Side::Buy, 7, 3), TimeInForce::GoodTillCancel)
+            .unwrap();
+        assert_eq!(
+            sell_ob.take_events(),
+            vec![execute(event(first, 5, 2)), execute(event(second, 7, 1))]
+        );
+        sell_ob.remove_order(second).unwrap();
+        assert_eq!(
+            sell_ob.take_events(),
+            vec![OrderEvent::Cancel {
+                id: second,
+                side: Side::Sell,
+                price: d(7),
+                amount: d(3),
+            }]
+        );
+    }
+
+    #[test]
+    fn test_symbol() {
+        assert_eq!(aapl().as_str(), "AAPL");
+        assert_eq!(Symbol::new("BRK.B").unwrap().to_string(), "BRK.B");
+        assert!(Symbol::new("").is_err());
+        assert!(Symbol::new("ABCDEFGHIJKLM").is_err());
+        assert!(Symbol::new("AA PL").is_err());
+        assert_eq!(serde_json::to_string(&aapl()).unwrap(), "\"AAPL\"");
+        assert_eq!(serde_json::from_str::<Symbol>("\"AAPL\"").unwrap(), aapl());
+        assert!(serde_json::from_str::<Symbol>("\"AA/PL\"").is_err());
+    }
+
+    #[test]
+    fn test_overflow() {
+        let big: Decimal = "100000000000000000000000".parse().unwrap();
+        let mut sell_ob = create_order_book(
+            Side::Sell,
+            vec![OpenLimitOrder {
+                id: Uuid::nil(),
+                owner: Uuid::nil(),
+                amount: big,
+                symbol: aapl(),
+                side: Side::Sell,
+                price: big,
+            }],
+        );
+        // big * big doesn't fit, the book is left alone rather than panicking
+        let result = sell_ob.fill_order(
+            OpenLimitOrder {
+                id: Uuid::nil(),
+                owner: Uuid::nil(),
+                amount: big,
+                symbol: aapl(),
+                side: Side::Buy,
+                price: Decimal::MAX,
+            },
+            TimeInForce::GoodTillCancel,
+        );
+        assert_eq!(result.unwrap_err(), Error::Overflow);
+        assert_eq!(sell_ob.get_book()[0][0].amount, big);
+        // Same for amounts that can't be lined up to the same scale
+        let result = sell_ob.fill_order(
+            OpenLimitOrder {
+                id: Uuid::nil(),
+                owner: Uuid::nil(),
+                amount: "0.000000000000000001".parse().unwrap(),
+                symbol: aapl(),
+                side: Side::Buy,
+                price: Decimal::MAX,
+            },
+            TimeInForce::GoodTillCancel,
+        );
+        assert_eq!(result.unwrap_err(), Error::Overflow);
+        assert_eq!(sell_ob.get_book()[0][0].amount, big);
+    }
+
+    // Whole number decimals back as integers, to check sums without going through Decimal.
+    fn units(value: Decimal) -> u128 {
+        value.to_string().parse().unwrap()
     }
 
+    proptest! {
+        // Whatever u32 prices and amounts are on the book and in the incoming order, matching
+        // never overflows and every bit of the order is accounted for.
+        #[test]
+        fn prop_fill_order(
+            resting in prop::collection::vec((1..=u32::MAX, any::<u32>()), 0..20),
+            amount in 1..=u32::MAX,
+            price: Option<u32>,
+            buy: bool,
+            tif in prop_oneof![
+                Just(TimeInForce::GoodTillCancel),
+                Just(TimeInForce::ImmediateOrCancel),
+                Just(TimeInForce::FillOrKill),
+            ],
+        ) {
+            let (side, book_side) = if buy {
+                (Side::Buy, Side::Sell)
+            } else {
+                (Side::Sell, Side::Buy)
+            };
+            let mut ob = create_order_book(
+                book_side,
+                resting
+                    .iter()
+                    .map(|&(amount, price)| OpenLimitOrder {
+                        id: Uuid::nil(),
+                        owner: Uuid::nil(),
+                        amount: d(amount),
+                        symbol: aapl(),
+                        side: book_side,
+                        price: d(price),
+                    })
+                    .collect(),
+            );
+            // No price is a market order
+            let order = OrderRequest {
+                amount: d(amount),
+                symbol: aapl(),
+                side,
+                order_type: if price.is_some() {
+                    OrderType::Limit
+                } else {
+                    OrderType::Market
+                },
+                time_in_force: tif,
+                price: price.map(d),
+                protection_price: None,
+            };
+            let order = order.to_order(Uuid::new_v4(), Uuid::nil()).unwrap();
+            let before: u128 = resting.iter().map(|&(amount, _)| amount as u128).sum();
+
+            let fr = ob.fill_order(order, tif).unwrap();
+            prop_assert_eq!(
+                units(fr.filled) + units(fr.remaining) + units(fr.cancelled),
+                amount as u128
+            );
+            let after: u128 = ob
+                .get_book()
+                .iter()
+                .flatten()
+                .map(|order| units(order.amount))
+                .sum();
+            prop_assert_eq!(before - after, units(fr.filled));
+            let notional: u128 = fr
+                .trades
+                .iter()
+                .map(|trade| units(trade.price) * units(trade.amount))
+                .sum();
+            prop_assert_eq!(units(fr.notional), notional);
+            if let Some(avg_price) = fr.avg_price {
+                let prices = fr.trades.iter().map(|trade| trade.price);
+                prop_assert!(avg_price >= prices.clone().min().unwrap());
+                prop_assert!(avg_price <= prices.max().unwrap());
+            }
+        }
+    }
 }
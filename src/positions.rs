@@ -0,0 +1,177 @@
+use crate::decimal::Decimal;
+use crate::error::Error;
+use crate::order_book::{Side, Symbol, AVG_PRICE_SCALE};
+use serde::{Deserialize, Serialize};
+
+// Where an account stands in one instrument from its fills only. Deposits and withdrawals
+// aren't trades and have no price, so they're in its balance but not here.
+#[derive(Serialize, Deserialize, Debug, Copy, Clone, PartialEq)]
+pub struct Position {
+    // What it's bought less what it's sold, positive when long and negative when short.
+    pub traded_net: Decimal,
+    // What the open position cost on average, None when it's flat.
+    pub avg_price: Option<Decimal>,
+    // From whatever has been closed out, against the average price it was opened at.
+    pub realized_pnl: Decimal,
+}
+
+impl Default for Position {
+    fn default() -> Position {
+        Position {
+            traded_net: Decimal::ZERO,
+            avg_price: None,
+            realized_pnl: Decimal::ZERO,
+        }
+    }
+}
+
+impl Position {
+    // The position after a fill. Adding to it averages the price in, to AVG_PRICE_SCALE
+    // decimal places (or the price's own scale if that's finer), and reducing it realizes
+    // the difference from the average price. Going through flat opens the rest at price.
+    pub fn fill(&self, side: Side, price: Decimal, amount: Decimal) -> Result<Position, Error> {
+        let change = match side {
+            Side::Buy => amount,
+            Side::Sell => -amount,
+        };
+        let net = self.traded_net.checked_add(change).ok_or(Error::Overflow)?;
+        let avg_price = match self.avg_price {
+            Some(avg_price) if self.traded_net.is_positive() != change.is_positive() => avg_price,
+            _ => {
+                // Opening or adding to it.
+                let cost = self
+                    .avg_price
+                    .unwrap_or(Decimal::ZERO)
+                    .checked_mul(self.traded_net)
+                    .and_then(|cost| cost.checked_add(price.checked_mul(change)?))
+                    .ok_or(Error::Overflow)?;
+                let scale = price.scale().max(AVG_PRICE_SCALE);
+                let avg_price = cost.checked_div(net, scale).ok_or(Error::Overflow)?;
+                return Ok(Position {
+                    traded_net: net,
+                    avg_price: Some(avg_price.normalize()),
+                    realized_pnl: self.realized_pnl,
+                });
+            }
+        };
+        // Reducing it, by up to all of it.
+        let closed = if net.is_zero() || net.is_positive() == self.traded_net.is_positive() {
+            -change
+        } else {
+            self.traded_net
+        };
+        let realized_pnl = price
+            .checked_sub(avg_price)
+            .and_then(|gain| gain.checked_mul(closed))
+            .and_then(|gain| self.realized_pnl.checked_add(gain))
+            .ok_or(Error::Overflow)?;
+        let avg_price = if net.is_zero() {
+            None
+        } else if net.is_positive() == self.traded_net.is_positive() {
+            Some(avg_price)
+        } else {
+            Some(price)
+        };
+        Ok(Position {
+            traded_net: net,
+            avg_price,
+            realized_pnl,
+        })
+    }
+
+    // What's still open is worth marked to mid, None without one.
+    pub fn unrealized_pnl(&self, mid: Option<Decimal>) -> Result<Option<Decimal>, Error> {
+        match (self.avg_price, mid) {
+            (None, _) => Ok(Some(Decimal::ZERO)),
+            (Some(_), None) => Ok(None),
+            (Some(avg_price), Some(mid)) => mid
+                .checked_sub(avg_price)
+                .and_then(|gain| gain.checked_mul(self.traded_net))
+                .map(Some)
+                .ok_or(Error::Overflow),
+        }
+    }
+}
+
+// A position marked to its instrument's current mid, what /accounts/{id}/positions lists.
+#[derive(Serialize, Deserialize, Debug, Copy, Clone, PartialEq)]
+pub struct MarkedPosition {
+    pub symbol: Symbol,
+    pub traded_net: Decimal,
+    pub avg_price: Option<Decimal>,
+    pub realized_pnl: Decimal,
+    // None unless both sides of the book have orders, or when delisted.
+    pub mid: Option<Decimal>,
+    // None without a mid.
+    pub unrealized_pnl: Option<Decimal>,
+}
+
+impl MarkedPosition {
+    pub fn new(
+        symbol: Symbol,
+        position: Position,
+        mid: Option<Decimal>,
+    ) -> Result<MarkedPosition, Error> {
+        Ok(MarkedPosition {
+            symbol,
+            traded_net: position.traded_net,
+            avg_price: position.avg_price,
+            realized_pnl: position.realized_pnl,
+            mid,
+            unrealized_pnl: position.unrealized_pnl(mid)?,
+        })
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use crate::decimal::Decimal;
+    use crate::order_book::{Side, Symbol};
+    use crate::positions::{MarkedPosition, Position};
+
+    fn d(s: &str) -> Decimal {
+        s.parse().unwrap()
+    }
+
+    fn position(net: &str, avg_price: Option<&str>, realized_pnl: &str) -> Position {
+        Position {
+            traded_net: d(net),
+            avg_price: avg_price.map(d),
+            realized_pnl: d(realized_pnl),
+        }
+    }
+
+    #[test]
+    fn test_fill() {
+        let flat = Position::default();
+        // Buying 10 at 10 and 5 at 11.5 averages 10.5.
+        let long = flat.fill(Side::Buy, d("10"), d("10")).unwrap();
+        let long = long.fill(Side::Buy, d("11.5"), d("5")).unwrap();
+        assert_eq!(long, position("15", Some("10.5"), "0"));
+        // Selling 6 at 12 realizes 6 * 1.5 and leaves the average alone.
+        let long = long.fill(Side::Sell, d("12"), d("6")).unwrap();
+        assert_eq!(long, position("9", Some("10.5"), "9"));
+        // Selling 12 at 10 closes the 9 for a 4.5 loss and opens a short of 3 at 10.
+        let short = long.fill(Side::Sell, d("10"), d("12")).unwrap();
+        assert_eq!(short, position("-3", Some("10"), "4.5"));
+        let short = short.fill(Side::Sell, d("11"), d("1")).unwrap();
+        assert_eq!(short, position("-4", Some("10.25"), "4.5"));
+        let flat = short.fill(Side::Buy, d("9.25"), d("4")).unwrap();
+        assert_eq!(flat, position("0", None, "8.5"));
+        // Averages that don't come out exactly are rounded.
+        let thirds = Position::default()
+            .fill(Side::Buy, d("1"), d("2"))
+            .unwrap()
+            .fill(Side::Buy, d("2"), d("1"))
+            .unwrap();
+        assert_eq!(thirds.avg_price, Some(d("1.33333333")));
+
+        let aapl = Symbol::new("AAPL").unwrap();
+        let marked = MarkedPosition::new(aapl, short, Some(d("9.75"))).unwrap();
+        assert_eq!(marked.unrealized_pnl, Some(d("2")));
+        let marked = MarkedPosition::new(aapl, short, None).unwrap();
+        assert_eq!(marked.unrealized_pnl, None);
+        let marked = MarkedPosition::new(aapl, flat, None).unwrap();
+        assert_eq!(marked.unrealized_pnl, Some(d("0")));
+    }
+}
@@ -0,0 +1,57 @@
+table! {
+    journal_progress (id) {
+        id -> Int4,
+        records -> Int8,
+    }
+}
+
+table! {
+    order_transitions (id) {
+        id -> Int8,
+        order_id -> Uuid,
+        change -> Text,
+        status -> Text,
+        price -> Nullable<Numeric>,
+        filled -> Numeric,
+        remaining -> Numeric,
+        cancelled -> Numeric,
+        timestamp -> Int8,
+    }
+}
+
+table! {
+    orders (id) {
+        id -> Uuid,
+        symbol -> Text,
+        side -> Text,
+        order_type -> Text,
+        time_in_force -> Text,
+        price -> Nullable<Numeric>,
+        protection_price -> Nullable<Numeric>,
+        amount -> Numeric,
+        filled -> Numeric,
+        remaining -> Numeric,
+        cancelled -> Numeric,
+        status -> Text,
+        created_at -> Int8,
+        updated_at -> Int8,
+        account -> Nullable<Uuid>,
+    }
+}
+
+table! {
+    trades (id) {
+        id -> Uuid,
+        symbol -> Text,
+        aggressor_id -> Uuid,
+        passive_id -> Uuid,
+        aggressor_side -> Text,
+        price -> Numeric,
+        amount -> Numeric,
+        timestamp -> Int8,
+    }
+}
+
+joinable!(order_transitions -> orders (order_id));
+
+allow_tables_to_appear_in_same_query!(journal_progress, order_transitions, orders, trades,);
@@ -0,0 +1,508 @@
+use crate::accounts::{Account, Accounts, ApiKey};
+use crate::balances::Balances;
+use crate::decimal::Decimal;
+use crate::error::Error;
+use crate::feed::Feed;
+use crate::fees::Fee;
+use crate::instruments::{Instrument, Registry};
+use crate::order_book::{now_millis, OpenLimitOrder, OrderBook, Side, Symbol, Trade};
+use crate::positions::Position;
+use crate::trade_log::TradeLog;
+use serde::{Deserialize, Serialize};
+use std::collections::BTreeMap;
+use std::fs::{self, File, OpenOptions, Permissions};
+use std::io::Write;
+use std::os::unix::fs::{OpenOptionsExt, PermissionsExt};
+use std::path::Path;
+use uuid::Uuid;
+
+// Bumped whenever what's in a snapshot changes, older ones can't be loaded.
+pub const SNAPSHOT_VERSION: u32 = 7;
+
+// The first line of a snapshot file, the snapshot itself follows it.
+#[derive(Serialize, Deserialize, Debug)]
+struct Header {
+    version: u32,
+    // CRC-32 of everything after the header line.
+    checksum: u32,
+}
+
+// An order on the book, the rest of it is the same for the whole level.
+#[derive(Serialize, Deserialize, Debug, Copy, Clone)]
+pub struct SavedOrder {
+    pub id: Uuid,
+    pub owner: Uuid,
+    pub price: Decimal,
+    pub amount: Decimal,
+}
+
+// Everything on an instrument's books, and where its feed had got to.
+#[derive(Serialize, Deserialize, Debug, Clone)]
+pub struct BookSnapshot {
+    pub symbol: Symbol,
+    pub seq: u64,
+    pub event_seq: u64,
+    // As get_book gives them, best price first and each level in queue order.
+    pub buys: Vec<Vec<SavedOrder>>,
+    pub sells: Vec<Vec<SavedOrder>>,
+}
+
+fn levels(book: &OrderBook) -> Vec<Vec<SavedOrder>> {
+    book.get_book()
+        .into_iter()
+        .map(|level| {
+            level
+                .into_iter()
+                .map(|order| SavedOrder {
+                    id: order.id,
+                    owner: order.owner,
+                    price: order.price,
+                    amount: order.amount,
+                })
+                .collect()
+        })
+        .collect()
+}
+
+// An account, every order it has placed, oldest first, its API keys, balances, positions and
+// every fee it has been charged, oldest first.
+#[derive(Serialize, Deserialize, Debug, Clone)]
+pub struct AccountSnapshot {
+    #[serde(flatten)]
+    pub account: Account,
+    pub orders: Vec<Uuid>,
+    pub keys: Vec<ApiKey>,
+    pub balances: Balances,
+    pub positions: BTreeMap<Symbol, Position>,
+    pub fees: Vec<Fee>,
+}
+
+// The whole exchange at a single point in its journal. Recovering from one only needs the
+// journal records written after it.
+#[derive(Serialize, Deserialize, Debug, Clone)]
+pub struct Snapshot {
+    // Milliseconds since the unix epoch.
+    pub taken_at: u64,
+    // How many journal records it includes.
+    pub journal_records: usize,
+    pub instruments: Vec<Instrument>,
+    pub accounts: Vec<AccountSnapshot>,
+    pub books: Vec<BookSnapshot>,
+    pub trades: Vec<Trade>,
+}
+
+#[derive(Serialize, Deserialize, Debug, Copy, Clone, PartialEq)]
+pub struct SnapshotSummary {
+    pub version: u32,
+    pub taken_at: u64,
+    pub journal_records: usize,
+    pub instruments: usize,
+    pub accounts: usize,
+    pub orders: usize,
+    pub trades: usize,
+}
+
+impl Snapshot {
+    // Nothing can change while it's taken, so the registry should be locked for writing
+    // and journal_records read with the journal locked.
+    pub fn take(
+        registry: &Registry,
+        accounts: &Accounts,
+        journal_records: usize,
+        trade_log: &TradeLog,
+        feed: &Feed,
+    ) -> Snapshot {
+        let mut books: Vec<BookSnapshot> = registry
+            .all_books()
+            .into_iter()
+            .map(|(symbol, books)| {
+                let (buy_book, sell_book) = books.read();
+                let (seq, event_seq) = feed.seqs(symbol);
+                BookSnapshot {
+                    symbol,
+                    seq,
+                    event_seq,
+                    buys: levels(&buy_book),
+                    sells: levels(&sell_book),
+                }
+            })
+            .collect();
+        books.sort_by_key(|book| book.symbol);
+        Snapshot {
+            taken_at: now_millis(),
+            journal_records,
+            instruments: registry.list(),
+            accounts: accounts
+                .list()
+                .into_iter()
+                .map(|account| AccountSnapshot {
+                    orders: accounts.orders(account.id).to_vec(),
+                    keys: accounts.keys(account.id),
+                    balances: accounts.ledger().balances(account.id),
+                    positions: accounts.ledger().positions(account.id),
+                    fees: accounts.ledger().fee_ledger().fees(account.id).to_vec(),
+                    account,
+                })
+                .collect(),
+            books,
+            trades: trade_log.get_trades(),
+        }
+    }
+
+    pub fn summary(&self) -> SnapshotSummary {
+        SnapshotSummary {
+            version: SNAPSHOT_VERSION,
+            taken_at: self.taken_at,
+            journal_records: self.journal_records,
+            instruments: self.instruments.len(),
+            accounts: self.accounts.len(),
+            orders: self
+                .books
+                .iter()
+                .flat_map(|book| book.buys.iter().chain(book.sells.iter()))
+                .map(Vec::len)
+                .sum(),
+            trades: self.trades.len(),
+        }
+    }
+
+    // Replaces whatever snapshot is at path. It's written alongside first and moved into
+    // place, so stopping partway through leaves the last one as it was.
+    pub fn save(&self, path: &str) -> Result<(), String> {
+        let body = serde_json::to_vec(self).map_err(|e| e.to_string())?;
+        let header = Header {
+            version: SNAPSHOT_VERSION,
+            checksum: crc32fast::hash(&body),
+        };
+        let mut contents = serde_json::to_vec(&header).map_err(|e| e.to_string())?;
+        contents.push(b'\n');
+        contents.extend(body);
+
+        // API key secrets are in it, so only its owner can read it. A leftover temp file
+        // keeps whatever permissions it had, so they're set again either way.
+        let temp_path = format!("{}.tmp", path);
+        let write = || -> std::io::Result<()> {
+            let mut file = OpenOptions::new()
+                .write(true)
+                .create(true)
+                .truncate(true)
+                .mode(0o600)
+                .open(&temp_path)?;
+            file.set_permissions(Permissions::from_mode(0o600))?;
+            file.write_all(&contents)?;
+            file.sync_all()?;
+            fs::rename(&temp_path, path)?;
+            // Make the rename itself stick.
+            match Path::new(path).parent() {
+                Some(dir) if !dir.as_os_str().is_empty() => File::open(dir)?.sync_all(),
+                _ => File::open(".")?.sync_all(),
+            }
+        };
+        write().map_err(|e| format!("unable to write {}: {}", path, e))
+    }
+
+    // The snapshot at path, or None if there isn't one.
+    pub fn load(path: &str) -> Result<Option<Snapshot>, String> {
+        let contents = match fs::read(path) {
+            Ok(contents) => contents,
+            Err(e) if e.kind() == std::io::ErrorKind::NotFound => return Ok(None),
+            Err(e) => return Err(format!("unable to read {}: {}", path, e)),
+        };
+        let split = contents
+            .iter()
+            .position(|&b| b == b'\n')
+            .ok_or_else(|| format!("{} has no header", path))?;
+        let (header, body) = (&contents[..split], &contents[split + 1..]);
+        let header: Header = serde_json::from_slice(header)
+            .map_err(|e| format!("unable to parse the header of {}: {}", path, e))?;
+        if header.version != SNAPSHOT_VERSION {
+            return Err(format!(
+                "{} is version {}, only version {} can be loaded",
+                path, header.version, SNAPSHOT_VERSION
+            ));
+        }
+        if crc32fast::hash(body) != header.checksum {
+            return Err(format!("{} is corrupt, its checksum doesn't match", path));
+        }
+        let snapshot =
+            serde_json::from_slice(body).map_err(|e| format!("unable to parse {}: {}", path, e))?;
+        Ok(Some(snapshot))
+    }
+
+    // Puts everything back as it was when the snapshot was taken. Instruments also in the
+    // instruments config keep the config's reference data but take the snapshot's status.
+    pub fn restore(
+        &self,
+        registry: &mut Registry,
+        accounts: &mut Accounts,
+        trade_log: &mut TradeLog,
+        feed: &mut Feed,
+    ) -> Result<(), String> {
+        for &instrument in self.instruments.iter() {
+            restore_instrument(registry, instrument)
+                .map_err(|e| format!("{}: {}", instrument.symbol, e))?;
+        }
+        for saved in self.accounts.iter() {
+            accounts
+                .insert(saved.account.clone())
+                .map_err(|e| format!("{}: {}", saved.account.name, e))?;
+            for &order in saved.orders.iter() {
+                accounts.record_order(saved.account.id, order);
+            }
+            for key in saved.keys.iter() {
+                accounts
+                    .insert_key(key.clone())
+                    .map_err(|e| format!("{}: {}", saved.account.name, e))?;
+            }
+            accounts.ledger_mut().restore(
+                saved.account.id,
+                saved.balances.clone(),
+                saved.positions.clone(),
+            );
+            for &fee in saved.fees.iter() {
+                accounts.ledger_mut().fee_ledger_mut().accrue(fee);
+            }
+        }
+        for book in self.books.iter() {
+            restore_book(registry, accounts, feed, book)
+                .map_err(|e| format!("{}: {}", book.symbol, e))?;
+        }
+        for &trade in self.trades.iter() {
+            trade_log.record(trade);
+        }
+        Ok(())
+    }
+}
+
+fn restore_instrument(registry: &mut Registry, instrument: Instrument) -> Result<(), Error> {
+    let status = match registry.get(instrument.symbol) {
+        Some(existing) => existing.status,
+        None => registry.add(instrument)?.status,
+    };
+    if status != instrument.status {
+        registry.set_status(instrument.symbol, instrument.status)?;
+    }
+    Ok(())
+}
+
+// Puts back every order on an instrument's books. What they hold is already in their
+// owners' restored balances.
+fn restore_book(
+    registry: &Registry,
+    accounts: &mut Accounts,
+    feed: &mut Feed,
+    book: &BookSnapshot,
+) -> Result<(), Error> {
+    let (mut buy_book, mut sell_book) = registry.books(book.symbol)?.lock();
+    // Adding each level's orders in turn puts them back in the same queue order.
+    for (orders, side) in [(&book.buys, Side::Buy), (&book.sells, Side::Sell)].iter() {
+        for order in orders.iter().flatten() {
+            let order = OpenLimitOrder {
+                id: order.id,
+                owner: order.owner,
+                amount: order.amount,
+                symbol: book.symbol,
+                price: order.price,
+                side: *side,
+            };
+            accounts.ledger_mut().restore_hold(&order)?;
+            if *side == Side::Buy {
+                buy_book.add_order(order)?;
+            } else {
+                sell_book.add_order(order)?;
+            }
+        }
+    }
+    buy_book.take_events();
+    sell_book.take_events();
+    feed.restore(book.symbol, book.seq, book.event_seq, &buy_book, &sell_book)
+}
+
+#[cfg(test)]
+mod tests {
+    use crate::accounts::{AccountRequest, Accounts, Scope};
+    use crate::balances::Balance;
+    use crate::decimal::Decimal;
+    use crate::feed::Feed;
+    use crate::fees::{FeeSchedule, Rates};
+    use crate::instruments::{InstrumentStatus, Registry};
+    use crate::order_book::{OpenLimitOrder, OrderBook, Side, Symbol, Trade};
+    use crate::snapshot::Snapshot;
+    use crate::trade_log::TradeLog;
+    use std::fs;
+    use std::os::unix::fs::PermissionsExt;
+    use uuid::Uuid;
+
+    // Given an id when it's added.
+    fn order(side: Side, price: u32, amount: u32, owner: Uuid) -> OpenLimitOrder {
+        let json = format!(
+            r#"{{"symbol": "AAPL", "side": "{:?}", "price": "{}", "amount": "{}"}}"#,
+            side, price, amount
+        );
+        let mut order: OpenLimitOrder = serde_json::from_str(&json).unwrap();
+        order.owner = owner;
+        order
+    }
+
+    fn ids(book: &OrderBook) -> Vec<(Uuid, Uuid)> {
+        book.get_book()
+            .into_iter()
+            .flatten()
+            .map(|order| (order.id, order.owner))
+            .collect()
+    }
+
+    #[test]
+    fn test_snapshot() {
+        let aapl = Symbol::new("AAPL").unwrap();
+        let msft = Symbol::new("MSFT").unwrap();
+        let mut registry = Registry::new();
+        for json in [r#"{"symbol": "AAPL"}"#, r#"{"symbol": "MSFT"}"#].iter() {
+            registry.add(serde_json::from_str(json).unwrap()).unwrap();
+        }
+        registry.set_status(msft, InstrumentStatus::Halted).unwrap();
+        let mut feed = Feed::new();
+        let mut trade_log = TradeLog::new();
+        let mut accounts = Accounts::new();
+        let request = AccountRequest {
+            name: "alice".to_string(),
+        };
+        let alice = accounts.open(request, 0).unwrap().id;
+        accounts.add_key(alice, Scope::Trade, 0).unwrap();
+        let mut sells = Vec::new();
+        {
+            let (mut buy_book, mut sell_book) = registry.books(aapl).unwrap().lock();
+            for &(side, price, amount) in [
+                (Side::Buy, 9, 1),
+                (Side::Buy, 10, 2),
+                (Side::Buy, 9, 3),
+                (Side::Sell, 11, 4),
+                (Side::Sell, 12, 5),
+                (Side::Sell, 11, 6),
+            ]
+            .iter()
+            {
+                let book = if side == Side::Buy {
+                    &mut buy_book
+                } else {
+                    &mut sell_book
+                };
+                let added = book.add_order(order(side, price, amount, alice)).unwrap();
+                accounts.record_order(alice, added.id);
+                if side == Side::Sell {
+                    sells.push(added.id);
+                }
+            }
+            let mut events = buy_book.take_events();
+            events.extend(sell_book.take_events());
+            feed.publish(aapl, &buy_book, &sell_book, &events, &[])
+                .unwrap();
+            accounts
+                .ledger_mut()
+                .settle(aapl, alice, &events, &[], &[])
+                .unwrap();
+        }
+        let trade: Trade = serde_json::from_str(&format!(
+            r#"{{"id": "{}", "symbol": "AAPL", "aggressor_id": "{}", "passive_id": "{}",
+                "aggressor_side": "Buy", "price": "11", "amount": "1", "timestamp": 0}}"#,
+            Uuid::new_v4(),
+            Uuid::new_v4(),
+            sells[0]
+        ))
+        .unwrap();
+        trade_log.record(trade);
+        // Alice trading with herself opens and closes a position, paying as the taker and
+        // getting the rebate as the maker.
+        let schedule = FeeSchedule {
+            rates: Rates {
+                maker_bps: "-1".parse().unwrap(),
+                taker_bps: Decimal::from(2u32),
+            },
+            ..FeeSchedule::default()
+        };
+        schedule.check().unwrap();
+        let fees = accounts.ledger().fees(&schedule, alice, &[trade]).unwrap();
+        accounts
+            .ledger_mut()
+            .settle(aapl, alice, &[], &[trade], &fees)
+            .unwrap();
+
+        let snapshot = Snapshot::take(&registry, &accounts, 8, &trade_log, &feed);
+        let summary = snapshot.summary();
+        assert_eq!(summary.journal_records, 8);
+        assert_eq!(summary.instruments, 2);
+        assert_eq!(summary.accounts, 1);
+        assert_eq!(summary.orders, 6);
+        assert_eq!(summary.trades, 1);
+
+        let path = std::env::temp_dir().join(format!("snapshot-{}.json", Uuid::new_v4()));
+        let path = path.to_str().unwrap();
+        assert!(Snapshot::load(path).unwrap().is_none());
+        snapshot.save(path).unwrap();
+        let mode = fs::metadata(path).unwrap().permissions().mode();
+        assert_eq!(mode & 0o777, 0o600);
+        let loaded = Snapshot::load(path).unwrap().unwrap();
+
+        let mut restored = Registry::new();
+        let mut restored_accounts = Accounts::new();
+        let mut restored_trade_log = TradeLog::new();
+        let mut restored_feed = Feed::new();
+        loaded
+            .restore(
+                &mut restored,
+                &mut restored_accounts,
+                &mut restored_trade_log,
+                &mut restored_feed,
+            )
+            .unwrap();
+        assert_eq!(restored.get(msft).unwrap().status, InstrumentStatus::Halted);
+        let (buy_book, sell_book) = registry.books(aapl).unwrap().read();
+        let (restored_buy_book, restored_sell_book) = restored.books(aapl).unwrap().read();
+        assert_eq!(ids(&restored_buy_book), ids(&buy_book));
+        assert_eq!(ids(&restored_sell_book), ids(&sell_book));
+        assert_eq!(restored_accounts.list(), accounts.list());
+        assert_eq!(restored_accounts.orders(alice), accounts.orders(alice));
+        assert_eq!(restored_accounts.keys(alice), accounts.keys(alice));
+        let balances = accounts.ledger().balances(alice);
+        assert_eq!(restored_accounts.ledger().balances(alice), balances);
+        let positions = accounts.ledger().positions(alice);
+        assert_eq!(positions[&aapl].realized_pnl, Decimal::ZERO);
+        assert_eq!(restored_accounts.ledger().positions(alice), positions);
+        let fees = accounts.ledger().fee_ledger().statement(alice, 0).unwrap();
+        assert_eq!(fees.paid, "0.0022".parse().unwrap());
+        assert_eq!(fees.rebated, "0.0011".parse().unwrap());
+        assert_eq!(fees.net, "0.0011".parse().unwrap());
+        assert_eq!(
+            restored_accounts
+                .ledger()
+                .fee_ledger()
+                .statement(alice, 0)
+                .unwrap(),
+            fees
+        );
+        // What the restored orders hold is known again.
+        restored_accounts.ledger_mut().delist(aapl).unwrap();
+        let restored_balances = restored_accounts.ledger().balances(alice);
+        assert_eq!(restored_balances.cash.reserved, Decimal::ZERO);
+        assert_eq!(restored_balances.instruments[&aapl], Balance::default());
+        assert_eq!(restored_trade_log.get_trades(), vec![trade]);
+        assert_eq!(restored_feed.seqs(aapl), feed.seqs(aapl));
+        assert_eq!(restored_feed.seqs(aapl), (1, 6));
+
+        // Anything else is refused.
+        let contents = fs::read_to_string(path).unwrap();
+        fs::write(
+            path,
+            contents.replacen(r#""amount":"6""#, r#""amount":"7""#, 1),
+        )
+        .unwrap();
+        assert!(Snapshot::load(path).unwrap_err().contains("checksum"));
+        fs::write(
+            path,
+            contents.replacen(r#""version":7"#, r#""version":6"#, 1),
+        )
+        .unwrap();
+        assert!(Snapshot::load(path).unwrap_err().contains("version 6"));
+        fs::remove_file(path).unwrap();
+    }
+}
@@ -0,0 +1,105 @@
+use crate::decimal::Decimal;
+use crate::error::Error;
+use crate::order_book::{OrderBook, Symbol, TopOfBook};
+use crate::trade_log::{TradeLog, TradeStats};
+use serde::{Deserialize, Serialize};
+
+// How far back a ticker's stats go.
+pub const TICKER_WINDOW_MILLIS: u64 = 24 * 60 * 60 * 1000;
+
+// Top of the book, the last trade and rolling stats for one instrument.
+#[derive(Serialize, Deserialize, Debug, Clone, PartialEq)]
+pub struct Ticker {
+    pub symbol: Symbol,
+    #[serde(flatten)]
+    pub top: TopOfBook,
+    // The most recent trade ever, however long ago it was.
+    pub last_price: Option<Decimal>,
+    pub last_amount: Option<Decimal>,
+    // Over the TICKER_WINDOW_MILLIS up to now.
+    #[serde(flatten)]
+    pub stats: TradeStats,
+}
+
+impl Ticker {
+    // now is in milliseconds since the unix epoch. Both books should be locked
+    // together so the bid and ask are from the same moment.
+    pub fn new(
+        symbol: Symbol,
+        buy_book: &OrderBook,
+        sell_book: &OrderBook,
+        trades: &TradeLog,
+        now: u64,
+    ) -> Result<Ticker, Error> {
+        let last = trades.last(symbol);
+        Ok(Ticker {
+            symbol,
+            top: TopOfBook::new(buy_book, sell_book)?,
+            last_price: last.map(|trade| trade.price),
+            last_amount: last.map(|trade| trade.amount),
+            stats: trades.stats(symbol, now.saturating_sub(TICKER_WINDOW_MILLIS))?,
+        })
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use crate::decimal::Decimal;
+    use crate::order_book::{OpenLimitOrder, OrderBook, Side, Symbol, Trade};
+    use crate::ticker::{Ticker, TICKER_WINDOW_MILLIS};
+    use crate::trade_log::TradeLog;
+    use uuid::Uuid;
+
+    fn d(n: u32) -> Decimal {
+        Decimal::from(n)
+    }
+
+    #[test]
+    fn test_ticker() {
+        let aapl = Symbol::new("AAPL").unwrap();
+        let order = |side, price, amount| OpenLimitOrder {
+            id: Uuid::new_v4(),
+            owner: Uuid::nil(),
+            amount: d(amount),
+            symbol: aapl,
+            price: d(price),
+            side,
+        };
+        let mut buy_book = OrderBook::new(Side::Buy);
+        let sell_book = OrderBook::new(Side::Sell);
+        for &(price, amount) in [(5, 1), (5, 2), (4, 7)].iter() {
+            buy_book.add_order(order(Side::Buy, price, amount)).unwrap();
+        }
+        let mut trades = TradeLog::new();
+        let now = 2 * TICKER_WINDOW_MILLIS;
+        for &(price, amount, timestamp) in [(3, 5, 0), (6, 1, now - 5), (7, 3, now - 1)].iter() {
+            trades.record(Trade {
+                id: Uuid::new_v4(),
+                symbol: aapl,
+                aggressor_id: Uuid::new_v4(),
+                passive_id: Uuid::new_v4(),
+                aggressor_side: Side::Sell,
+                price: d(price),
+                amount: d(amount),
+                timestamp,
+            });
+        }
+
+        let ticker = Ticker::new(aapl, &buy_book, &sell_book, &trades, now).unwrap();
+        assert_eq!(ticker.top.best_bid, Some(d(5)));
+        assert_eq!(ticker.top.bid_amount, Some(d(3)));
+        assert_eq!(ticker.top.best_ask, None);
+        assert_eq!(ticker.top.ask_amount, None);
+        assert_eq!(ticker.last_price, Some(d(7)));
+        assert_eq!(ticker.last_amount, Some(d(3)));
+        // The first trade is more than a day old.
+        assert_eq!(ticker.stats.open, Some(d(6)));
+        assert_eq!(ticker.stats.low, Some(d(6)));
+        assert_eq!(ticker.stats.volume, d(4));
+        assert_eq!(ticker.stats.vwap, Some("6.75".parse().unwrap()));
+
+        let json = serde_json::to_value(&ticker).unwrap();
+        assert_eq!(json["last_price"], "7");
+        assert_eq!(json["vwap"], "6.75");
+    }
+}
@@ -0,0 +1,260 @@
+use crate::decimal::Decimal;
+use crate::error::Error;
+use crate::order_book::{Side, Symbol, Trade, AVG_PRICE_SCALE};
+use serde::{Deserialize, Serialize};
+use std::collections::HashMap;
+use uuid::Uuid;
+
+// Every trade the exchange has made, looked up by either of the orders involved
+// or by symbol.
+pub struct TradeLog {
+    trades: Vec<Trade>,
+    by_order: HashMap<Uuid, Vec<usize>>,
+    // Oldest first, so also in timestamp order.
+    by_symbol: HashMap<Symbol, Vec<usize>>,
+}
+
+// Summary of an instrument's trades over some period.
+// Everything but volume and trades is None if nothing traded.
+#[derive(Serialize, Deserialize, Debug, Copy, Clone, PartialEq)]
+pub struct TradeStats {
+    pub open: Option<Decimal>,
+    pub high: Option<Decimal>,
+    pub low: Option<Decimal>,
+    pub close: Option<Decimal>,
+    // Total amount traded.
+    pub volume: Decimal,
+    // Volume weighted average price, to the same scale as a fill's avg_price.
+    pub vwap: Option<Decimal>,
+    pub trades: usize,
+}
+
+// Whether an order was resting on the book when it traded or came in and took liquidity.
+#[derive(Serialize, Deserialize, Debug, Copy, Clone, PartialEq)]
+pub enum Liquidity {
+    Maker,
+    Taker,
+}
+
+// A trade as one of the orders in it saw it.
+#[derive(Serialize, Deserialize, Debug, Copy, Clone, PartialEq)]
+pub struct Fill {
+    pub trade_id: Uuid,
+    pub order_id: Uuid,
+    pub symbol: Symbol,
+    pub side: Side,
+    pub price: Decimal,
+    pub amount: Decimal,
+    pub liquidity: Liquidity,
+    // Milliseconds since the unix epoch.
+    pub timestamp: u64,
+}
+
+impl TradeLog {
+    pub fn new() -> TradeLog {
+        TradeLog {
+            trades: Vec::new(),
+            by_order: HashMap::new(),
+            by_symbol: HashMap::new(),
+        }
+    }
+
+    pub fn record(&mut self, trade: Trade) {
+        let index = self.trades.len();
+        self.trades.push(trade);
+        self.by_order
+            .entry(trade.aggressor_id)
+            .or_default()
+            .push(index);
+        self.by_order
+            .entry(trade.passive_id)
+            .or_default()
+            .push(index);
+        self.by_symbol.entry(trade.symbol).or_default().push(index);
+    }
+
+    // Trades the order was involved in on either side, oldest first.
+    pub fn for_order(&self, id: Uuid) -> Vec<Trade> {
+        match self.by_order.get(&id) {
+            Some(indexes) => indexes.iter().map(|&i| self.trades[i]).collect(),
+            None => Vec::new(),
+        }
+    }
+
+    // Every fill of any of the orders, oldest first. A trade between two of them is a fill
+    // for each.
+    pub fn fills(&self, orders: &[Uuid]) -> Vec<Fill> {
+        let mut fills: Vec<(usize, Fill)> = Vec::new();
+        for &order_id in orders.iter() {
+            for &i in self.by_order.get(&order_id).into_iter().flatten() {
+                let trade = self.trades[i];
+                let (side, liquidity) = if trade.aggressor_id == order_id {
+                    (trade.aggressor_side, Liquidity::Taker)
+                } else if trade.aggressor_side == Side::Buy {
+                    (Side::Sell, Liquidity::Maker)
+                } else {
+                    (Side::Buy, Liquidity::Maker)
+                };
+                let fill = Fill {
+                    trade_id: trade.id,
+                    order_id,
+                    symbol: trade.symbol,
+                    side,
+                    price: trade.price,
+                    amount: trade.amount,
+                    liquidity,
+                    timestamp: trade.timestamp,
+                };
+                fills.push((i, fill));
+            }
+        }
+        fills.sort_by_key(|&(i, fill)| (i, fill.liquidity == Liquidity::Maker));
+        fills.into_iter().map(|(_, fill)| fill).collect()
+    }
+
+    pub fn get_trades(&self) -> Vec<Trade> {
+        self.trades.clone()
+    }
+
+    // Most recent trade in the symbol.
+    pub fn last(&self, symbol: Symbol) -> Option<Trade> {
+        let &i = self.by_symbol.get(&symbol)?.last()?;
+        Some(self.trades[i])
+    }
+
+    // Stats for the symbol's trades at or after since, in milliseconds since the unix epoch.
+    pub fn stats(&self, symbol: Symbol, since: u64) -> Result<TradeStats, Error> {
+        let indexes: &[usize] = match self.by_symbol.get(&symbol) {
+            Some(indexes) => indexes,
+            None => &[],
+        };
+        let start = indexes.partition_point(|&i| self.trades[i].timestamp < since);
+        let trades: Vec<Trade> = indexes[start..].iter().map(|&i| self.trades[i]).collect();
+        let mut volume = Decimal::ZERO;
+        let mut notional = Decimal::ZERO;
+        for trade in trades.iter() {
+            volume = volume.checked_add(trade.amount).ok_or(Error::Overflow)?;
+            notional = trade
+                .price
+                .checked_mul(trade.amount)
+                .and_then(|value| notional.checked_add(value))
+                .ok_or(Error::Overflow)?;
+        }
+        let vwap = if volume.is_zero() {
+            None
+        } else {
+            let scale = trades
+                .iter()
+                .map(|trade| trade.price.scale())
+                .fold(AVG_PRICE_SCALE, u32::max);
+            let vwap = notional.checked_div(volume, scale).ok_or(Error::Overflow)?;
+            Some(vwap.normalize())
+        };
+        Ok(TradeStats {
+            open: trades.first().map(|trade| trade.price),
+            high: trades.iter().map(|trade| trade.price).max(),
+            low: trades.iter().map(|trade| trade.price).min(),
+            close: trades.last().map(|trade| trade.price),
+            volume,
+            vwap,
+            trades: trades.len(),
+        })
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use crate::decimal::Decimal;
+    use crate::order_book::{Side, Symbol, Trade};
+    use crate::trade_log::{Liquidity, TradeLog};
+    use uuid::Uuid;
+
+    fn trade(symbol: &str, price: &str, amount: u32, timestamp: u64) -> Trade {
+        Trade {
+            id: Uuid::new_v4(),
+            symbol: Symbol::new(symbol).unwrap(),
+            aggressor_id: Uuid::new_v4(),
+            passive_id: Uuid::new_v4(),
+            aggressor_side: Side::Buy,
+            price: price.parse().unwrap(),
+            amount: Decimal::from(amount),
+            timestamp,
+        }
+    }
+
+    #[test]
+    fn test_for_order() {
+        let aggressor = Uuid::parse_str("00000000-0000-0000-0000-000000000001").unwrap();
+        let first_passive = Uuid::parse_str("00000000-0000-0000-0000-000000000002").unwrap();
+        let second_passive = Uuid::parse_str("00000000-0000-0000-0000-000000000003").unwrap();
+        let mut log = TradeLog::new();
+        for &(passive_id, price) in [(first_passive, "5.25"), (second_passive, "6")].iter() {
+            log.record(Trade {
+                id: Uuid::new_v4(),
+                symbol: Symbol::new("AAPL").unwrap(),
+                aggressor_id: aggressor,
+                passive_id,
+                aggressor_side: Side::Buy,
+                price: price.parse().unwrap(),
+                amount: Decimal::from(1u32),
+                timestamp: 0,
+            });
+        }
+
+        assert_eq!(log.for_order(aggressor).len(), 2);
+        assert_eq!(log.for_order(first_passive).len(), 1);
+        assert_eq!(log.for_order(first_passive)[0].price.to_string(), "5.25");
+        assert_eq!(log.for_order(second_passive)[0].price, Decimal::from(6u32));
+        assert!(log.for_order(Uuid::new_v4()).is_empty());
+        assert_eq!(log.get_trades().len(), 2);
+
+        let fills = log.fills(&[second_passive, aggressor]);
+        let seen: Vec<(Uuid, Side, Liquidity)> = fills
+            .iter()
+            .map(|fill| (fill.order_id, fill.side, fill.liquidity))
+            .collect();
+        assert_eq!(
+            seen,
+            vec![
+                (aggressor, Side::Buy, Liquidity::Taker),
+                (aggressor, Side::Buy, Liquidity::Taker),
+                (second_passive, Side::Sell, Liquidity::Maker),
+            ]
+        );
+        assert_eq!(fills[1].trade_id, fills[2].trade_id);
+        assert!(log.fills(&[Uuid::new_v4()]).is_empty());
+    }
+
+    #[test]
+    fn test_stats() {
+        let aapl = Symbol::new("AAPL").unwrap();
+        let mut log = TradeLog::new();
+        log.record(trade("AAPL", "9", 100, 10));
+        log.record(trade("AAPL", "5.25", 2, 20));
+        log.record(trade("MSFT", "300", 1, 25));
+        log.record(trade("AAPL", "6", 1, 30));
+        log.record(trade("AAPL", "5.5", 3, 40));
+
+        let stats = log.stats(aapl, 20).unwrap();
+        assert_eq!(stats.open, Some("5.25".parse().unwrap()));
+        assert_eq!(stats.high, Some("6".parse().unwrap()));
+        assert_eq!(stats.low, Some("5.25".parse().unwrap()));
+        assert_eq!(stats.close, Some("5.5".parse().unwrap()));
+        assert_eq!(stats.volume, Decimal::from(6u32));
+        // (10.5 + 6 + 16.5) / 6
+        assert_eq!(stats.vwap, Some("5.5".parse().unwrap()));
+        assert_eq!(stats.trades, 3);
+        assert_eq!(log.stats(aapl, 0).unwrap().trades, 4);
+        assert_eq!(log.last(aapl).unwrap().timestamp, 40);
+
+        // Nothing in the window, or nothing ever.
+        let stats = log.stats(aapl, 41).unwrap();
+        assert_eq!(stats.open, None);
+        assert_eq!(stats.vwap, None);
+        assert_eq!(stats.volume, Decimal::ZERO);
+        assert_eq!(stats.trades, 0);
+        let amzn = Symbol::new("AMZN").unwrap();
+        assert_eq!(log.stats(amzn, 0).unwrap().trades, 0);
+        assert!(log.last(amzn).is_none());
+    }
+}